@@ -0,0 +1,269 @@
+//! Admin commands that inspect and maintain the configured store.
+//!
+//! These run in a separate process from the broker, against the same store. Changes to keys are
+//! picked up by running brokers in the same way as `--import-key`.
+
+use crate::agents::{
+    DeleteSession, Expiring, FlushCache, GetKeySet, ImportKeySet, KeySet, ListLimits, ListSessions,
+    ResetLimit, StoreSender,
+};
+use crate::config::ConfigBuilder;
+use crate::crypto::SigningAlgorithm;
+use crate::email_address::EmailAddress;
+use crate::utils::{
+    fs::write_private_file, key_backup, keyed_hash::KeyedHasher, BoxError, SecureRandom,
+};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// An admin command, as parsed from the command line.
+pub enum Command {
+    ListSessions,
+    ExpireSession(String),
+    ShowLimits(Option<String>),
+    ResetLimit(String),
+    HashLimitValue(String),
+    ShowKeys,
+    RotateKeys,
+    ExportKeys {
+        output: PathBuf,
+        passphrase_file: Option<PathBuf>,
+    },
+    FlushCache,
+}
+
+impl Command {
+    /// Whether the command only reads from the store.
+    fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            Command::ListSessions
+                | Command::ShowLimits(_)
+                | Command::HashLimitValue(_)
+                | Command::ShowKeys
+                | Command::ExportKeys { .. }
+        )
+    }
+}
+
+/// Run an admin command against the configured store.
+pub async fn run(builder: ConfigBuilder, command: Command) {
+    // Memory storage lives in the running broker, and admin commands can only read its snapshot.
+    if builder.memory_storage {
+        if !command.is_read_only() {
+            panic!("This admin command cannot change memory storage, only a running broker can");
+        }
+        if builder.memory_snapshot_file.is_none() {
+            panic!("Admin commands require memory_snapshot_file when using memory_storage");
+        }
+    }
+    let signing_algs = builder.signing_algs.clone();
+    let limit_key_hasher = builder
+        .limit_key_hasher()
+        .unwrap_or_else(|err| panic!("failed to build configuration: {}", err));
+    let store = builder
+        .into_store()
+        .await
+        .unwrap_or_else(|err| panic!("failed to build configuration: {}", err));
+
+    let result = match command {
+        Command::ListSessions => list_sessions(&*store).await,
+        Command::ExpireSession(session_id) => expire_session(&*store, session_id).await,
+        Command::ShowLimits(prefix) => show_limits(&*store, prefix).await,
+        Command::ResetLimit(key) => reset_limit(&*store, key).await,
+        Command::HashLimitValue(value) => hash_limit_value(limit_key_hasher.as_ref(), &value),
+        Command::ShowKeys => show_keys(&*store, &signing_algs).await,
+        Command::RotateKeys => rotate_keys(&*store, &signing_algs).await,
+        Command::ExportKeys {
+            output,
+            passphrase_file,
+        } => export_keys(&*store, &output, passphrase_file.as_deref()).await,
+        Command::FlushCache => flush_cache(&*store).await,
+    };
+
+    store.stop().await;
+    if let Err(err) = result {
+        panic!("Command failed: {}", err);
+    }
+}
+
+fn format_time(time: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(time).to_string()
+}
+
+async fn list_sessions(store: &dyn StoreSender) -> Result<(), BoxError> {
+    let mut entries = store.send(ListSessions).await?;
+    entries.sort_by_key(|entry| entry.expires);
+    for entry in &entries {
+        println!(
+            "{}  {}  {}  expires {}",
+            entry.session_id,
+            entry.data.data.email_addr,
+            entry
+                .data
+                .data
+                .return_params
+                .redirect_uri
+                .origin()
+                .ascii_serialization(),
+            format_time(entry.expires)
+        );
+    }
+    eprintln!("{} active session(s)", entries.len());
+    Ok(())
+}
+
+async fn expire_session(store: &dyn StoreSender, session_id: String) -> Result<(), BoxError> {
+    store.send(DeleteSession { session_id }).await?;
+    eprintln!("Session expired");
+    Ok(())
+}
+
+async fn show_limits(store: &dyn StoreSender, prefix: Option<String>) -> Result<(), BoxError> {
+    let mut entries = store.send(ListLimits).await?;
+    if let Some(ref prefix) = prefix {
+        entries.retain(|entry| entry.key.starts_with(prefix.as_str()));
+    }
+    entries.sort_by(|a, b| a.key.cmp(&b.key));
+    for entry in &entries {
+        println!(
+            "{}  {}  resets {}",
+            entry.key,
+            entry.count,
+            format_time(entry.expires)
+        );
+    }
+    eprintln!("{} rate limit counter(s)", entries.len());
+    Ok(())
+}
+
+async fn reset_limit(store: &dyn StoreSender, key: String) -> Result<(), BoxError> {
+    if store.send(ResetLimit { key }).await? {
+        eprintln!("Rate limit counter reset");
+    } else {
+        eprintln!("No active rate limit counter with that key");
+    }
+    Ok(())
+}
+
+fn hash_limit_value(hasher: Option<&KeyedHasher>, value: &str) -> Result<(), BoxError> {
+    let hasher = hasher.ok_or("limit_key_secret is not set, rate limit keys are not hashed")?;
+    // Email addresses are normalized before hashing, like in the broker itself.
+    match value.parse::<EmailAddress>() {
+        Ok(email_addr) => println!("{}", hasher.short(email_addr.as_str())),
+        Err(_) => println!("{}", hasher.short(value)),
+    }
+    Ok(())
+}
+
+async fn show_keys(
+    store: &dyn StoreSender,
+    signing_algs: &[SigningAlgorithm],
+) -> Result<(), BoxError> {
+    for signing_alg in signing_algs {
+        let key_set = store.send(GetKeySet(*signing_alg)).await?;
+        println!("{}", signing_alg);
+        println!("  current:  {}", describe_key(key_set.current.as_ref()));
+        println!("  next:     {}", describe_key(key_set.next.as_ref()));
+        println!(
+            "  previous: {}",
+            if key_set.previous.is_some() {
+                "present"
+            } else {
+                "none"
+            }
+        );
+    }
+    Ok(())
+}
+
+fn describe_key(entry: Option<&Expiring<String>>) -> String {
+    match entry {
+        Some(entry) if entry.is_alive() => format!("expires {}", format_time(entry.expires)),
+        Some(entry) => format!("expired {}", format_time(entry.expires)),
+        None => "none".to_owned(),
+    }
+}
+
+/// Force a rotation of each key set.
+///
+/// The next key is promoted to current, and the current key becomes the previous key. The key
+/// manager of a running broker then notices the missing next key and generates a new one.
+async fn rotate_keys(
+    store: &dyn StoreSender,
+    signing_algs: &[SigningAlgorithm],
+) -> Result<(), BoxError> {
+    for signing_alg in signing_algs {
+        let key_set = store.send(GetKeySet(*signing_alg)).await?;
+        let next = match key_set.next {
+            Some(next) => next,
+            None => {
+                eprintln!(
+                    "No next {} key to rotate to, start the broker to generate keys",
+                    signing_alg
+                );
+                continue;
+            }
+        };
+        store
+            .send(ImportKeySet(KeySet {
+                signing_alg: *signing_alg,
+                current: Some(next),
+                next: None,
+                previous: key_set.current.map(|entry| entry.value),
+            }))
+            .await;
+        eprintln!("Rotated {} keys", signing_alg);
+    }
+    Ok(())
+}
+
+/// Write all stored key sets to a backup file, or stdout if the path is `-`.
+///
+/// Key sets for all algorithms are exported, not just those enabled in configuration.
+async fn export_keys(
+    store: &dyn StoreSender,
+    output: &Path,
+    passphrase_file: Option<&Path>,
+) -> Result<(), BoxError> {
+    let passphrase = passphrase_file.map(read_passphrase);
+    let mut key_sets = Vec::new();
+    for signing_alg in &SigningAlgorithm::ALL {
+        let key_set = store.send(GetKeySet(*signing_alg)).await?;
+        if key_set.current.is_some() {
+            key_sets.push(key_set);
+        }
+    }
+    let count = key_sets.len();
+    if count == 0 {
+        return Err("there are no keys to export, start the broker to generate keys".into());
+    }
+    let rng = SecureRandom::new().await;
+    let data = key_backup::encode(key_sets, passphrase.as_deref(), &rng);
+
+    if output == Path::new("-") {
+        println!("{}", data);
+    } else {
+        write_private_file(output, format!("{}\n", data).as_bytes())?;
+    }
+    eprintln!("Exported {} key set(s)", count);
+    Ok(())
+}
+
+/// Read a passphrase from a file, without the trailing newline.
+pub fn read_passphrase(path: &Path) -> String {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => contents.trim_end_matches(&['\r', '\n'][..]).to_owned(),
+        Err(err) => panic!(
+            "Could not read passphrase file '{}': {}",
+            path.display(),
+            err
+        ),
+    }
+}
+
+async fn flush_cache(store: &dyn StoreSender) -> Result<(), BoxError> {
+    let count = store.send(FlushCache).await?;
+    eprintln!("Removed {} cache entries", count);
+    Ok(())
+}
@@ -1,5 +1,5 @@
 use crate::crypto::SigningAlgorithm;
-use crate::utils::agent::{Message, Sender};
+use crate::utils::agent::{Message, Sender, Stopper};
 use crate::utils::keys::SignError;
 use serde_json::Value as JsonValue;
 
@@ -18,11 +18,23 @@ impl Message for GetPublicJwks {
     type Reply = Vec<JsonValue>;
 }
 
+/// Message requesting the signing algorithms for which no keys are active.
+///
+/// This is used by the health endpoints. The reply is empty if the key manager can sign with every
+/// configured algorithm.
+pub struct CheckKeys;
+impl Message for CheckKeys {
+    type Reply = Vec<SigningAlgorithm>;
+}
+
 /// Key manager abstraction. Combines all message types.
 ///
 /// Downside of this is that it needs to be implemented on the agent side as:
 /// `impl KeyManagerSender for Addr<FoobarKeyManager> {}`
-pub trait KeyManagerSender: Sender<SignJws> + Sender<GetPublicJwks> {}
+pub trait KeyManagerSender:
+    Sender<SignJws> + Sender<GetPublicJwks> + Sender<CheckKeys> + Stopper
+{
+}
 
 pub mod manual;
 pub mod rotating;
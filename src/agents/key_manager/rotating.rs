@@ -206,6 +206,12 @@ impl Agent for RotatingKeys {
             me.send(Check).await;
         });
     }
+
+    fn stopping(&mut self, cx: Context<Self, AgentStopping>) {
+        // Dropping our handle ends the timer task, so no more rotations are started.
+        self.delays = None;
+        cx.reply(());
+    }
 }
 
 impl Handler<Check> for RotatingKeys {
@@ -362,4 +368,20 @@ impl Handler<GetPublicJwks> for RotatingKeys {
     }
 }
 
+impl Handler<CheckKeys> for RotatingKeys {
+    fn handle(&mut self, _message: CheckKeys, cx: Context<Self, CheckKeys>) {
+        use SigningAlgorithm::*;
+        let missing = self
+            .signing_algs
+            .iter()
+            .filter(|signing_alg| match signing_alg {
+                EdDsa => self.ed25519_keys.is_none(),
+                Rs256 => self.rsa_keys.is_none(),
+            })
+            .cloned()
+            .collect();
+        cx.reply(missing);
+    }
+}
+
 impl KeyManagerSender for Addr<RotatingKeys> {}
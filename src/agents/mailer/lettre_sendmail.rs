@@ -1,5 +1,6 @@
 use crate::agents::*;
 use crate::email_address::EmailAddress;
+use crate::metrics;
 use crate::utils::agent::*;
 use lettre::{SendmailTransport, Transport};
 
@@ -30,9 +31,20 @@ impl Handler<SendMail> for SendmailMailer {
                 cx.reply(true);
             }
             Err(err) => {
+                metrics::MAIL_FAILURES
+                    .with_label_values(&["sendmail"])
+                    .inc();
                 log::error!("Could not send mail: {}", err);
                 cx.reply(false);
             }
         }
     }
 }
+
+impl Handler<CheckMailer> for SendmailMailer {
+    fn handle(&mut self, _message: CheckMailer, cx: Context<Self, CheckMailer>) {
+        cx.reply("sendmail");
+    }
+}
+
+impl MailerSender for Addr<SendmailMailer> {}
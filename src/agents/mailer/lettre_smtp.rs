@@ -1,5 +1,6 @@
 use crate::agents::*;
 use crate::email_address::EmailAddress;
+use crate::metrics;
 use crate::utils::agent::*;
 use lettre::{
     smtp::authentication::Credentials, ClientSecurity, ClientTlsParameters, SmtpClient,
@@ -58,6 +59,7 @@ impl Handler<SendMail> for SmtpMailer {
                 if result.is_positive() {
                     cx.reply(true);
                 } else {
+                    metrics::MAIL_FAILURES.with_label_values(&["smtp"]).inc();
                     log::error!(
                         "SMTP server rejected a mail: {} {}",
                         result.code,
@@ -67,9 +69,18 @@ impl Handler<SendMail> for SmtpMailer {
                 }
             }
             Err(err) => {
+                metrics::MAIL_FAILURES.with_label_values(&["smtp"]).inc();
                 log::error!("Could not send mail: {}", err);
                 cx.reply(false);
             }
         }
     }
 }
+
+impl Handler<CheckMailer> for SmtpMailer {
+    fn handle(&mut self, _message: CheckMailer, cx: Context<Self, CheckMailer>) {
+        cx.reply("smtp");
+    }
+}
+
+impl MailerSender for Addr<SmtpMailer> {}
@@ -1,5 +1,6 @@
 use crate::agents::*;
 use crate::email_address::EmailAddress;
+use crate::metrics;
 use crate::utils::agent::*;
 use http::Request;
 use hyper::Body;
@@ -60,6 +61,7 @@ impl Handler<SendMail> for MailgunMailer {
             match future.await {
                 Ok(_) => true,
                 Err(err) => {
+                    metrics::MAIL_FAILURES.with_label_values(&["mailgun"]).inc();
                     log::error!("Mailgun request failed: {}", err);
                     false
                 }
@@ -67,3 +69,11 @@ impl Handler<SendMail> for MailgunMailer {
         });
     }
 }
+
+impl Handler<CheckMailer> for MailgunMailer {
+    fn handle(&mut self, _message: CheckMailer, cx: Context<Self, CheckMailer>) {
+        cx.reply("mailgun");
+    }
+}
+
+impl MailerSender for Addr<MailgunMailer> {}
@@ -1,5 +1,5 @@
 use crate::email_address::EmailAddress;
-use crate::utils::agent::Message;
+use crate::utils::agent::{Message, Sender, Stopper};
 
 #[cfg(feature = "lettre_email")]
 use ::{lettre::SendableEmail, lettre_email::EmailBuilder};
@@ -15,6 +15,20 @@ impl Message for SendMail {
     type Reply = bool;
 }
 
+/// Message requesting the name of the mailer backend.
+///
+/// This is used by the health endpoints to confirm a mailer is configured and responding.
+pub struct CheckMailer;
+impl Message for CheckMailer {
+    type Reply = &'static str;
+}
+
+/// Mailer abstraction.
+///
+/// Downside of this is that it needs to be implemented on the agent side as:
+/// `impl MailerSender for Addr<FoobarMailer> {}`
+pub trait MailerSender: Sender<SendMail> + Sender<CheckMailer> + Stopper {}
+
 #[cfg(feature = "lettre_email")]
 impl SendMail {
     /// Convert the message to a lettre `SendableEmail`.
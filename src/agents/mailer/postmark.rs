@@ -1,5 +1,6 @@
 use crate::agents::*;
 use crate::email_address::EmailAddress;
+use crate::metrics;
 use crate::utils::agent::*;
 use http::Request;
 use hyper::Body;
@@ -62,6 +63,9 @@ impl Handler<SendMail> for PostmarkMailer {
             let data = match future.await {
                 Ok(result) => result.data,
                 Err(err) => {
+                    metrics::MAIL_FAILURES
+                        .with_label_values(&["postmark"])
+                        .inc();
                     log::error!("Postmark request failed: {}", err);
                     return false;
                 }
@@ -69,6 +73,9 @@ impl Handler<SendMail> for PostmarkMailer {
             let response: PostmarkResponse = match serde_json::from_str(&data) {
                 Ok(response) => response,
                 Err(err) => {
+                    metrics::MAIL_FAILURES
+                        .with_label_values(&["postmark"])
+                        .inc();
                     log::error!("Could not parse Postmark response: {}", err);
                     return false;
                 }
@@ -76,9 +83,20 @@ impl Handler<SendMail> for PostmarkMailer {
             if response.error_code == 0 {
                 true
             } else {
+                metrics::MAIL_FAILURES
+                    .with_label_values(&["postmark"])
+                    .inc();
                 log::error!("Postmark returned error code {}", response.error_code);
                 false
             }
         });
     }
 }
+
+impl Handler<CheckMailer> for PostmarkMailer {
+    fn handle(&mut self, _message: CheckMailer, cx: Context<Self, CheckMailer>) {
+        cx.reply("postmark");
+    }
+}
+
+impl MailerSender for Addr<PostmarkMailer> {}
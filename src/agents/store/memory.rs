@@ -1,14 +1,26 @@
 use crate::agents::*;
 use crate::config::LimitConfig;
 use crate::crypto::SigningAlgorithm;
-use crate::utils::agent::*;
+use crate::metrics;
+use crate::utils::{
+    agent::*, fs::write_private_file, storage_encryption::StorageEncryption, BoxError,
+};
 use crate::web::Session;
+use serde::{Deserialize, Serialize};
 use std::collections::hash_map::{Entry, HashMap};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io::ErrorKind as IoErrorKind;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::sync::Mutex;
+use tokio::task::{spawn_blocking, JoinHandle};
 use url::Url;
 
+/// Current version of the snapshot format.
+const SNAPSHOT_VERSION: u32 = 1;
+
 /// Combines any type with an `Instant` expiry time.
 struct Expiring<T> {
     value: T,
@@ -26,9 +38,56 @@ impl<T> Expiring<T> {
     fn is_alive(&self) -> bool {
         self.expires > Instant::now()
     }
+
+    /// The expiry time as a `SystemTime`.
+    fn expires_at(&self) -> SystemTime {
+        SystemTime::now() + self.expires.saturating_duration_since(Instant::now())
+    }
+
+    /// The expiry time as a Unix timestamp.
+    fn expires_timestamp(&self) -> u64 {
+        self.expires_at()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs())
+    }
+
+    /// Create a value from a Unix timestamp. Returns `None` if the value has already expired.
+    fn from_timestamp(value: T, timestamp: u64) -> Option<Self> {
+        let expires_at = UNIX_EPOCH + Duration::from_secs(timestamp);
+        let duration = expires_at.duration_since(SystemTime::now()).ok()?;
+        Some(Expiring::from_duration(value, duration))
+    }
+}
+
+/// Contents of a snapshot file.
+///
+/// Session data and key sets are encoded the same way the other stores save them, including
+/// encryption if `storage_encryption_key` is set. The fetch cache is not saved.
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    version: u32,
+    sessions: Vec<SnapshotSession>,
+    limits: Vec<SnapshotLimit>,
+    key_sets: BTreeMap<SigningAlgorithm, String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotSession {
+    id: String,
+    data: String,
+    expires: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotLimit {
+    key: String,
+    count: usize,
+    expires: u64,
 }
 
 /// Message sent at an interval to collect garbage.
+///
+/// If a snapshot file is configured, this also writes a snapshot.
 struct Gc;
 impl Message for Gc {
     type Reply = ();
@@ -68,6 +127,14 @@ pub struct MemoryStore {
     limits: HashMap<String, Expiring<usize>>,
     /// Keys storage.
     keys: HashMap<SigningAlgorithm, KeysSlot>,
+    /// Encryption of session data and key sets in snapshots.
+    encryption: StorageEncryption,
+    /// Path of the snapshot file, if enabled.
+    snapshot_file: Option<PathBuf>,
+    /// Lock held while writing a snapshot.
+    snapshot_lock: Arc<Mutex<()>>,
+    /// Handle of the garbage collection task.
+    gc_task: Option<JoinHandle<()>>,
 }
 
 impl MemoryStore {
@@ -75,12 +142,12 @@ impl MemoryStore {
         expire_sessions: Duration,
         expire_cache: Duration,
         limit_configs: Vec<LimitConfig>,
+        encryption: StorageEncryption,
+        snapshot_file: Option<PathBuf>,
+        read_only: bool,
         fetcher: Addr<FetchAgent>,
-    ) -> Self {
-        log::warn!("Storing sessions and keys in memory.");
-        log::warn!("Note that these will be lost on restart!");
-
-        MemoryStore {
+    ) -> Result<Self, BoxError> {
+        let mut store = MemoryStore {
             expire_sessions,
             expire_cache,
             limit_configs,
@@ -90,7 +157,133 @@ impl MemoryStore {
             cache: HashMap::new(),
             limits: HashMap::new(),
             keys: HashMap::new(),
+            encryption,
+            snapshot_file: None,
+            snapshot_lock: Arc::new(Mutex::new(())),
+            gc_task: None,
+        };
+
+        if let Some(snapshot_file) = snapshot_file {
+            store.load_snapshot(&snapshot_file)?;
+            if read_only {
+                // Another process, usually a running broker, owns the snapshot file. Admin commands
+                // only read from it, and refuse to run commands that make changes.
+                log::warn!(
+                    "Loaded memory store snapshot from: {} (read-only)",
+                    snapshot_file.display()
+                );
+                return Ok(store);
+            }
+            log::warn!(
+                "Storing sessions and keys in memory, with snapshots at: {}",
+                snapshot_file.display()
+            );
+            log::warn!("Please always double check this directory has secure permissions!");
+            store.snapshot_file = Some(snapshot_file);
+        } else {
+            log::warn!("Storing sessions and keys in memory.");
+            log::warn!("Note that these will be lost on restart!");
+        }
+
+        Ok(store)
+    }
+
+    /// Restore state from a snapshot file, if it exists.
+    fn load_snapshot(&mut self, path: &Path) -> Result<(), BoxError> {
+        let data = match std::fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == IoErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        let snapshot: Snapshot = serde_json::from_str(&data)?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(format!(
+                "Unsupported memory store snapshot version: {}",
+                snapshot.version
+            )
+            .into());
+        }
+        for entry in snapshot.sessions {
+            let data = self.encryption.open_session(&entry.id, entry.data)?;
+            if let Some(expiring) = Expiring::from_timestamp(data, entry.expires) {
+                self.sessions.insert(entry.id, expiring);
+            }
+        }
+        for entry in snapshot.limits {
+            if let Some(expiring) = Expiring::from_timestamp(entry.count, entry.expires) {
+                self.limits.insert(entry.key, expiring);
+            }
+        }
+        for (signing_alg, data) in snapshot.key_sets {
+            let key_set = self.encryption.open_key_set(signing_alg, data)?;
+            self.keys.insert(signing_alg, Arc::new(Mutex::new(key_set)));
         }
+        log::info!(
+            "Restored {} session(s), {} rate limit(s) and {} key set(s) from snapshot",
+            self.sessions.len(),
+            self.limits.len(),
+            self.keys.len()
+        );
+        Ok(())
+    }
+
+    /// Build a future that writes a snapshot, if a snapshot file is configured.
+    ///
+    /// Sessions and rate limits are captured immediately. Key sets are captured when the future
+    /// runs, because they may be locked for rotation.
+    fn snapshot(&self) -> Option<impl Future<Output = ()> + Send + 'static> {
+        let path = self.snapshot_file.clone()?;
+        let encryption = self.encryption.clone();
+        let lock = self.snapshot_lock.clone();
+        let sessions = self
+            .sessions
+            .iter()
+            .filter(|(_, entry)| entry.is_alive())
+            .map(|(session_id, entry)| SnapshotSession {
+                id: session_id.clone(),
+                data: encryption
+                    .seal_session(session_id, &entry.value)
+                    .expect("Could not encode session as JSON"),
+                expires: entry.expires_timestamp(),
+            })
+            .collect();
+        let limits = self
+            .limits
+            .iter()
+            .filter(|(_, entry)| entry.is_alive())
+            .map(|(key, entry)| SnapshotLimit {
+                key: key.clone(),
+                count: entry.value,
+                expires: entry.expires_timestamp(),
+            })
+            .collect();
+        let key_slots: Vec<_> = self
+            .keys
+            .iter()
+            .map(|(signing_alg, slot)| (*signing_alg, slot.clone()))
+            .collect();
+        Some(async move {
+            // Write snapshots one at a time, so an older snapshot never replaces a newer one.
+            let _guard = lock.lock().await;
+            let mut key_sets = BTreeMap::new();
+            for (signing_alg, slot) in key_slots {
+                let key_set = slot.lock().await;
+                key_sets.insert(signing_alg, encryption.seal_key_set(&key_set));
+            }
+            let snapshot = Snapshot {
+                version: SNAPSHOT_VERSION,
+                sessions,
+                limits,
+                key_sets,
+            };
+            let data = serde_json::to_vec(&snapshot).expect("Could not encode snapshot as JSON");
+            let res = spawn_blocking(move || write_private_file(&path, &data))
+                .await
+                .expect("Snapshot task panicked");
+            if let Err(err) = res {
+                log::error!("Failed to write memory store snapshot: {}", err);
+            }
+        })
     }
 }
 
@@ -98,17 +291,27 @@ impl Agent for MemoryStore {
     fn started(&mut self, cx: Context<Self, AgentStarted>) {
         // Start the garbage collection loop.
         let addr = cx.addr().clone();
-        tokio::spawn(async move {
+        self.gc_task = Some(tokio::spawn(async move {
             let mut interval = tokio::time::interval(Duration::from_secs(60));
-            // Ignore the first (immediate) tick, because we'll always be empty.
+            // Ignore the first (immediate) tick, because we just started.
             interval.tick().await;
             loop {
                 interval.tick().await;
                 addr.send(Gc).await;
             }
-        });
+        }));
         cx.reply(());
     }
+
+    fn stopping(&mut self, cx: Context<Self, AgentStopping>) {
+        if let Some(gc_task) = self.gc_task.take() {
+            gc_task.abort();
+        }
+        match self.snapshot() {
+            Some(snapshot) => cx.reply_later(snapshot),
+            None => cx.reply(()),
+        }
+    }
 }
 
 impl Handler<Gc> for MemoryStore {
@@ -140,7 +343,10 @@ impl Handler<Gc> for MemoryStore {
             .drain()
             .filter(|(_, ref entry)| entry.is_alive())
             .collect();
-        cx.reply(())
+        match self.snapshot() {
+            Some(snapshot) => cx.reply_later(snapshot),
+            None => cx.reply(()),
+        }
     }
 }
 
@@ -172,6 +378,17 @@ impl Handler<DeleteSession> for MemoryStore {
     }
 }
 
+impl Handler<TakeSession> for MemoryStore {
+    fn handle(&mut self, message: TakeSession, cx: Context<Self, TakeSession>) {
+        let data = self
+            .sessions
+            .remove(&message.session_id)
+            .filter(|entry| entry.is_alive())
+            .map(|entry| entry.value);
+        cx.reply(Ok(data))
+    }
+}
+
 impl Handler<FetchUrlCached> for MemoryStore {
     fn handle(&mut self, message: FetchUrlCached, cx: Context<Self, FetchUrlCached>) {
         let fetcher = self.fetcher.clone();
@@ -180,8 +397,10 @@ impl Handler<FetchUrlCached> for MemoryStore {
         cx.reply_later(async move {
             let mut slot = slot.lock().await;
             if let Some(entry) = slot.as_ref().filter(|entry| entry.is_alive()) {
+                metrics::FETCH_CACHE.with_label_values(&["hit"]).inc();
                 return Ok(entry.value.clone());
             }
+            metrics::FETCH_CACHE.with_label_values(&["miss"]).inc();
             let result = fetcher.send(FetchUrl::get(&message.url)).await?;
             let ttl = std::cmp::max(expire_cache, result.max_age);
             *slot = Some(Expiring::from_duration(result.data.clone(), ttl));
@@ -215,7 +434,12 @@ impl Handler<IncrAndTestLimits> for MemoryStore {
                     1
                 }
             };
-            ok = ok && count <= config.max_count;
+            if count > config.max_count {
+                metrics::RATE_LIMIT_REJECTIONS
+                    .with_label_values(&[&config.id.to_string()])
+                    .inc();
+                ok = false;
+            }
         }
         cx.reply(Ok(ok));
     }
@@ -245,16 +469,19 @@ impl Handler<DecrLimits> for MemoryStore {
 impl Handler<EnableRotatingKeys> for MemoryStore {
     fn handle(&mut self, message: EnableRotatingKeys, cx: Context<Self, EnableRotatingKeys>) {
         self.key_manager = Some(message.key_manager.clone());
-        let mut update_msgs = Vec::with_capacity(message.signing_algs.len());
+        let mut slots = Vec::with_capacity(message.signing_algs.len());
         for signing_alg in &message.signing_algs {
-            let key_set = KeySet::empty(*signing_alg);
-            update_msgs.push(UpdateKeys(key_set.clone()));
-            self.keys
-                .insert(*signing_alg, Arc::new(Mutex::new(key_set)));
+            // Keys may have been restored from a snapshot.
+            let slot = self
+                .keys
+                .entry(*signing_alg)
+                .or_insert_with(|| Arc::new(Mutex::new(KeySet::empty(*signing_alg))));
+            slots.push(slot.clone());
         }
         cx.reply_later(async move {
-            for update_msg in update_msgs {
-                message.key_manager.send(update_msg).await;
+            for slot in slots {
+                let key_set = slot.lock().await.clone();
+                message.key_manager.send(UpdateKeys(key_set)).await;
             }
         });
     }
@@ -282,4 +509,73 @@ impl Handler<ImportKeySet> for MemoryStore {
     }
 }
 
+impl Handler<CheckStore> for MemoryStore {
+    fn handle(&mut self, _message: CheckStore, cx: Context<Self, CheckStore>) {
+        cx.reply(Ok(()));
+    }
+}
+
+impl Handler<ListSessions> for MemoryStore {
+    fn handle(&mut self, _message: ListSessions, cx: Context<Self, ListSessions>) {
+        let entries = self
+            .sessions
+            .iter()
+            .filter(|(_, entry)| entry.is_alive())
+            .map(|(session_id, entry)| SessionEntry {
+                session_id: session_id.clone(),
+                data: entry.value.clone(),
+                expires: entry.expires_at(),
+            })
+            .collect();
+        cx.reply(Ok(entries));
+    }
+}
+
+impl Handler<ListLimits> for MemoryStore {
+    fn handle(&mut self, _message: ListLimits, cx: Context<Self, ListLimits>) {
+        let entries = self
+            .limits
+            .iter()
+            .filter(|(_, entry)| entry.is_alive())
+            .map(|(key, entry)| LimitEntry {
+                key: key.clone(),
+                count: entry.value,
+                expires: entry.expires_at(),
+            })
+            .collect();
+        cx.reply(Ok(entries));
+    }
+}
+
+impl Handler<ResetLimit> for MemoryStore {
+    fn handle(&mut self, message: ResetLimit, cx: Context<Self, ResetLimit>) {
+        let existed = self
+            .limits
+            .remove(&message.key)
+            .filter(|entry| entry.is_alive())
+            .is_some();
+        cx.reply(Ok(existed));
+    }
+}
+
+impl Handler<GetKeySet> for MemoryStore {
+    fn handle(&mut self, message: GetKeySet, cx: Context<Self, GetKeySet>) {
+        let slot_rc = self.keys.get(&message.0).cloned();
+        cx.reply_later(async move {
+            Ok(match slot_rc {
+                Some(slot_rc) => slot_rc.lock().await.clone(),
+                None => KeySet::empty(message.0),
+            })
+        });
+    }
+}
+
+impl Handler<FlushCache> for MemoryStore {
+    fn handle(&mut self, _message: FlushCache, cx: Context<Self, FlushCache>) {
+        let count = self.cache.len();
+        self.cache.clear();
+        cx.reply(Ok(count));
+    }
+}
+
 impl StoreSender for Addr<MemoryStore> {}
@@ -1,10 +1,11 @@
 use crate::agents::key_manager::rotating::{KeySet, RotatingKeys};
 use crate::config::LimitInput;
 use crate::crypto::SigningAlgorithm;
-use crate::utils::agent::{Addr, Message, Sender};
+use crate::utils::agent::{Addr, Handler, Message, Sender, Stopper};
 use crate::utils::BoxError;
 use crate::web::Session;
 use std::collections::HashSet;
+use std::time::{Duration, SystemTime};
 use url::Url;
 
 /// Message requesting a session be saved.
@@ -36,6 +37,17 @@ impl Message for DeleteSession {
     type Reply = Result<(), BoxError>;
 }
 
+/// Message requesting a session be deleted, returning it if it existed.
+///
+/// This is atomic, so when racing requests take the same session, only one of them receives it.
+pub struct TakeSession {
+    /// The session ID.
+    pub session_id: String,
+}
+impl Message for TakeSession {
+    type Reply = Result<Option<Session>, BoxError>;
+}
+
 /// Message requesting a URL be fetched, possibly from cache.
 pub struct FetchUrlCached {
     /// The URL to fetch.
@@ -91,11 +103,33 @@ impl Message for EnableRotatingKeys {
 /// send `UpdateKeys` to the key manager to install the new key set.
 ///
 /// (The store is also responsible for notifying other workers of key updates, if applicable.)
+///
+/// If the store fails to read or save keys, it should log the error and use `retry_rotate_keys`,
+/// because the key manager does not retry by itself.
 pub struct RotateKeysLocked(pub SigningAlgorithm);
 impl Message for RotateKeysLocked {
     type Reply = ();
 }
 
+/// Delay before retrying to load or rotate keys, after a store error.
+pub const KEYS_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// Send `RotateKeysLocked` to the store again after a delay.
+pub fn retry_rotate_keys<A>(addr: Addr<A>, signing_alg: SigningAlgorithm)
+where
+    A: Handler<RotateKeysLocked> + Send + 'static,
+{
+    log::warn!(
+        "Retrying rotation of {} keys in {}s",
+        signing_alg,
+        KEYS_RETRY_DELAY.as_secs()
+    );
+    tokio::spawn(async move {
+        tokio::time::sleep(KEYS_RETRY_DELAY).await;
+        addr.send(RotateKeysLocked(signing_alg));
+    });
+}
+
 /// Write a new key set, and notify other workers if possible.
 ///
 /// This is used to implement `--import-key`.
@@ -104,6 +138,78 @@ impl Message for ImportKeySet {
     type Reply = ();
 }
 
+/// Message requesting the store check its connection to the backend.
+///
+/// This is used by the health endpoints. The store should do a cheap round-trip to the backend, if
+/// it has one.
+pub struct CheckStore;
+impl Message for CheckStore {
+    type Reply = Result<(), BoxError>;
+}
+
+/// A stored session, as returned by `ListSessions`.
+pub struct SessionEntry {
+    /// The session ID.
+    pub session_id: String,
+    /// Session data.
+    pub data: Session,
+    /// When the session expires.
+    pub expires: SystemTime,
+}
+
+/// Message requesting all active sessions be listed.
+///
+/// This is used by the admin commands.
+pub struct ListSessions;
+impl Message for ListSessions {
+    type Reply = Result<Vec<SessionEntry>, BoxError>;
+}
+
+/// A rate limit counter, as returned by `ListLimits`.
+pub struct LimitEntry {
+    /// The key, as built by `LimitInput::build_key` without a store-specific prefix.
+    pub key: String,
+    /// The current count.
+    pub count: usize,
+    /// When the counter resets.
+    pub expires: SystemTime,
+}
+
+/// Message requesting all active rate limit counters be listed.
+///
+/// This is used by the admin commands.
+pub struct ListLimits;
+impl Message for ListLimits {
+    type Reply = Result<Vec<LimitEntry>, BoxError>;
+}
+
+/// Message requesting a rate limit counter be reset.
+///
+/// The result is `true` if the counter existed.
+pub struct ResetLimit {
+    /// The key, in the same format as `LimitEntry::key`.
+    pub key: String,
+}
+impl Message for ResetLimit {
+    type Reply = Result<bool, BoxError>;
+}
+
+/// Message requesting the stored key set for a signing algorithm.
+///
+/// This is used by the admin commands. If no keys are stored, an empty key set is returned.
+pub struct GetKeySet(pub SigningAlgorithm);
+impl Message for GetKeySet {
+    type Reply = Result<KeySet, BoxError>;
+}
+
+/// Message requesting all entries in the fetch cache be removed.
+///
+/// The result is the number of entries removed.
+pub struct FlushCache;
+impl Message for FlushCache {
+    type Reply = Result<usize, BoxError>;
+}
+
 /// Store abstraction. Combines all message types.
 ///
 /// Downside of this is that it needs to be implemented on the agent side as:
@@ -112,12 +218,20 @@ pub trait StoreSender:
     Sender<SaveSession>
     + Sender<GetSession>
     + Sender<DeleteSession>
+    + Sender<TakeSession>
     + Sender<FetchUrlCached>
     + Sender<IncrAndTestLimits>
     + Sender<DecrLimits>
     + Sender<EnableRotatingKeys>
     + Sender<RotateKeysLocked>
     + Sender<ImportKeySet>
+    + Sender<CheckStore>
+    + Sender<ListSessions>
+    + Sender<ListLimits>
+    + Sender<ResetLimit>
+    + Sender<GetKeySet>
+    + Sender<FlushCache>
+    + Stopper
 {
 }
 
@@ -132,4 +246,9 @@ pub use self::redis::RedisStore;
 #[cfg(feature = "rusqlite")]
 pub mod rusqlite;
 #[cfg(feature = "rusqlite")]
-pub use self::rusqlite::RusqliteStore;
+pub use self::rusqlite::{RusqliteStore, SqliteOptions};
+
+#[cfg(feature = "postgres")]
+pub mod postgres;
+#[cfg(feature = "postgres")]
+pub use self::postgres::PostgresStore;
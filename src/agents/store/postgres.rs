@@ -0,0 +1,980 @@
+use crate::agents::*;
+use crate::config::LimitConfig;
+use crate::crypto::SigningAlgorithm;
+use crate::metrics;
+use crate::utils::{
+    agent::*, base64url, storage_encryption::StorageEncryption, unix_timestamp, BoxError,
+    BoxFuture, SecureRandom,
+};
+use ::tokio_postgres::{
+    AsyncMessage, Client, Config as PgConfig, Connection, Error as PgError, Socket, Transaction,
+};
+use futures_util::{future, stream, StreamExt};
+use native_tls::TlsConnector;
+use postgres_native_tls::{MakeTlsConnector, TlsStream};
+use std::collections::HashSet;
+use std::convert::identity;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+/// Advisory lock ID used while verifying the schema. 'Prtr' in hex.
+const SCHEMA_LOCK_ID: i64 = 0x5072_7472;
+
+/// Channel used to notify other workers of key set changes.
+///
+/// The payload is the signing algorithm and the ID of the sending worker, separated by a colon.
+const KEYS_CHANNEL: &str = "portier_keys_updated";
+
+/// Delay before the first attempt to reconnect after the connection was lost.
+const RECONNECT_DELAY_MIN: Duration = Duration::from_millis(500);
+
+/// Maximum delay between attempts to reconnect. The delay doubles after each failed attempt.
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(30);
+
+/// Future that drives a connection until it fails or closes.
+type Driver = BoxFuture<Result<(), PgError>>;
+
+/// Message sent at an interval to collect garbage.
+struct Gc;
+impl Message for Gc {
+    type Reply = ();
+}
+
+/// Internal message used to fetch keys and send an update to the key manager.
+struct ReloadKeys(SigningAlgorithm);
+impl Message for ReloadKeys {
+    type Reply = ();
+}
+
+/// Store implementation using PostgreSQL.
+pub struct PostgresStore {
+    /// A random unique ID for ourselves.
+    id: Arc<str>,
+    /// The client, replaced by the connection task after a reconnect.
+    client: watch::Receiver<Arc<Client>>,
+    /// Payloads of key update notifications, until rotating keys are enabled.
+    notifications: Option<UnboundedReceiver<String>>,
+    /// TTL of session keys
+    expire_sessions: Duration,
+    /// TTL of cache keys
+    expire_cache: Duration,
+    /// Rate limit configuration.
+    limit_configs: Vec<LimitConfig>,
+    /// Encryption of session data and key sets.
+    encryption: StorageEncryption,
+    /// The agent used for fetching on cache miss.
+    fetcher: Addr<FetchAgent>,
+    /// Key manager if rotating keys are enabled.
+    key_manager: Option<Addr<RotatingKeys>>,
+    /// Handle of the garbage collection task.
+    gc_task: Option<JoinHandle<()>>,
+    /// Handle of the task that reloads keys after a reconnect.
+    reload_task: Option<JoinHandle<()>>,
+}
+
+impl PostgresStore {
+    pub async fn new(
+        url: &str,
+        expire_sessions: Duration,
+        expire_cache: Duration,
+        limit_configs: Vec<LimitConfig>,
+        encryption: StorageEncryption,
+        fetcher: Addr<FetchAgent>,
+        rng: SecureRandom,
+    ) -> Result<Self, BoxError> {
+        let id = base64url::encode(&rng.generate_async(16).await).into();
+        let config: PgConfig = url.parse()?;
+        let tls = MakeTlsConnector::new(TlsConnector::new()?);
+        let (mut client, connection) = config.connect(tls.clone()).await?;
+
+        // Drive the connection while verifying the schema, then hand it to the connection task.
+        let (notify_tx, notify_rx) = unbounded_channel();
+        let mut driver: Driver = Box::pin(drive(connection, notify_tx.clone()));
+        tokio::select! {
+            res = Self::verify_schema(&mut client) => res?,
+            res = &mut driver => {
+                res?;
+                return Err("PostgreSQL connection closed".into());
+            }
+        }
+        let (client_tx, client_rx) = watch::channel(Arc::new(client));
+        tokio::spawn(conn_loop(config, tls, driver, client_tx, notify_tx));
+
+        log::warn!("Storing sessions and keys in PostgreSQL");
+        log::warn!("Please always double check this database and the connection to it are secure!");
+        log::warn!("(This warning can't be fixed; it's a friendly reminder.)");
+
+        Ok(PostgresStore {
+            id,
+            client: client_rx,
+            notifications: Some(notify_rx),
+            expire_sessions,
+            expire_cache,
+            limit_configs,
+            encryption,
+            fetcher,
+            key_manager: None,
+            gc_task: None,
+            reload_task: None,
+        })
+    }
+
+    /// Get the current client.
+    fn client(&self) -> Arc<Client> {
+        self.client.borrow().clone()
+    }
+
+    async fn verify_schema(client: &mut Client) -> Result<(), PgError> {
+        let tx = client.transaction().await?;
+        // Prevent multiple workers starting at the same time from racing to create the schema.
+        tx.execute("SELECT pg_advisory_xact_lock($1)", &[&SCHEMA_LOCK_ID])
+            .await?;
+        tx.batch_execute("CREATE TABLE IF NOT EXISTS portier_schema (version INTEGER NOT NULL)")
+            .await?;
+        let version: Option<i32> = tx
+            .query_opt("SELECT version FROM portier_schema", &[])
+            .await?
+            .map(|row| row.get(0));
+        match version {
+            None => Self::init_schema(&tx).await?,
+            Some(1) => {}
+            Some(version) => panic!(
+                "The PostgreSQL database has an unknown schema version: {}",
+                version
+            ),
+        }
+        tx.commit().await
+    }
+
+    async fn init_schema(tx: &Transaction<'_>) -> Result<(), PgError> {
+        tx.batch_execute(
+            "
+            CREATE TABLE sessions (
+                id TEXT NOT NULL PRIMARY KEY,
+                data TEXT NOT NULL,
+                expires BIGINT NOT NULL
+            );
+            CREATE INDEX sessions_expires ON sessions (expires);
+
+            CREATE TABLE cache_entries (
+                url TEXT NOT NULL PRIMARY KEY,
+                data TEXT NOT NULL,
+                expires BIGINT NOT NULL
+            );
+            CREATE INDEX cache_entries_expires ON cache_entries (expires);
+
+            CREATE TABLE rate_limits (
+                id TEXT NOT NULL PRIMARY KEY,
+                value BIGINT NOT NULL,
+                expires BIGINT NOT NULL
+            );
+            CREATE INDEX rate_limits_expires ON rate_limits (expires);
+
+            CREATE TABLE key_sets (
+                signing_alg TEXT NOT NULL PRIMARY KEY,
+                key_set TEXT NOT NULL
+            );
+
+            INSERT INTO portier_schema (version) VALUES (1);
+            ",
+        )
+        .await
+    }
+
+    /// Delete a session, returning its data if it existed and had not expired.
+    async fn take_session(client: &Client, session_id: &str) -> Result<Option<String>, PgError> {
+        let now = unix_timestamp() as i64;
+        let row = client
+            .query_opt(
+                "DELETE FROM sessions WHERE id = $1 RETURNING data, expires",
+                &[&session_id],
+            )
+            .await?;
+        Ok(row
+            .filter(|row| row.get::<_, i64>(1) > now)
+            .map(|row| row.get(0)))
+    }
+
+    /// Fetch a key set, along with its stored JSON representation.
+    async fn fetch_key_set(
+        client: &Client,
+        encryption: &StorageEncryption,
+        signing_alg: SigningAlgorithm,
+    ) -> Result<(Option<String>, KeySet), PgError> {
+        let data: Option<String> = client
+            .query_opt(
+                "SELECT key_set FROM key_sets WHERE signing_alg = $1",
+                &[&signing_alg.as_str()],
+            )
+            .await?
+            .map(|row| row.get(0));
+        let key_set = data.as_ref().map_or_else(
+            || KeySet::empty(signing_alg),
+            |data| {
+                encryption
+                    .open_key_set(signing_alg, data.clone())
+                    .unwrap_or_else(|err| panic!("Invalid key set in PostgreSQL: {}", err))
+            },
+        );
+        Ok((data, key_set))
+    }
+
+    /// Save a key set, but only if the stored key set is unchanged since it was fetched.
+    ///
+    /// Returns `false` if another worker changed the key set in the mean time.
+    async fn replace_key_set(
+        client: &Client,
+        encryption: &StorageEncryption,
+        key_set: &KeySet,
+        expected: Option<&str>,
+    ) -> Result<bool, PgError> {
+        let signing_alg = key_set.signing_alg.as_str();
+        let data = encryption.seal_key_set(key_set);
+        let count = if let Some(expected) = expected {
+            client
+                .execute(
+                    "UPDATE key_sets SET key_set = $2
+                    WHERE signing_alg = $1 AND key_set = $3",
+                    &[&signing_alg, &data, &expected],
+                )
+                .await?
+        } else {
+            client
+                .execute(
+                    "INSERT INTO key_sets (signing_alg, key_set) VALUES ($1, $2)
+                    ON CONFLICT (signing_alg) DO NOTHING",
+                    &[&signing_alg, &data],
+                )
+                .await?
+        };
+        Ok(count == 1)
+    }
+
+    /// Notify other workers that a key set changed.
+    async fn notify_keys_updated(
+        client: &Client,
+        my_id: &str,
+        signing_alg: SigningAlgorithm,
+    ) -> Result<(), PgError> {
+        let payload = format!("{}:{}", signing_alg, my_id);
+        client
+            .execute("SELECT pg_notify($1, $2)", &[&KEYS_CHANNEL, &payload])
+            .await?;
+        Ok(())
+    }
+}
+
+/// Drive a connection until it fails or closes, forwarding key update notifications.
+async fn drive(
+    mut connection: Connection<Socket, TlsStream<Socket>>,
+    notify_tx: UnboundedSender<String>,
+) -> Result<(), PgError> {
+    let mut messages = stream::poll_fn(move |cx| connection.poll_message(cx));
+    while let Some(message) = messages.next().await {
+        match message? {
+            AsyncMessage::Notification(notification) => {
+                if notification.channel() == KEYS_CHANNEL {
+                    let _ = notify_tx.send(notification.payload().to_owned());
+                }
+            }
+            AsyncMessage::Notice(notice) => log::debug!("PostgreSQL: {}", notice),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// The PostgreSQL connection loop.
+///
+/// If the connection is lost, this reconnects and sends the new client to the store through
+/// `client_tx`. This ends when the store stopped and dropped its receiver.
+async fn conn_loop(
+    config: PgConfig,
+    tls: MakeTlsConnector,
+    mut driver: Driver,
+    client_tx: watch::Sender<Arc<Client>>,
+    notify_tx: UnboundedSender<String>,
+) {
+    loop {
+        tokio::select! {
+            res = &mut driver => match res {
+                Ok(()) => log::error!("PostgreSQL connection closed, reconnecting"),
+                Err(err) => log::error!("PostgreSQL connection failed, reconnecting: {}", err),
+            },
+            () = client_tx.closed() => break,
+        }
+        let client = tokio::select! {
+            (client, new_driver) = reconnect(&config, &tls, &notify_tx) => {
+                driver = new_driver;
+                client
+            }
+            () = client_tx.closed() => break,
+        };
+        if client_tx.send(Arc::new(client)).is_err() {
+            break;
+        }
+    }
+}
+
+/// Reconnect, retrying with exponential backoff until successful.
+async fn reconnect(
+    config: &PgConfig,
+    tls: &MakeTlsConnector,
+    notify_tx: &UnboundedSender<String>,
+) -> (Client, Driver) {
+    let mut delay = RECONNECT_DELAY_MIN;
+    loop {
+        sleep(delay).await;
+        delay = std::cmp::min(delay * 2, RECONNECT_DELAY_MAX);
+        match config.connect(tls.clone()).await {
+            Ok((client, connection)) => {
+                log::info!("Reconnected to PostgreSQL");
+                return (client, Box::pin(drive(connection, notify_tx.clone())));
+            }
+            Err(err) => log::error!(
+                "Failed to reconnect to PostgreSQL, retrying in {:?}: {}",
+                delay,
+                err
+            ),
+        }
+    }
+}
+
+impl Agent for PostgresStore {
+    fn started(&mut self, cx: Context<Self, AgentStarted>) {
+        // Start the garbage collection loop.
+        let addr = cx.addr().clone();
+        self.gc_task = Some(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                addr.send(Gc).await;
+            }
+        }));
+        cx.reply(());
+    }
+
+    fn stopping(&mut self, cx: Context<Self, AgentStopping>) {
+        if let Some(gc_task) = self.gc_task.take() {
+            gc_task.abort();
+        }
+        if let Some(reload_task) = self.reload_task.take() {
+            reload_task.abort();
+        }
+        // Dropping the agent ends the connection task, which in turn ends the notification task.
+        cx.reply(());
+    }
+}
+
+impl Handler<Gc> for PostgresStore {
+    fn handle(&mut self, _message: Gc, cx: Context<Self, Gc>) {
+        let client = self.client();
+        cx.reply_later(async move {
+            let now = unix_timestamp() as i64;
+            for table in &["sessions", "cache_entries", "rate_limits"] {
+                let query = format!("DELETE FROM {} WHERE expires <= $1", table);
+                if let Err(err) = client.execute(query.as_str(), &[&now]).await {
+                    log::error!("Failed to clean up {} in PostgreSQL: {}", table, err);
+                }
+            }
+        });
+    }
+}
+
+impl Handler<SaveSession> for PostgresStore {
+    fn handle(&mut self, message: SaveSession, cx: Context<Self, SaveSession>) {
+        let client = self.client();
+        let encryption = self.encryption.clone();
+        let ttl = self.expire_sessions;
+        cx.reply_later(async move {
+            let expires = (unix_timestamp() + ttl.as_secs()) as i64;
+            let data = encryption.seal_session(&message.session_id, &message.data)?;
+            client
+                .execute(
+                    "INSERT INTO sessions (id, data, expires) VALUES ($1, $2, $3)
+                    ON CONFLICT (id) DO UPDATE SET data = $2, expires = $3",
+                    &[&message.session_id, &data, &expires],
+                )
+                .await?;
+            Ok(())
+        });
+    }
+}
+
+impl Handler<GetSession> for PostgresStore {
+    fn handle(&mut self, message: GetSession, cx: Context<Self, GetSession>) {
+        let client = self.client();
+        let encryption = self.encryption.clone();
+        cx.reply_later(async move {
+            let now = unix_timestamp() as i64;
+            let data: Option<String> = client
+                .query_opt(
+                    "SELECT data FROM sessions WHERE id = $1 AND expires > $2",
+                    &[&message.session_id, &now],
+                )
+                .await?
+                .map(|row| row.get(0));
+            if let Some(data) = data {
+                Ok(Some(encryption.open_session(&message.session_id, data)?))
+            } else {
+                Ok(None)
+            }
+        });
+    }
+}
+
+impl Handler<DeleteSession> for PostgresStore {
+    fn handle(&mut self, message: DeleteSession, cx: Context<Self, DeleteSession>) {
+        let client = self.client();
+        cx.reply_later(async move {
+            client
+                .execute("DELETE FROM sessions WHERE id = $1", &[&message.session_id])
+                .await?;
+            Ok(())
+        });
+    }
+}
+
+impl Handler<TakeSession> for PostgresStore {
+    fn handle(&mut self, message: TakeSession, cx: Context<Self, TakeSession>) {
+        let client = self.client();
+        let encryption = self.encryption.clone();
+        cx.reply_later(async move {
+            let data = PostgresStore::take_session(&client, &message.session_id).await?;
+            if let Some(data) = data {
+                Ok(Some(encryption.open_session(&message.session_id, data)?))
+            } else {
+                Ok(None)
+            }
+        });
+    }
+}
+
+impl Handler<FetchUrlCached> for PostgresStore {
+    fn handle(&mut self, message: FetchUrlCached, cx: Context<Self, FetchUrlCached>) {
+        // TODO: Add locking to coordinate multiple fetches for the same resource.
+        let client = self.client();
+        let fetcher = self.fetcher.clone();
+        let expire_cache = self.expire_cache;
+        cx.reply_later(async move {
+            let now = unix_timestamp() as i64;
+            let data: Option<String> = client
+                .query_opt(
+                    "SELECT data FROM cache_entries WHERE url = $1 AND expires > $2",
+                    &[&message.url.as_str(), &now],
+                )
+                .await?
+                .map(|row| row.get(0));
+            if let Some(data) = data {
+                metrics::FETCH_CACHE.with_label_values(&["hit"]).inc();
+                return Ok(data);
+            }
+            metrics::FETCH_CACHE.with_label_values(&["miss"]).inc();
+            let result = fetcher.send(FetchUrl::get(&message.url)).await?;
+            let ttl = std::cmp::max(expire_cache, result.max_age);
+            let expires = (unix_timestamp() + ttl.as_secs()) as i64;
+            client
+                .execute(
+                    "INSERT INTO cache_entries (url, data, expires) VALUES ($1, $2, $3)
+                    ON CONFLICT (url) DO UPDATE SET data = $2, expires = $3",
+                    &[&message.url.as_str(), &result.data, &expires],
+                )
+                .await?;
+            Ok(result.data)
+        });
+    }
+}
+
+impl Handler<IncrAndTestLimits> for PostgresStore {
+    fn handle(&mut self, message: IncrAndTestLimits, cx: Context<Self, IncrAndTestLimits>) {
+        let client = self.client();
+        let ops: Vec<_> = self
+            .limit_configs
+            .iter()
+            .map(|config| {
+                let id = message.input.build_key(config, "", "|");
+                (config.clone(), id)
+            })
+            .collect();
+        cx.reply_later(async move {
+            let now = unix_timestamp() as i64;
+            let results = future::try_join_all(ops.into_iter().map(|(config, id)| {
+                let client = client.clone();
+                async move {
+                    let window = config.window.as_secs() as i64;
+                    let count: i64 = client
+                        .query_one(
+                            "INSERT INTO rate_limits (id, value, expires) VALUES ($1, 1, $2::BIGINT + $3::BIGINT)
+                            ON CONFLICT (id) DO UPDATE SET
+                                value = CASE
+                                    WHEN rate_limits.expires <= $2 THEN 1
+                                    ELSE rate_limits.value + 1
+                                END,
+                                expires = CASE
+                                    WHEN rate_limits.expires <= $2 OR $4::BOOLEAN THEN $2 + $3
+                                    ELSE rate_limits.expires
+                                END
+                            RETURNING value",
+                            &[&id, &now, &window, &config.extend_window],
+                        )
+                        .await?
+                        .get(0);
+                    if count as usize > config.max_count {
+                        metrics::RATE_LIMIT_REJECTIONS
+                            .with_label_values(&[&config.id.to_string()])
+                            .inc();
+                        return Ok::<_, BoxError>(false);
+                    }
+                    Ok(true)
+                }
+            }))
+            .await?;
+            Ok(results.into_iter().all(identity))
+        });
+    }
+}
+
+impl Handler<DecrLimits> for PostgresStore {
+    fn handle(&mut self, message: DecrLimits, cx: Context<Self, DecrLimits>) {
+        let client = self.client();
+        let ids: Vec<_> = self
+            .limit_configs
+            .iter()
+            .filter(|config| config.decr_complete)
+            .map(|config| message.input.build_key(config, "", "|"))
+            .collect();
+        cx.reply_later(async move {
+            let now = unix_timestamp() as i64;
+            for id in ids {
+                client
+                    .execute(
+                        "WITH deleted AS (
+                            DELETE FROM rate_limits
+                            WHERE id = $1 AND (expires <= $2 OR value <= 1)
+                            RETURNING id
+                        )
+                        UPDATE rate_limits SET value = value - 1
+                        WHERE id = $1 AND NOT EXISTS (SELECT 1 FROM deleted)",
+                        &[&id, &now],
+                    )
+                    .await?;
+            }
+            Ok(())
+        });
+    }
+}
+
+impl Handler<EnableRotatingKeys> for PostgresStore {
+    fn handle(&mut self, message: EnableRotatingKeys, cx: Context<Self, EnableRotatingKeys>) {
+        let me = cx.addr().clone();
+        let my_id = self.id.clone();
+        let client = self.client();
+        let mut notifications = self
+            .notifications
+            .take()
+            .expect("Rotating keys were enabled twice");
+        self.key_manager = Some(message.key_manager.clone());
+        // Updates may have been missed while the connection was down, so listen again and reload
+        // all keys after a reconnect.
+        let mut client_rx = self.client.clone();
+        let signing_algs = message.signing_algs.clone();
+        let me2 = me.clone();
+        self.reload_task = Some(tokio::spawn(async move {
+            while client_rx.changed().await.is_ok() {
+                log::warn!("Reloading keys after PostgreSQL reconnect");
+                let client = client_rx.borrow().clone();
+                if let Err(err) = client
+                    .batch_execute(&format!("LISTEN {}", KEYS_CHANNEL))
+                    .await
+                {
+                    log::error!("Failed to listen for key changes in PostgreSQL: {}", err);
+                    continue;
+                }
+                for signing_alg in &signing_algs {
+                    me2.send(ReloadKeys(*signing_alg));
+                }
+            }
+        }));
+        cx.reply_later(async move {
+            // Listen for key changes by other workers. After a reconnect, this is done again.
+            if let Err(err) = client
+                .batch_execute(&format!("LISTEN {}", KEYS_CHANNEL))
+                .await
+            {
+                log::error!("Failed to listen for key changes in PostgreSQL: {}", err);
+            }
+            let signing_algs: HashSet<_> = message.signing_algs.clone();
+            let me2 = me.clone();
+            tokio::spawn(async move {
+                while let Some(payload) = notifications.recv().await {
+                    let mut parts = payload.splitn(2, ':');
+                    let signing_alg = parts.next().and_then(|alg| alg.parse().ok());
+                    let from_id = parts.next();
+                    if let (Some(signing_alg), Some(from_id)) = (signing_alg, from_id) {
+                        if from_id != &*my_id && signing_algs.contains(&signing_alg) {
+                            me2.send(ReloadKeys(signing_alg));
+                        }
+                    }
+                }
+            });
+            // Fetch current keys.
+            for signing_alg in &message.signing_algs {
+                me.send(ReloadKeys(*signing_alg)).await;
+            }
+        });
+    }
+}
+
+impl Handler<RotateKeysLocked> for PostgresStore {
+    fn handle(&mut self, message: RotateKeysLocked, cx: Context<Self, RotateKeysLocked>) {
+        let me = cx.addr().clone();
+        let my_id = self.id.clone();
+        let client = self.client();
+        let encryption = self.encryption.clone();
+        let key_manager = self.key_manager.as_ref().unwrap().clone();
+        cx.reply_later(async move {
+            // Instead of locking, we only save if no other worker rotated keys in the mean time.
+            let (stored, key_set) = match Self::fetch_key_set(&client, &encryption, message.0).await
+            {
+                Ok(res) => res,
+                Err(err) => {
+                    log::error!("Failed to fetch keys from PostgreSQL: {}", err);
+                    return retry_rotate_keys(me, message.0);
+                }
+            };
+            if let Some(key_set) = key_manager.send(RotateKeys(key_set)).await {
+                let saved =
+                    match Self::replace_key_set(&client, &encryption, &key_set, stored.as_deref())
+                        .await
+                    {
+                        Ok(saved) => saved,
+                        Err(err) => {
+                            log::error!("Failed to save keys to PostgreSQL: {}", err);
+                            return retry_rotate_keys(me, message.0);
+                        }
+                    };
+                if saved {
+                    // The keys are saved, so install them even if other workers miss the update.
+                    if let Err(err) = Self::notify_keys_updated(&client, &my_id, message.0).await {
+                        log::error!("Failed to notify key changes in PostgreSQL: {}", err);
+                    }
+                    key_manager.send(UpdateKeys(key_set)).await;
+                } else {
+                    log::info!(
+                        "Another worker rotated {} keys first, using those instead",
+                        message.0
+                    );
+                    me.send(ReloadKeys(message.0)).await;
+                }
+            }
+        });
+    }
+}
+
+impl Handler<ImportKeySet> for PostgresStore {
+    fn handle(&mut self, message: ImportKeySet, cx: Context<Self, ImportKeySet>) {
+        let my_id = self.id.clone();
+        let client = self.client();
+        let encryption = self.encryption.clone();
+        cx.reply_later(async move {
+            let key_set = message.0;
+            let data = encryption.seal_key_set(&key_set);
+            if let Err(err) = client
+                .execute(
+                    "INSERT INTO key_sets (signing_alg, key_set) VALUES ($1, $2)
+                    ON CONFLICT (signing_alg) DO UPDATE SET key_set = $2",
+                    &[&key_set.signing_alg.as_str(), &data],
+                )
+                .await
+            {
+                log::error!("Failed to save keys to PostgreSQL: {}", err);
+                return;
+            }
+            if let Err(err) = Self::notify_keys_updated(&client, &my_id, key_set.signing_alg).await
+            {
+                log::error!("Failed to notify key changes in PostgreSQL: {}", err);
+            }
+        });
+    }
+}
+
+impl Handler<CheckStore> for PostgresStore {
+    fn handle(&mut self, _message: CheckStore, cx: Context<Self, CheckStore>) {
+        let client = self.client();
+        cx.reply_later(async move {
+            client.execute("SELECT 1", &[]).await?;
+            Ok(())
+        });
+    }
+}
+
+impl Handler<ListSessions> for PostgresStore {
+    fn handle(&mut self, _message: ListSessions, cx: Context<Self, ListSessions>) {
+        let client = self.client();
+        let encryption = self.encryption.clone();
+        cx.reply_later(async move {
+            let now = unix_timestamp() as i64;
+            let rows = client
+                .query(
+                    "SELECT id, data, expires FROM sessions WHERE expires > $1",
+                    &[&now],
+                )
+                .await?;
+            let mut entries = Vec::with_capacity(rows.len());
+            for row in rows {
+                let session_id: String = row.get(0);
+                let data: String = row.get(1);
+                let expires: i64 = row.get(2);
+                entries.push(SessionEntry {
+                    data: encryption.open_session(&session_id, data)?,
+                    session_id,
+                    expires: UNIX_EPOCH + Duration::from_secs(expires as u64),
+                });
+            }
+            Ok(entries)
+        });
+    }
+}
+
+impl Handler<ListLimits> for PostgresStore {
+    fn handle(&mut self, _message: ListLimits, cx: Context<Self, ListLimits>) {
+        let client = self.client();
+        cx.reply_later(async move {
+            let now = unix_timestamp() as i64;
+            let rows = client
+                .query(
+                    "SELECT id, value, expires FROM rate_limits WHERE expires > $1",
+                    &[&now],
+                )
+                .await?;
+            let entries = rows
+                .into_iter()
+                .map(|row| {
+                    let count: i64 = row.get(1);
+                    let expires: i64 = row.get(2);
+                    LimitEntry {
+                        key: row.get(0),
+                        count: count as usize,
+                        expires: UNIX_EPOCH + Duration::from_secs(expires as u64),
+                    }
+                })
+                .collect();
+            Ok(entries)
+        });
+    }
+}
+
+impl Handler<ResetLimit> for PostgresStore {
+    fn handle(&mut self, message: ResetLimit, cx: Context<Self, ResetLimit>) {
+        let client = self.client();
+        cx.reply_later(async move {
+            let now = unix_timestamp() as i64;
+            let count = client
+                .execute(
+                    "DELETE FROM rate_limits WHERE id = $1 AND expires > $2",
+                    &[&message.key, &now],
+                )
+                .await?;
+            Ok(count > 0)
+        });
+    }
+}
+
+impl Handler<GetKeySet> for PostgresStore {
+    fn handle(&mut self, message: GetKeySet, cx: Context<Self, GetKeySet>) {
+        let client = self.client();
+        let encryption = self.encryption.clone();
+        cx.reply_later(async move {
+            let (_, key_set) = Self::fetch_key_set(&client, &encryption, message.0).await?;
+            Ok(key_set)
+        });
+    }
+}
+
+impl Handler<FlushCache> for PostgresStore {
+    fn handle(&mut self, _message: FlushCache, cx: Context<Self, FlushCache>) {
+        let client = self.client();
+        cx.reply_later(async move {
+            let count = client.execute("DELETE FROM cache_entries", &[]).await?;
+            Ok(count as usize)
+        });
+    }
+}
+
+impl Handler<ReloadKeys> for PostgresStore {
+    fn handle(&mut self, message: ReloadKeys, cx: Context<Self, ReloadKeys>) {
+        let client = self.client();
+        let encryption = self.encryption.clone();
+        let key_manager = self.key_manager.as_ref().unwrap().clone();
+        cx.reply_later(async move {
+            // After a reconnect, keys are reloaded again.
+            match Self::fetch_key_set(&client, &encryption, message.0).await {
+                Ok((_, key_set)) => key_manager.send(UpdateKeys(key_set)).await,
+                Err(err) => log::error!("Failed to fetch keys from PostgreSQL: {}", err),
+            }
+        });
+    }
+}
+
+impl StoreSender for Addr<PostgresStore> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::tokio_postgres::NoTls;
+
+    /// Connect to the test database in a fresh schema, or return `None` to skip the test.
+    ///
+    /// Set `PORTIER_TEST_POSTGRES_URL` to run these tests.
+    async fn connect(name: &str) -> Option<(Client, String)> {
+        let url = std::env::var("PORTIER_TEST_POSTGRES_URL").ok()?;
+        let (client, connection) = ::tokio_postgres::connect(&url, NoTls).await.unwrap();
+        tokio::spawn(connection);
+        let schema = format!("portier_test_{}_{}", name, std::process::id());
+        client
+            .batch_execute(&format!(
+                "DROP SCHEMA IF EXISTS {0} CASCADE;
+                CREATE SCHEMA {0};
+                SET search_path TO {0};",
+                schema
+            ))
+            .await
+            .unwrap();
+        Some((client, schema))
+    }
+
+    #[tokio::test]
+    async fn test_verify_schema() {
+        let (mut client, schema) = match connect("schema").await {
+            Some(res) => res,
+            None => return,
+        };
+        PostgresStore::verify_schema(&mut client).await.unwrap();
+        let version: i32 = client
+            .query_one("SELECT version FROM portier_schema", &[])
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(version, 1);
+        let count: i64 = client
+            .query_one("SELECT COUNT(*) FROM key_sets", &[])
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(count, 0);
+
+        // Running again is a no-op.
+        PostgresStore::verify_schema(&mut client).await.unwrap();
+        let count: i64 = client
+            .query_one("SELECT COUNT(*) FROM portier_schema", &[])
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(count, 1);
+
+        client
+            .batch_execute(&format!("DROP SCHEMA {} CASCADE", schema))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_take_session() {
+        let (mut client, schema) = match connect("sessions").await {
+            Some(res) => res,
+            None => return,
+        };
+        PostgresStore::verify_schema(&mut client).await.unwrap();
+        let expires = unix_timestamp() as i64 + 60;
+        client
+            .execute(
+                "INSERT INTO sessions (id, data, expires) VALUES ('a', '{}', $1), ('b', '{}', 0)",
+                &[&expires],
+            )
+            .await
+            .unwrap();
+
+        // A session can only be taken once.
+        assert_eq!(
+            PostgresStore::take_session(&client, "a").await.unwrap(),
+            Some("{}".to_owned())
+        );
+        assert_eq!(
+            PostgresStore::take_session(&client, "a").await.unwrap(),
+            None
+        );
+
+        // Expired sessions are removed, but not returned.
+        assert_eq!(
+            PostgresStore::take_session(&client, "b").await.unwrap(),
+            None
+        );
+        let count: i64 = client
+            .query_one("SELECT COUNT(*) FROM sessions", &[])
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(count, 0);
+
+        client
+            .batch_execute(&format!("DROP SCHEMA {} CASCADE", schema))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replace_key_set() {
+        let (mut client, schema) = match connect("key_sets").await {
+            Some(res) => res,
+            None => return,
+        };
+        PostgresStore::verify_schema(&mut client).await.unwrap();
+        let encryption = StorageEncryption::new(None, SecureRandom::new().await);
+        let signing_alg = SigningAlgorithm::EdDsa;
+        let mut key_set = KeySet::empty(signing_alg);
+
+        // Only one worker can create the key set.
+        key_set.previous = Some("a".to_owned());
+        assert!(
+            PostgresStore::replace_key_set(&client, &encryption, &key_set, None)
+                .await
+                .unwrap()
+        );
+        key_set.previous = Some("b".to_owned());
+        assert!(
+            !PostgresStore::replace_key_set(&client, &encryption, &key_set, None)
+                .await
+                .unwrap()
+        );
+
+        // Updates only apply if the stored key set is unchanged.
+        let (stored, fetched) = PostgresStore::fetch_key_set(&client, &encryption, signing_alg)
+            .await
+            .unwrap();
+        assert_eq!(fetched.previous.as_deref(), Some("a"));
+        assert!(
+            PostgresStore::replace_key_set(&client, &encryption, &key_set, stored.as_deref())
+                .await
+                .unwrap()
+        );
+        key_set.previous = Some("c".to_owned());
+        assert!(
+            !PostgresStore::replace_key_set(&client, &encryption, &key_set, stored.as_deref())
+                .await
+                .unwrap()
+        );
+        let (_, fetched) = PostgresStore::fetch_key_set(&client, &encryption, signing_alg)
+            .await
+            .unwrap();
+        assert_eq!(fetched.previous.as_deref(), Some("b"));
+
+        client
+            .batch_execute(&format!("DROP SCHEMA {} CASCADE", schema))
+            .await
+            .unwrap();
+    }
+}
@@ -1,17 +1,24 @@
 use crate::agents::*;
 use crate::config::LimitConfig;
 use crate::crypto::SigningAlgorithm;
+use crate::metrics;
 use crate::utils::{
     agent::*,
-    redis::{locking, pubsub},
+    redis::{
+        connection::{Connection as RedisConn, Target, TlsConfig},
+        locking, pubsub,
+    },
+    storage_encryption::StorageEncryption,
     BoxError, SecureRandom,
 };
-use ::redis::{
-    aio::MultiplexedConnection as RedisConn, pipe, AsyncCommands, Client as RedisClient,
-    IntoConnectionInfo, RedisResult, Script,
-};
+use ::redis::{pipe, AsyncCommands, RedisResult, Script};
 use futures_util::future;
-use std::{convert::identity, sync::Arc, time::Duration};
+use std::{
+    convert::identity,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::{sync::broadcast::error::RecvError, task::JoinHandle};
 
 /// Internal message used to lock a key set.
 struct LockKeys(SigningAlgorithm);
@@ -43,6 +50,8 @@ pub struct RedisStore {
     id: Arc<[u8]>,
     /// The connection.
     conn: RedisConn,
+    /// Whether to wrap the variable part of session and rate limit keys in a hash tag.
+    hash_tags: bool,
     /// Pubsub client.
     pubsub: pubsub::Subscriber,
     /// Locking client.
@@ -61,28 +70,28 @@ pub struct RedisStore {
     decr_limit_script: Arc<Script>,
     /// Rate limit configuration.
     limit_configs: Vec<LimitConfig>,
+    /// Encryption of session data and key sets.
+    encryption: StorageEncryption,
+    /// Handle of the ping task.
+    ping_task: Option<JoinHandle<()>>,
 }
 
 impl RedisStore {
     pub async fn new(
-        mut url: String,
+        url: String,
+        tls: TlsConfig,
         expire_sessions: Duration,
         expire_cache: Duration,
         limit_configs: Vec<LimitConfig>,
+        encryption: StorageEncryption,
         fetcher: Addr<FetchAgent>,
         rng: SecureRandom,
     ) -> RedisResult<Self> {
-        if url.starts_with("http://") {
-            url = url.replace("http://", "redis://");
-        } else if !url.starts_with("redis://") {
-            url = format!("redis://{}", &url);
-        }
         let id = rng.generate_async(16).await.into();
-        let info = url.as_str().into_connection_info()?;
-        let pubsub = pubsub::connect(&info).await?;
-        let conn = RedisClient::open(info)?
-            .get_multiplexed_tokio_connection()
-            .await?;
+        let target = Target::new(&url, &tls)?;
+        let conn = RedisConn::connect(&target).await?;
+        let hash_tags = conn.is_cluster();
+        let pubsub = pubsub::connect(target).await?;
         let locking = locking::LockClient::new(conn.clone(), pubsub.clone(), rng);
 
         log::warn!("Storing sessions and keys in Redis at {}", url);
@@ -111,6 +120,7 @@ impl RedisStore {
         Ok(RedisStore {
             id,
             conn,
+            hash_tags,
             pubsub,
             locking,
             expire_sessions,
@@ -120,11 +130,39 @@ impl RedisStore {
             incr_limit_script,
             decr_limit_script,
             limit_configs,
+            encryption,
+            ping_task: None,
         })
     }
 
-    fn format_session_key(session_id: &str) -> String {
-        format!("session:{}", session_id)
+    /// Build a key from a prefix and variable part, adding a hash tag in a cluster.
+    ///
+    /// This keeps a session or rate limit in a single slot, even if keys are added for it later.
+    fn format_key(&self, prefix: &str, id: &str) -> String {
+        if self.hash_tags {
+            format!("{}{{{}}}", prefix, id)
+        } else {
+            format!("{}{}", prefix, id)
+        }
+    }
+
+    /// Extract the variable part from a key built with `format_key`.
+    fn parse_key<'a>(key: &'a str, prefix: &str) -> &'a str {
+        let id = &key[prefix.len()..];
+        if id.starts_with('{') && id.ends_with('}') {
+            &id[1..id.len() - 1]
+        } else {
+            id
+        }
+    }
+
+    fn format_session_key(&self, session_id: &str) -> String {
+        self.format_key("session:", session_id)
+    }
+
+    /// Convert the result of `TTL` to an expiry time.
+    fn expires_from_ttl(ttl: i64) -> SystemTime {
+        SystemTime::now() + Duration::from_secs(ttl.max(0) as u64)
     }
 }
 
@@ -132,18 +170,28 @@ impl Agent for RedisStore {
     fn started(&mut self, cx: Context<Self, AgentStarted>) {
         // Ping Redis at an interval.
         let mut conn = self.conn.clone();
-        tokio::spawn(async move {
+        self.ping_task = Some(tokio::spawn(async move {
             let mut interval = tokio::time::interval(Duration::from_secs(20));
             // Ignore the first (immediate) tick.
             interval.tick().await;
             loop {
                 interval.tick().await;
-                let _: String = ::redis::cmd("PING")
-                    .query_async(&mut conn)
-                    .await
-                    .expect("Redis ping failed");
+                // The connection reconnects by itself, so only log failures.
+                let res: RedisResult<String> = ::redis::cmd("PING").query_async(&mut conn).await;
+                if let Err(err) = res {
+                    log::error!("Redis ping failed: {}", err);
+                }
             }
-        });
+        }));
+        cx.reply(());
+    }
+
+    fn stopping(&mut self, cx: Context<Self, AgentStopping>) {
+        if let Some(ping_task) = self.ping_task.take() {
+            ping_task.abort();
+        }
+        // Dropping the agent closes the pubsub connection, which in turn ends the key
+        // subscription tasks.
         cx.reply(());
     }
 }
@@ -152,9 +200,10 @@ impl Handler<SaveSession> for RedisStore {
     fn handle(&mut self, message: SaveSession, cx: Context<Self, SaveSession>) {
         let mut conn = self.conn.clone();
         let ttl = self.expire_sessions;
+        let key = self.format_session_key(&message.session_id);
+        let encryption = self.encryption.clone();
         cx.reply_later(async move {
-            let key = Self::format_session_key(&message.session_id);
-            let data = serde_json::to_string(&message.data)?;
+            let data = encryption.seal_session(&message.session_id, &message.data)?;
             conn.set_ex(&key, data, ttl.as_secs() as usize).await?;
             Ok(())
         });
@@ -164,11 +213,12 @@ impl Handler<SaveSession> for RedisStore {
 impl Handler<GetSession> for RedisStore {
     fn handle(&mut self, message: GetSession, cx: Context<Self, GetSession>) {
         let mut conn = self.conn.clone();
+        let key = self.format_session_key(&message.session_id);
+        let encryption = self.encryption.clone();
         cx.reply_later(async move {
-            let key = Self::format_session_key(&message.session_id);
             let data: Option<String> = conn.get(&key).await?;
             if let Some(data) = data {
-                Ok(Some(serde_json::from_str(&data)?))
+                Ok(Some(encryption.open_session(&message.session_id, data)?))
             } else {
                 Ok(None)
             }
@@ -179,14 +229,37 @@ impl Handler<GetSession> for RedisStore {
 impl Handler<DeleteSession> for RedisStore {
     fn handle(&mut self, message: DeleteSession, cx: Context<Self, DeleteSession>) {
         let mut conn = self.conn.clone();
+        let key = self.format_session_key(&message.session_id);
         cx.reply_later(async move {
-            let key = Self::format_session_key(&message.session_id);
             conn.del(&key).await?;
             Ok(())
         });
     }
 }
 
+impl Handler<TakeSession> for RedisStore {
+    fn handle(&mut self, message: TakeSession, cx: Context<Self, TakeSession>) {
+        let mut conn = self.conn.clone();
+        let key = self.format_session_key(&message.session_id);
+        let encryption = self.encryption.clone();
+        cx.reply_later(async move {
+            // Use a transaction instead of `GETDEL`, which requires Redis 6.2.
+            let (data, deleted): (Option<String>, usize) = pipe()
+                .atomic()
+                .get(&key)
+                .del(&key)
+                .query_async(&mut conn)
+                .await?;
+            match data {
+                Some(data) if deleted == 1 => {
+                    Ok(Some(encryption.open_session(&message.session_id, data)?))
+                }
+                _ => Ok(None),
+            }
+        });
+    }
+}
+
 impl Handler<FetchUrlCached> for RedisStore {
     fn handle(&mut self, message: FetchUrlCached, cx: Context<Self, FetchUrlCached>) {
         let mut conn = self.conn.clone();
@@ -196,10 +269,11 @@ impl Handler<FetchUrlCached> for RedisStore {
         cx.reply_later(async move {
             let key = format!("cache:{}", message.url);
             let _lock = locking.lock(format!("lock:{}", key).as_bytes()).await;
-            if let Some(data) = conn.get(key).await? {
+            if let Some(data) = conn.get(&key).await? {
+                metrics::FETCH_CACHE.with_label_values(&["hit"]).inc();
                 Ok(data)
             } else {
-                let key = message.url.as_str().to_owned();
+                metrics::FETCH_CACHE.with_label_values(&["miss"]).inc();
                 let result = fetcher.send(FetchUrl::get(&message.url)).await?;
                 let ttl = std::cmp::max(expire_cache, result.max_age);
                 conn.set_ex(key, result.data.clone(), ttl.as_secs() as usize)
@@ -218,7 +292,8 @@ impl Handler<IncrAndTestLimits> for RedisStore {
             .limit_configs
             .iter()
             .map(|config| {
-                let key = message.input.build_key(&config, "rate-limit:", "|");
+                let key =
+                    self.format_key("rate-limit:", &message.input.build_key(&config, "", "|"));
                 (config.clone(), key)
             })
             .collect();
@@ -234,7 +309,13 @@ impl Handler<IncrAndTestLimits> for RedisStore {
                         .arg(config.extend_window)
                         .invoke_async(&mut conn)
                         .await?;
-                    Ok::<_, BoxError>(count <= config.max_count)
+                    if count > config.max_count {
+                        metrics::RATE_LIMIT_REJECTIONS
+                            .with_label_values(&[&config.id.to_string()])
+                            .inc();
+                        return Ok::<_, BoxError>(false);
+                    }
+                    Ok(true)
                 }
             }))
             .await?;
@@ -252,7 +333,7 @@ impl Handler<DecrLimits> for RedisStore {
             .iter()
             .filter_map(|config| {
                 if config.decr_complete {
-                    Some(message.input.build_key(&config, "rate-limit:", "|"))
+                    Some(self.format_key("rate-limit:", &message.input.build_key(&config, "", "|")))
                 } else {
                     None
                 }
@@ -283,6 +364,20 @@ impl Handler<EnableRotatingKeys> for RedisStore {
         let mut pubsub = self.pubsub.clone();
         self.key_manager = Some(message.key_manager.clone());
         cx.reply_later(async move {
+            // Updates may have been missed while the pubsub connection was down, so reload all
+            // keys after a reconnect.
+            let mut reconnects = pubsub.reconnects();
+            let signing_algs = message.signing_algs.clone();
+            let me2 = me.clone();
+            tokio::spawn(async move {
+                // This ends when the pubsub connection was closed, because the store stopped.
+                while reconnects.changed().await.is_ok() {
+                    log::warn!("Reloading keys after Redis pubsub reconnect");
+                    for signing_alg in &signing_algs {
+                        me2.send(UpdateKeysLocked(*signing_alg));
+                    }
+                }
+            });
             for signing_alg in &message.signing_algs {
                 let signing_alg = *signing_alg;
                 // Listen for key changes by other workers.
@@ -292,7 +387,12 @@ impl Handler<EnableRotatingKeys> for RedisStore {
                 let my_id2 = my_id.clone();
                 tokio::spawn(async move {
                     loop {
-                        let from_id = sub.recv().await.expect("Redis keys subscription failed");
+                        let from_id = match sub.recv().await {
+                            Ok(from_id) => from_id,
+                            // The pubsub connection was closed, because the store stopped.
+                            Err(RecvError::Closed) => break,
+                            Err(err) => panic!("Redis keys subscription failed: {}", err),
+                        };
                         if from_id.as_slice() != my_id2.as_ref() {
                             me2.send(UpdateKeysLocked(signing_alg));
                         }
@@ -311,14 +411,20 @@ impl Handler<RotateKeysLocked> for RedisStore {
         let key_manager = self.key_manager.as_ref().unwrap().clone();
         cx.reply_later(async move {
             let lock = me.send(LockKeys(message.0)).await;
-            let key_set = me
-                .send(FetchKeys(message.0))
-                .await
-                .expect("Failed to fetch keys from Redis");
+            let key_set = match me.send(FetchKeys(message.0)).await {
+                Ok(key_set) => key_set,
+                Err(err) => {
+                    log::error!("Failed to fetch keys from Redis: {}", err);
+                    drop(lock);
+                    return retry_rotate_keys(me, message.0);
+                }
+            };
             if let Some(key_set) = key_manager.send(RotateKeys(key_set)).await {
-                me.send(SaveKeys(key_set.clone()))
-                    .await
-                    .expect("Failed to save keys to Redis");
+                if let Err(err) = me.send(SaveKeys(key_set.clone())).await {
+                    log::error!("Failed to save keys to Redis: {}", err);
+                    drop(lock);
+                    return retry_rotate_keys(me, message.0);
+                }
                 drop(lock);
                 key_manager.send(UpdateKeys(key_set)).await;
             }
@@ -330,9 +436,100 @@ impl Handler<ImportKeySet> for RedisStore {
     fn handle(&mut self, message: ImportKeySet, cx: Context<Self, ImportKeySet>) {
         let me = cx.addr().clone();
         cx.reply_later(async move {
-            me.send(SaveKeys(message.0))
-                .await
-                .expect("Failed to save keys to Redis");
+            if let Err(err) = me.send(SaveKeys(message.0)).await {
+                log::error!("Failed to save keys to Redis: {}", err);
+            }
+        });
+    }
+}
+
+impl Handler<CheckStore> for RedisStore {
+    fn handle(&mut self, _message: CheckStore, cx: Context<Self, CheckStore>) {
+        let mut conn = self.conn.clone();
+        cx.reply_later(async move {
+            let _: String = ::redis::cmd("PING").query_async(&mut conn).await?;
+            Ok(())
+        });
+    }
+}
+
+impl Handler<ListSessions> for RedisStore {
+    fn handle(&mut self, _message: ListSessions, cx: Context<Self, ListSessions>) {
+        let mut conn = self.conn.clone();
+        let encryption = self.encryption.clone();
+        cx.reply_later(async move {
+            let keys = conn.scan_keys("session:*").await?;
+            let mut entries = Vec::with_capacity(keys.len());
+            for key in keys {
+                let (data, ttl): (Option<String>, i64) =
+                    pipe().get(&key).ttl(&key).query_async(&mut conn).await?;
+                // The session may have expired since the scan.
+                if let Some(data) = data {
+                    let session_id = Self::parse_key(&key, "session:").to_owned();
+                    entries.push(SessionEntry {
+                        data: encryption.open_session(&session_id, data)?,
+                        session_id,
+                        expires: Self::expires_from_ttl(ttl),
+                    });
+                }
+            }
+            Ok(entries)
+        });
+    }
+}
+
+impl Handler<ListLimits> for RedisStore {
+    fn handle(&mut self, _message: ListLimits, cx: Context<Self, ListLimits>) {
+        let mut conn = self.conn.clone();
+        cx.reply_later(async move {
+            let keys = conn.scan_keys("rate-limit:*").await?;
+            let mut entries = Vec::with_capacity(keys.len());
+            for key in keys {
+                let (count, ttl): (Option<usize>, i64) =
+                    pipe().get(&key).ttl(&key).query_async(&mut conn).await?;
+                if let Some(count) = count {
+                    entries.push(LimitEntry {
+                        key: Self::parse_key(&key, "rate-limit:").to_owned(),
+                        count,
+                        expires: Self::expires_from_ttl(ttl),
+                    });
+                }
+            }
+            Ok(entries)
+        });
+    }
+}
+
+impl Handler<ResetLimit> for RedisStore {
+    fn handle(&mut self, message: ResetLimit, cx: Context<Self, ResetLimit>) {
+        let mut conn = self.conn.clone();
+        let key = self.format_key("rate-limit:", &message.key);
+        cx.reply_later(async move {
+            let count: usize = conn.del(key).await?;
+            Ok(count > 0)
+        });
+    }
+}
+
+impl Handler<GetKeySet> for RedisStore {
+    fn handle(&mut self, message: GetKeySet, cx: Context<Self, GetKeySet>) {
+        let me = cx.addr().clone();
+        cx.reply_later(async move { Ok(me.send(FetchKeys(message.0)).await?) });
+    }
+}
+
+impl Handler<FlushCache> for RedisStore {
+    fn handle(&mut self, _message: FlushCache, cx: Context<Self, FlushCache>) {
+        let mut conn = self.conn.clone();
+        cx.reply_later(async move {
+            let keys = conn.scan_keys("cache:*").await?;
+            // Delete keys one by one, because keys may be in different slots in a cluster.
+            let mut count = 0;
+            for key in keys {
+                let deleted: usize = conn.del(key).await?;
+                count += deleted;
+            }
+            Ok(count)
         });
     }
 }
@@ -350,11 +547,16 @@ impl Handler<FetchKeys> for RedisStore {
         let mut conn = self.conn.clone();
         let signing_alg = message.0;
         let db_key = format!("keys:{}", signing_alg);
+        let encryption = self.encryption.clone();
         cx.reply_later(async move {
             let key_set: Option<String> = conn.get(db_key).await?;
             let key_set = key_set.map_or_else(
                 || KeySet::empty(signing_alg),
-                |data| serde_json::from_str(&data).expect("Invalid key set JSON in Redis"),
+                |data| {
+                    encryption
+                        .open_key_set(signing_alg, data)
+                        .unwrap_or_else(|err| panic!("Invalid key set in Redis: {}", err))
+                },
             );
             Ok(key_set)
         })
@@ -366,7 +568,7 @@ impl Handler<SaveKeys> for RedisStore {
         let mut conn = self.conn.clone();
         let signing_alg = message.0.signing_alg;
         let db_key = format!("keys:{}", signing_alg);
-        let data = serde_json::to_string(&message.0).expect("Could not encode key set as JSON");
+        let data = self.encryption.seal_key_set(&message.0);
         let mut pipe = pipe();
         pipe.atomic()
             .set(db_key, data)
@@ -380,13 +582,25 @@ impl Handler<UpdateKeysLocked> for RedisStore {
         let me = cx.addr().clone();
         let key_manager = self.key_manager.as_ref().unwrap().clone();
         cx.reply_later(async move {
-            let key_set = {
+            let res = {
                 let _lock = me.send(LockKeys(message.0)).await;
-                me.send(FetchKeys(message.0))
-                    .await
-                    .expect("Failed to fetch keys from Redis")
+                me.send(FetchKeys(message.0)).await
             };
-            key_manager.send(UpdateKeys(key_set)).await;
+            match res {
+                Ok(key_set) => key_manager.send(UpdateKeys(key_set)).await,
+                Err(err) => {
+                    // Keys may have changed, so keep trying until we have the latest.
+                    log::error!(
+                        "Failed to fetch keys from Redis, retrying in {}s: {}",
+                        KEYS_RETRY_DELAY.as_secs(),
+                        err
+                    );
+                    tokio::spawn(async move {
+                        tokio::time::sleep(KEYS_RETRY_DELAY).await;
+                        me.send(UpdateKeysLocked(message.0));
+                    });
+                }
+            }
         });
     }
 }
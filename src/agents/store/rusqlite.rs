@@ -1,11 +1,17 @@
 use crate::agents::*;
 use crate::config::LimitConfig;
 use crate::crypto::SigningAlgorithm;
-use crate::utils::{agent::*, unix_timestamp};
-use ::rusqlite::{Connection, Error as SqlError, OptionalExtension, ToSql, NO_PARAMS};
-use std::path::PathBuf;
-use std::time::Duration;
-use tokio::task::spawn_blocking;
+use crate::metrics;
+use crate::utils::{agent::*, storage_encryption::StorageEncryption, unix_timestamp};
+use ::rusqlite::{
+    Connection, Error as SqlError, OptionalExtension, ToSql, TransactionBehavior, NO_PARAMS,
+};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::sync::Semaphore;
+use tokio::task::{spawn_blocking, JoinHandle};
 use url::Url;
 
 macro_rules! params {
@@ -17,6 +23,102 @@ macro_rules! params {
 /// Database file `application_id` value. 'Prtr' in hex.
 const APP_ID: u32 = 0x5072_7472;
 
+/// Interval at which key sets are checked for changes made by other processes.
+const KEYS_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Schema migrations, in order.
+///
+/// Each entry upgrades the schema by one version, and `user_version` holds the number of
+/// migrations applied. Existing entries must never be changed; add a new entry instead.
+const MIGRATIONS: &[&str] = &[
+    // Version 1: initial schema.
+    "
+    CREATE TABLE sessions (
+        id TEXT NOT NULL PRIMARY KEY,
+        data TEXT NOT NULL,
+        expires INTEGER NOT NULL
+    );
+    CREATE INDEX sessions_expires ON sessions (expires);
+
+    CREATE TABLE cache_entries (
+        url TEXT NOT NULL PRIMARY KEY,
+        data TEXT NOT NULL,
+        expires INTEGER NOT NULL
+    );
+    CREATE INDEX cache_entries_expires ON cache_entries (expires);
+
+    CREATE TABLE rate_limits (
+        id TEXT NOT NULL PRIMARY KEY,
+        value INTEGER NOT NULL,
+        expires INTEGER NOT NULL
+    );
+    CREATE INDEX rate_limits_expires ON rate_limits (expires);
+
+    CREATE TABLE key_sets (
+        signing_alg TEXT NOT NULL PRIMARY KEY,
+        key_set TEXT NOT NULL
+    );
+    ",
+    // Version 2: track key set changes, so processes sharing the database can detect rotations.
+    "
+    ALTER TABLE key_sets ADD COLUMN version INTEGER NOT NULL DEFAULT 0;
+    ",
+];
+
+/// Connection settings for the SQLite store.
+#[derive(Clone, Debug)]
+pub struct SqliteOptions {
+    /// How long to wait for a lock held by another connection before failing.
+    pub busy_timeout: Duration,
+    /// Number of connections used for reads.
+    pub read_connections: usize,
+}
+
+/// Pool of read-only connections.
+///
+/// In WAL mode, readers don't block the writer or each other, so reads that don't need to be
+/// ordered with writes run on these connections on blocking threads, instead of in the agent.
+#[derive(Clone)]
+struct ReadPool {
+    conns: Arc<Mutex<Vec<Connection>>>,
+    permits: Arc<Semaphore>,
+}
+
+impl ReadPool {
+    fn open(sqlite_db: &Path, options: &SqliteOptions) -> Result<Self, SqlError> {
+        let conns = (0..options.read_connections)
+            .map(|_| {
+                let conn = Connection::open(sqlite_db)?;
+                conn.busy_timeout(options.busy_timeout)?;
+                conn.execute_batch("PRAGMA query_only = ON")?;
+                Ok(conn)
+            })
+            .collect::<Result<Vec<_>, SqlError>>()?;
+        Ok(ReadPool {
+            permits: Arc::new(Semaphore::new(conns.len())),
+            conns: Arc::new(Mutex::new(conns)),
+        })
+    }
+
+    /// Run a function with a connection from the pool, on a blocking thread.
+    async fn run<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&Connection) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let _permit = self.permits.acquire().await.unwrap();
+        let conn = self.conns.lock().unwrap().pop().unwrap();
+        let conns = self.conns.clone();
+        spawn_blocking(move || {
+            let result = f(&conn);
+            conns.lock().unwrap().push(conn);
+            result
+        })
+        .await
+        .unwrap()
+    }
+}
+
 /// Message sent at an interval to collect garbage.
 struct Gc;
 impl Message for Gc {
@@ -39,6 +141,24 @@ impl Message for SaveKeys {
     type Reply = Result<(), SqlError>;
 }
 
+/// Message used internally to save a rotated key set.
+///
+/// The key set is only saved if the stored version is unchanged, and the reply indicates whether
+/// it was saved.
+struct ReplaceKeys {
+    key_set: KeySet,
+    version: i64,
+}
+impl Message for ReplaceKeys {
+    type Reply = Result<bool, SqlError>;
+}
+
+/// Message sent at an interval to check for key set changes made by other processes.
+struct PollKeys;
+impl Message for PollKeys {
+    type Reply = ();
+}
+
 /// Store implementation using `rusqlite`.
 pub struct RusqliteStore {
     /// TTL of session keys
@@ -47,27 +167,41 @@ pub struct RusqliteStore {
     expire_cache: Duration,
     /// Rate limit configuration.
     limit_configs: Vec<LimitConfig>,
-    /// SQLite connection.
+    /// Encryption of session data and key sets.
+    encryption: StorageEncryption,
+    /// SQLite connection, used for all writes.
     conn: Connection,
+    /// SQLite connections used for reads.
+    read_pool: ReadPool,
     /// The agent used for fetching on cache miss.
     fetcher: Addr<FetchAgent>,
     /// Key manager if rotating keys are enabled.
     key_manager: Option<Addr<RotatingKeys>>,
+    /// Last seen version of each enabled key set.
+    key_versions: HashMap<SigningAlgorithm, i64>,
+    /// Handle of the garbage collection task.
+    gc_task: Option<JoinHandle<()>>,
+    /// Handle of the key set polling task.
+    keys_task: Option<JoinHandle<()>>,
 }
 
 impl RusqliteStore {
     pub async fn new(
         sqlite_db: PathBuf,
+        options: SqliteOptions,
         expire_sessions: Duration,
         expire_cache: Duration,
         limit_configs: Vec<LimitConfig>,
+        encryption: StorageEncryption,
         fetcher: Addr<FetchAgent>,
     ) -> Result<Self, SqlError> {
         spawn_blocking(move || {
-            let conn = Connection::open(&sqlite_db)?;
-            conn.busy_timeout(Duration::from_millis(500))?;
+            let mut conn = Connection::open(&sqlite_db)?;
+            conn.busy_timeout(options.busy_timeout)?;
+            Self::enable_wal(&conn)?;
             Self::verify_app_id(&conn)?;
-            Self::verify_schema(&conn)?;
+            Self::migrate(&mut conn, &sqlite_db)?;
+            let read_pool = ReadPool::open(&sqlite_db, &options)?;
             log::warn!(
                 "Storing sessions and keys in SQLite at: {}",
                 sqlite_db.display()
@@ -78,15 +212,33 @@ impl RusqliteStore {
                 expire_sessions,
                 expire_cache,
                 limit_configs,
+                encryption,
                 conn,
+                read_pool,
                 fetcher,
                 key_manager: None,
+                key_versions: HashMap::new(),
+                gc_task: None,
+                keys_task: None,
             })
         })
         .await
         .unwrap()
     }
 
+    /// Switch the database to WAL mode. This setting is persistent.
+    fn enable_wal(conn: &Connection) -> Result<(), SqlError> {
+        let mode: String =
+            conn.query_row("PRAGMA journal_mode = WAL", NO_PARAMS, |row| row.get(0))?;
+        if !mode.eq_ignore_ascii_case("wal") {
+            log::warn!(
+                "Could not enable WAL mode for the SQLite database, using journal mode: {}",
+                mode
+            );
+        }
+        Ok(())
+    }
+
     fn verify_app_id(conn: &Connection) -> Result<(), SqlError> {
         // If this is 0, assume the file was just now created.
         let schema_version: u32 =
@@ -111,72 +263,109 @@ impl RusqliteStore {
         Ok(())
     }
 
-    fn verify_schema(conn: &Connection) -> Result<(), SqlError> {
+    fn user_version(conn: &Connection) -> Result<usize, SqlError> {
         let user_version: u32 =
             conn.query_row("SELECT * FROM pragma_user_version()", NO_PARAMS, |row| {
                 row.get(0)
             })?;
-        match user_version {
-            0 => Self::init_schema(conn),
-            1 => Ok(()),
-            _ => panic!(
-                "The SQLite database has an unknown version: {}",
-                user_version
-            ),
-        }
+        Ok(user_version as usize)
     }
 
-    fn init_schema(conn: &Connection) -> Result<(), SqlError> {
-        conn.execute_batch(
-            "
-            BEGIN;
-
-            CREATE TABLE sessions (
-                id TEXT NOT NULL PRIMARY KEY,
-                data TEXT NOT NULL,
-                expires INTEGER NOT NULL
-            );
-            CREATE INDEX sessions_expires ON sessions (expires);
-
-            CREATE TABLE cache_entries (
-                url TEXT NOT NULL PRIMARY KEY,
-                data TEXT NOT NULL,
-                expires INTEGER NOT NULL
-            );
-            CREATE INDEX cache_entries_expires ON cache_entries (expires);
+    /// Bring the schema up-to-date by running all pending migrations.
+    ///
+    /// Migrations run in a single transaction that takes the write lock up front, so brokers
+    /// starting at the same time don't migrate twice. If the database already contains data, it
+    /// is first copied to a backup file next to the database.
+    fn migrate(conn: &mut Connection, sqlite_db: &Path) -> Result<(), SqlError> {
+        let latest = MIGRATIONS.len();
+        if Self::user_version(conn)? == latest {
+            return Ok(());
+        }
 
-            CREATE TABLE rate_limits (
-                id TEXT NOT NULL PRIMARY KEY,
-                value INTEGER NOT NULL,
-                expires INTEGER NOT NULL
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        // Another process may have migrated while we were waiting for the lock.
+        let user_version = Self::user_version(&tx)?;
+        if user_version > latest {
+            panic!(
+                "The SQLite database has an unknown version: {} (this broker supports up to {})",
+                user_version, latest
             );
-            CREATE INDEX rate_limits_expires ON rate_limits (expires);
+        }
+        if user_version == latest {
+            return Ok(());
+        }
 
-            CREATE TABLE key_sets (
-                signing_alg TEXT NOT NULL PRIMARY KEY,
-                key_set TEXT NOT NULL
+        if user_version > 0 {
+            let backup = format!(
+                "{}.v{}-{}.bak",
+                sqlite_db.display(),
+                user_version,
+                unix_timestamp()
             );
+            // VACUUM can't run inside a transaction, so back up using a separate connection. This
+            // still sees the data we're about to migrate, because we hold the write lock.
+            Connection::open(sqlite_db)?.execute("VACUUM INTO ?1", &[&backup])?;
+            log::warn!("Backed up the SQLite database to: {}", backup);
+        }
 
-            PRAGMA user_version = 1;
-            COMMIT;
-            ",
-        )?;
-        Ok(())
+        for (idx, migration) in MIGRATIONS.iter().enumerate().skip(user_version) {
+            tx.execute_batch(migration)?;
+            log::info!("Migrated the SQLite database to version {}", idx + 1);
+        }
+        // Note: can't use parameter binding in pragma.
+        tx.execute(&format!("PRAGMA user_version = {}", latest), NO_PARAMS)?;
+        tx.commit()
     }
 
-    fn get_key_set(&mut self, signing_alg: SigningAlgorithm) -> KeySet {
-        self.conn
+    /// Delete a session, returning its data if it existed and had not expired.
+    ///
+    /// Other brokers may share the database, so this takes the write lock before reading.
+    /// (`DELETE ... RETURNING` requires a newer SQLite than we bundle.)
+    fn take_session(conn: &mut Connection, session_id: &str) -> Result<Option<String>, SqlError> {
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let now = unix_timestamp() as i64;
+        let data: Option<String> = tx
             .query_row(
-                "SELECT key_set FROM key_sets WHERE signing_alg = ?1 LIMIT 1",
-                params![&signing_alg.as_str()],
+                "SELECT data FROM sessions WHERE id = ?1 AND expires > ?2 LIMIT 1",
+                params![&session_id, &now],
                 |row| row.get(0),
             )
-            .optional()
-            .expect("Could not fetch keys from SQLite")
-            .map_or_else(
-                || KeySet::empty(signing_alg),
-                |data: String| serde_json::from_str(&data).expect("Invalid key set JSON in SQLite"),
+            .optional()?;
+        let deleted = tx.execute("DELETE FROM sessions WHERE id = ?1", &[&session_id])?;
+        tx.commit()?;
+        Ok(data.filter(|_| deleted == 1))
+    }
+
+    /// Fetch a key set and its version. The version is 0 if the key set doesn't exist.
+    fn get_key_set_version(
+        &mut self,
+        signing_alg: SigningAlgorithm,
+    ) -> Result<(KeySet, i64), SqlError> {
+        let row = self
+            .conn
+            .query_row(
+                "SELECT key_set, version FROM key_sets WHERE signing_alg = ?1 LIMIT 1",
+                params![&signing_alg.as_str()],
+                |row| Ok((row.get(0)?, row.get(1)?)),
             )
+            .optional()?;
+        Ok(row.map_or_else(
+            || (KeySet::empty(signing_alg), 0),
+            |(data, version): (String, i64)| {
+                let key_set = self
+                    .encryption
+                    .open_key_set(signing_alg, data)
+                    .unwrap_or_else(|err| panic!("Invalid key set in SQLite: {}", err));
+                (key_set, version)
+            },
+        ))
+    }
+
+    /// Fetch a key set, and remember its version so we don't report the same change twice.
+    fn reload_key_set(&mut self, signing_alg: SigningAlgorithm) -> Result<KeySet, SqlError> {
+        let (key_set, version) = self.get_key_set_version(signing_alg)?;
+        self.key_versions.insert(signing_alg, version);
+        Ok(key_set)
     }
 }
 
@@ -184,13 +373,23 @@ impl Agent for RusqliteStore {
     fn started(&mut self, cx: Context<Self, AgentStarted>) {
         // Start the garbage collection loop.
         let addr = cx.addr().clone();
-        tokio::spawn(async move {
+        self.gc_task = Some(tokio::spawn(async move {
             let mut interval = tokio::time::interval(Duration::from_secs(60));
             loop {
                 interval.tick().await;
                 addr.send(Gc).await;
             }
-        });
+        }));
+        cx.reply(());
+    }
+
+    fn stopping(&mut self, cx: Context<Self, AgentStopping>) {
+        if let Some(gc_task) = self.gc_task.take() {
+            gc_task.abort();
+        }
+        if let Some(keys_task) = self.keys_task.take() {
+            keys_task.abort();
+        }
         cx.reply(());
     }
 }
@@ -198,15 +397,12 @@ impl Agent for RusqliteStore {
 impl Handler<Gc> for RusqliteStore {
     fn handle(&mut self, _message: Gc, cx: Context<Self, Gc>) {
         let now = unix_timestamp() as i64;
-        self.conn
-            .execute("DELETE FROM sessions WHERE expires <= ?1", &[now])
-            .expect("session cleanup failed");
-        self.conn
-            .execute("DELETE FROM cache_entries WHERE expires <= ?1", &[now])
-            .expect("cache cleanup failed");
-        self.conn
-            .execute("DELETE FROM rate_limits WHERE expires <= ?1", &[now])
-            .expect("rate limits cleanup failed");
+        for table in &["sessions", "cache_entries", "rate_limits"] {
+            let query = format!("DELETE FROM {} WHERE expires <= ?1", table);
+            if let Err(err) = self.conn.execute(&query, &[now]) {
+                log::error!("Failed to clean up {} in SQLite: {}", table, err);
+            }
+        }
         cx.reply(())
     }
 }
@@ -215,7 +411,9 @@ impl Handler<SaveSession> for RusqliteStore {
     fn handle(&mut self, message: SaveSession, cx: Context<Self, SaveSession>) {
         cx.reply_with(move || {
             let expires = (unix_timestamp() + self.expire_sessions.as_secs()) as i64;
-            let data = serde_json::to_string(&message.data)?;
+            let data = self
+                .encryption
+                .seal_session(&message.session_id, &message.data)?;
             self.conn.execute(
                 "REPLACE INTO sessions (id, data, expires) VALUES (?1, ?2, ?3)",
                 params![&message.session_id, &data, &expires],
@@ -227,18 +425,23 @@ impl Handler<SaveSession> for RusqliteStore {
 
 impl Handler<GetSession> for RusqliteStore {
     fn handle(&mut self, message: GetSession, cx: Context<Self, GetSession>) {
-        cx.reply_with(move || {
-            let now = unix_timestamp() as i64;
-            let data: Option<String> = self
-                .conn
-                .query_row(
-                    "SELECT data FROM sessions WHERE id = ?1 AND expires > ?2 LIMIT 1",
-                    params![&message.session_id, &now],
-                    |row| row.get(0),
-                )
-                .optional()?;
+        let read_pool = self.read_pool.clone();
+        let encryption = self.encryption.clone();
+        cx.reply_later(async move {
+            let session_id = message.session_id.clone();
+            let data: Option<String> = read_pool
+                .run(move |conn| {
+                    let now = unix_timestamp() as i64;
+                    conn.query_row(
+                        "SELECT data FROM sessions WHERE id = ?1 AND expires > ?2 LIMIT 1",
+                        params![&session_id, &now],
+                        |row| row.get(0),
+                    )
+                    .optional()
+                })
+                .await?;
             if let Some(data) = data {
-                let data = serde_json::from_str(&data)?;
+                let data = encryption.open_session(&message.session_id, data)?;
                 Ok(Some(data))
             } else {
                 Ok(None)
@@ -257,27 +460,46 @@ impl Handler<DeleteSession> for RusqliteStore {
     }
 }
 
+impl Handler<TakeSession> for RusqliteStore {
+    fn handle(&mut self, message: TakeSession, cx: Context<Self, TakeSession>) {
+        cx.reply_with(move || {
+            let data = Self::take_session(&mut self.conn, &message.session_id)?;
+            if let Some(data) = data {
+                Ok(Some(
+                    self.encryption.open_session(&message.session_id, data)?,
+                ))
+            } else {
+                Ok(None)
+            }
+        });
+    }
+}
+
 impl Handler<FetchUrlCached> for RusqliteStore {
     fn handle(&mut self, message: FetchUrlCached, cx: Context<Self, FetchUrlCached>) {
         // TODO: Add locking to coordinate multiple fetches for the same resource.
-        let now = unix_timestamp() as i64;
-        let data: Result<Option<String>, SqlError> = self
-            .conn
-            .query_row(
-                "SELECT data FROM cache_entries WHERE url = ?1 AND expires > ?2 LIMIT 1",
-                params![&message.url.as_str(), &now],
-                |row| row.get(0),
-            )
-            .optional();
-        match data {
-            Err(e) => return cx.reply(Err(e.into())),
-            Ok(Some(data)) => return cx.reply(Ok(data)),
-            Ok(None) => {}
-        }
         let me = cx.addr().clone();
+        let read_pool = self.read_pool.clone();
         let fetcher = self.fetcher.clone();
         let expire_cache = self.expire_cache;
         cx.reply_later(async move {
+            let url = message.url.clone();
+            let data: Option<String> = read_pool
+                .run(move |conn| {
+                    let now = unix_timestamp() as i64;
+                    conn.query_row(
+                        "SELECT data FROM cache_entries WHERE url = ?1 AND expires > ?2 LIMIT 1",
+                        params![&url.as_str(), &now],
+                        |row| row.get(0),
+                    )
+                    .optional()
+                })
+                .await?;
+            if let Some(data) = data {
+                metrics::FETCH_CACHE.with_label_values(&["hit"]).inc();
+                return Ok(data);
+            }
+            metrics::FETCH_CACHE.with_label_values(&["miss"]).inc();
             let result = fetcher.send(FetchUrl::get(&message.url.clone())).await?;
             let ttl = std::cmp::max(expire_cache, result.max_age);
             me.send(SaveCache {
@@ -335,7 +557,12 @@ impl Handler<IncrAndTestLimits> for RusqliteStore {
                     |row| row.get(0),
                 )?;
                 tx.commit()?;
-                ok = ok && count as usize <= config.max_count;
+                if count as usize > config.max_count {
+                    metrics::RATE_LIMIT_REJECTIONS
+                        .with_label_values(&[&config.id.to_string()])
+                        .inc();
+                    ok = false;
+                }
             }
             Ok(ok)
         })
@@ -372,9 +599,21 @@ impl Handler<EnableRotatingKeys> for RusqliteStore {
         self.key_manager = Some(message.key_manager.clone());
         let mut update_msgs = Vec::with_capacity(message.signing_algs.len());
         for signing_alg in &message.signing_algs {
-            let key_set = self.get_key_set(*signing_alg);
-            update_msgs.push(UpdateKeys(key_set.clone()));
+            let key_set = self
+                .reload_key_set(*signing_alg)
+                .expect("Could not fetch keys from SQLite");
+            update_msgs.push(UpdateKeys(key_set));
         }
+        // Other processes sharing the database may rotate keys, so poll for changes.
+        let addr = cx.addr().clone();
+        self.keys_task = Some(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(KEYS_POLL_INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                addr.send(PollKeys).await;
+            }
+        }));
         cx.reply_later(async move {
             for update_msg in update_msgs {
                 message.key_manager.send(update_msg).await;
@@ -386,14 +625,40 @@ impl Handler<EnableRotatingKeys> for RusqliteStore {
 impl Handler<RotateKeysLocked> for RusqliteStore {
     fn handle(&mut self, message: RotateKeysLocked, cx: Context<Self, RotateKeysLocked>) {
         let me = cx.addr().clone();
-        let key_set = self.get_key_set(message.0);
+        let (key_set, version) = match self.get_key_set_version(message.0) {
+            Ok(res) => res,
+            Err(err) => {
+                log::error!("Could not fetch keys from SQLite: {}", err);
+                retry_rotate_keys(me, message.0);
+                return cx.reply(());
+            }
+        };
         let key_manager = self.key_manager.as_ref().unwrap().clone();
         cx.reply_later(async move {
+            // Instead of locking, we only save if no other process rotated keys in the mean time.
             if let Some(key_set) = key_manager.send(RotateKeys(key_set)).await {
-                me.send(SaveKeys(key_set.clone()))
+                let saved = match me
+                    .send(ReplaceKeys {
+                        key_set: key_set.clone(),
+                        version,
+                    })
                     .await
-                    .expect("Could not save keys to SQLite");
-                key_manager.send(UpdateKeys(key_set)).await;
+                {
+                    Ok(saved) => saved,
+                    Err(err) => {
+                        log::error!("Could not save keys to SQLite: {}", err);
+                        return retry_rotate_keys(me, message.0);
+                    }
+                };
+                if saved {
+                    key_manager.send(UpdateKeys(key_set)).await;
+                } else {
+                    log::info!(
+                        "Another process rotated {} keys first, using those instead",
+                        message.0
+                    );
+                    me.send(PollKeys).await;
+                }
             }
         });
     }
@@ -403,20 +668,110 @@ impl Handler<ImportKeySet> for RusqliteStore {
     fn handle(&mut self, message: ImportKeySet, cx: Context<Self, ImportKeySet>) {
         let me = cx.addr().clone();
         cx.reply_later(async move {
-            me.send(SaveKeys(message.0))
+            if let Err(err) = me.send(SaveKeys(message.0)).await {
+                log::error!("Could not save keys to SQLite: {}", err);
+            }
+        });
+    }
+}
+
+impl Handler<CheckStore> for RusqliteStore {
+    fn handle(&mut self, _message: CheckStore, cx: Context<Self, CheckStore>) {
+        cx.reply_with(move || {
+            let _: i64 =
+                self.conn
+                    .query_row("SELECT COUNT(*) FROM key_sets", NO_PARAMS, |row| row.get(0))?;
+            Ok(())
+        });
+    }
+}
+
+impl Handler<ListSessions> for RusqliteStore {
+    fn handle(&mut self, _message: ListSessions, cx: Context<Self, ListSessions>) {
+        let read_pool = self.read_pool.clone();
+        let encryption = self.encryption.clone();
+        cx.reply_later(async move {
+            read_pool
+                .run(move |conn| {
+                    let now = unix_timestamp() as i64;
+                    let mut stmt =
+                        conn.prepare("SELECT id, data, expires FROM sessions WHERE expires > ?1")?;
+                    let rows =
+                        stmt.query_map(&[now], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+                    let mut entries = Vec::new();
+                    for row in rows {
+                        let (session_id, data, expires): (String, String, i64) = row?;
+                        entries.push(SessionEntry {
+                            data: encryption.open_session(&session_id, data)?,
+                            session_id,
+                            expires: UNIX_EPOCH + Duration::from_secs(expires as u64),
+                        });
+                    }
+                    Ok(entries)
+                })
                 .await
-                .expect("Could not save keys to SQLite");
         });
     }
 }
 
+impl Handler<ListLimits> for RusqliteStore {
+    fn handle(&mut self, _message: ListLimits, cx: Context<Self, ListLimits>) {
+        let read_pool = self.read_pool.clone();
+        cx.reply_later(async move {
+            read_pool
+                .run(|conn| {
+                    let now = unix_timestamp() as i64;
+                    let mut stmt = conn
+                        .prepare("SELECT id, value, expires FROM rate_limits WHERE expires > ?1")?;
+                    let rows = stmt.query_map(&[now], |row| {
+                        let count: i64 = row.get(1)?;
+                        let expires: i64 = row.get(2)?;
+                        Ok(LimitEntry {
+                            key: row.get(0)?,
+                            count: count as usize,
+                            expires: UNIX_EPOCH + Duration::from_secs(expires as u64),
+                        })
+                    })?;
+                    Ok(rows.collect::<Result<_, _>>()?)
+                })
+                .await
+        });
+    }
+}
+
+impl Handler<ResetLimit> for RusqliteStore {
+    fn handle(&mut self, message: ResetLimit, cx: Context<Self, ResetLimit>) {
+        cx.reply_with(move || {
+            let now = unix_timestamp() as i64;
+            let count = self.conn.execute(
+                "DELETE FROM rate_limits WHERE id = ?1 AND expires > ?2",
+                params![&message.key, &now],
+            )?;
+            Ok(count > 0)
+        });
+    }
+}
+
+impl Handler<GetKeySet> for RusqliteStore {
+    fn handle(&mut self, message: GetKeySet, cx: Context<Self, GetKeySet>) {
+        cx.reply_with(move || Ok(self.get_key_set_version(message.0)?.0));
+    }
+}
+
+impl Handler<FlushCache> for RusqliteStore {
+    fn handle(&mut self, _message: FlushCache, cx: Context<Self, FlushCache>) {
+        cx.reply_with(move || Ok(self.conn.execute("DELETE FROM cache_entries", NO_PARAMS)?));
+    }
+}
+
 impl Handler<SaveKeys> for RusqliteStore {
     fn handle(&mut self, message: SaveKeys, cx: Context<Self, SaveKeys>) {
         let key_set = message.0;
         cx.reply_with(move || {
-            let data = serde_json::to_string(&key_set).expect("Could not encode key set as JSON");
+            let data = self.encryption.seal_key_set(&key_set);
             self.conn.execute(
-                "REPLACE INTO key_sets (signing_alg, key_set) VALUES (?1, ?2)",
+                "INSERT INTO key_sets (signing_alg, key_set, version) VALUES (?1, ?2, 1)
+                ON CONFLICT(signing_alg) DO UPDATE SET key_set = ?2, version = version + 1",
                 params![&key_set.signing_alg.as_str(), &data],
             )?;
             Ok(())
@@ -424,4 +779,235 @@ impl Handler<SaveKeys> for RusqliteStore {
     }
 }
 
+impl Handler<ReplaceKeys> for RusqliteStore {
+    fn handle(&mut self, message: ReplaceKeys, cx: Context<Self, ReplaceKeys>) {
+        let key_set = message.key_set;
+        let version = message.version;
+        cx.reply_with(move || {
+            let data = self.encryption.seal_key_set(&key_set);
+            let count = self.conn.execute(
+                "INSERT INTO key_sets (signing_alg, key_set, version) VALUES (?1, ?2, ?3 + 1)
+                ON CONFLICT(signing_alg) DO UPDATE SET key_set = ?2, version = version + 1
+                WHERE version = ?3",
+                params![&key_set.signing_alg.as_str(), &data, &version],
+            )?;
+            if count == 1 {
+                self.key_versions.insert(key_set.signing_alg, version + 1);
+            }
+            Ok(count == 1)
+        });
+    }
+}
+
+impl Handler<PollKeys> for RusqliteStore {
+    fn handle(&mut self, _message: PollKeys, cx: Context<Self, PollKeys>) {
+        let key_manager = self.key_manager.as_ref().unwrap().clone();
+        // Errors are usually temporary, like a busy database, so simply skip this poll.
+        let versions = self
+            .conn
+            .prepare_cached("SELECT signing_alg, version FROM key_sets")
+            .and_then(|mut stmt| {
+                stmt.query_map(NO_PARAMS, |row| {
+                    Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+                })?
+                .collect::<Result<Vec<_>, _>>()
+            });
+        let versions = match versions {
+            Ok(versions) => versions,
+            Err(err) => {
+                log::error!("Could not poll keys in SQLite: {}", err);
+                return cx.reply(());
+            }
+        };
+        let changed: Vec<SigningAlgorithm> = versions
+            .into_iter()
+            .filter_map(|(signing_alg, version)| {
+                let signing_alg = signing_alg.parse().ok()?;
+                match self.key_versions.get(&signing_alg) {
+                    Some(known) if *known != version => Some(signing_alg),
+                    _ => None,
+                }
+            })
+            .collect();
+        let mut update_msgs = Vec::with_capacity(changed.len());
+        for signing_alg in changed {
+            log::info!("Reloading {} keys changed by another process", signing_alg);
+            match self.reload_key_set(signing_alg) {
+                Ok(key_set) => update_msgs.push(UpdateKeys(key_set)),
+                // The version is only updated on success, so the next poll tries again.
+                Err(err) => log::error!("Could not fetch keys from SQLite: {}", err),
+            }
+        }
+        cx.reply_later(async move {
+            for update_msg in update_msgs {
+                key_manager.send(update_msg).await;
+            }
+        });
+    }
+}
+
 impl StoreSender for Addr<RusqliteStore> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_version(conn: &Connection) -> usize {
+        conn.query_row("SELECT * FROM pragma_user_version()", NO_PARAMS, |row| {
+            row.get::<_, u32>(0)
+        })
+        .unwrap() as usize
+    }
+
+    #[test]
+    fn test_migrate_new_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let path = Path::new(":memory:");
+        RusqliteStore::migrate(&mut conn, path).unwrap();
+        assert_eq!(user_version(&conn), MIGRATIONS.len());
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM key_sets", NO_PARAMS, |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 0);
+
+        // Running again is a no-op.
+        RusqliteStore::migrate(&mut conn, path).unwrap();
+        assert_eq!(user_version(&conn), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn test_migrate_keeps_key_sets() {
+        let dir = std::env::temp_dir().join(format!("portier-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("db.sqlite3");
+        let mut conn = Connection::open(&path).unwrap();
+        conn.execute_batch(MIGRATIONS[0]).unwrap();
+        conn.execute_batch(
+            "INSERT INTO key_sets (signing_alg, key_set) VALUES ('EdDSA', '{}');
+            PRAGMA user_version = 1;",
+        )
+        .unwrap();
+
+        RusqliteStore::migrate(&mut conn, &path).unwrap();
+        assert_eq!(user_version(&conn), MIGRATIONS.len());
+        let version: i64 = conn
+            .query_row(
+                "SELECT version FROM key_sets WHERE signing_alg = 'EdDSA'",
+                NO_PARAMS,
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(version, 0);
+        // A backup was made before migrating.
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+
+        drop(conn);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_migrate_waits_for_lock() {
+        let dir = std::env::temp_dir().join(format!("portier-test-lock-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("db.sqlite3");
+        let mut other = Connection::open(&path).unwrap();
+        other.execute_batch(MIGRATIONS[0]).unwrap();
+        other.execute_batch("PRAGMA user_version = 1").unwrap();
+
+        // Another broker is migrating the database, and holds the write lock.
+        let tx = other
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .unwrap();
+        let thread = {
+            let path = path.clone();
+            std::thread::spawn(move || {
+                let mut conn = Connection::open(&path).unwrap();
+                conn.busy_timeout(Duration::from_secs(5)).unwrap();
+                RusqliteStore::migrate(&mut conn, &path).unwrap();
+            })
+        };
+        std::thread::sleep(Duration::from_millis(200));
+        for migration in &MIGRATIONS[1..] {
+            tx.execute_batch(migration).unwrap();
+        }
+        tx.execute(
+            &format!("PRAGMA user_version = {}", MIGRATIONS.len()),
+            NO_PARAMS,
+        )
+        .unwrap();
+        tx.commit().unwrap();
+        thread.join().unwrap();
+
+        // The migration was already done once the lock was released, so no backup was made.
+        assert_eq!(user_version(&other), MIGRATIONS.len());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        drop(other);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_take_session() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        RusqliteStore::migrate(&mut conn, Path::new(":memory:")).unwrap();
+        conn.execute(
+            "INSERT INTO sessions (id, data, expires) VALUES ('a', '{}', ?1), ('b', '{}', 0)",
+            &[&(unix_timestamp() as i64 + 60)],
+        )
+        .unwrap();
+
+        // A session can only be taken once.
+        assert_eq!(
+            RusqliteStore::take_session(&mut conn, "a").unwrap(),
+            Some("{}".to_owned())
+        );
+        assert_eq!(RusqliteStore::take_session(&mut conn, "a").unwrap(), None);
+
+        // Expired sessions are removed, but not returned.
+        assert_eq!(RusqliteStore::take_session(&mut conn, "b").unwrap(), None);
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM sessions", NO_PARAMS, |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[tokio::test]
+    async fn test_read_pool() {
+        let dir = std::env::temp_dir().join(format!("portier-test-pool-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("db.sqlite3");
+        let mut conn = Connection::open(&path).unwrap();
+        RusqliteStore::enable_wal(&conn).unwrap();
+        RusqliteStore::migrate(&mut conn, &path).unwrap();
+        let options = SqliteOptions {
+            busy_timeout: Duration::from_millis(100),
+            read_connections: 2,
+        };
+        let pool = ReadPool::open(&path, &options).unwrap();
+
+        // Readers see committed writes, even while another transaction is open.
+        conn.execute_batch("INSERT INTO sessions (id, data, expires) VALUES ('a', '{}', 0)")
+            .unwrap();
+        let tx = conn.transaction().unwrap();
+        tx.execute_batch("INSERT INTO sessions (id, data, expires) VALUES ('b', '{}', 0)")
+            .unwrap();
+        let count: i64 = pool
+            .run(|conn| {
+                conn.query_row("SELECT COUNT(*) FROM sessions", NO_PARAMS, |row| row.get(0))
+            })
+            .await
+            .unwrap();
+        assert_eq!(count, 1);
+        tx.commit().unwrap();
+
+        // Readers can't write.
+        let result = pool
+            .run(|conn| conn.execute("DELETE FROM sessions", NO_PARAMS))
+            .await;
+        assert!(result.is_err());
+
+        drop(conn);
+        drop(pool);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,85 @@
+//! Audit log of completed and failed authentications.
+//!
+//! When configured, every authentication that reaches a bridge verification step is recorded as a
+//! JSON line in an append-only file. Each line is written and flushed before the response is sent.
+
+use crate::error::BrokerError;
+use crate::utils::agent::{Addr, Agent, Context as AgentContext, Handler, Message};
+use crate::utils::keyed_hash::KeyedHasher;
+use crate::web::Context;
+use serde_json::json;
+use std::fs::{File, OpenOptions};
+use std::io::{Error as IoError, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Message requesting an event be written to the audit log.
+pub struct WriteAuditEvent(pub serde_json::Value);
+impl Message for WriteAuditEvent {
+    type Reply = ();
+}
+
+/// Agent that appends events to the audit log file.
+pub struct AuditLog {
+    file: File,
+    email_hasher: Option<KeyedHasher>,
+}
+
+impl AuditLog {
+    /// Open the audit log file for appending.
+    ///
+    /// If `email_hasher` is set, email addresses are replaced with their keyed hash.
+    pub fn new(path: &Path, email_hasher: Option<KeyedHasher>) -> Result<Self, IoError> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        log::info!("Writing audit log to {}", path.display());
+        Ok(AuditLog { file, email_hasher })
+    }
+}
+
+impl Agent for AuditLog {}
+
+impl Handler<WriteAuditEvent> for AuditLog {
+    fn handle(&mut self, message: WriteAuditEvent, cx: AgentContext<Self, WriteAuditEvent>) {
+        let mut event = message.0;
+        if let Some(ref hasher) = self.email_hasher {
+            let hash = event["email"].as_str().map(|email| hasher.hex(email));
+            if let Some(hash) = hash {
+                let event = event.as_object_mut().expect("audit event is not an object");
+                event.remove("email");
+                event.insert("email_hmac_sha256".to_owned(), hash.into());
+            }
+        }
+        let result = writeln!(self.file, "{}", event).and_then(|()| self.file.sync_data());
+        if let Err(err) = result {
+            log::error!("Could not write to the audit log: {}", err);
+        }
+        cx.reply(());
+    }
+}
+
+/// Record the outcome of an authentication.
+///
+/// The context must have session data loaded. `bridge` names the bridge that verified the user,
+/// and `err` is the reason verification failed, if it did.
+pub async fn record(ctx: &Context, bridge: &str, err: Option<&BrokerError>) {
+    let audit_log: &Addr<AuditLog> = match ctx.app.audit_log {
+        Some(ref audit_log) => audit_log,
+        None => return,
+    };
+    let data = ctx
+        .session_data
+        .as_ref()
+        .expect("audit record without a session");
+    let mut event = json!({
+        "timestamp": humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+        "origin": data.return_params.redirect_uri.origin().ascii_serialization(),
+        "email": data.email_addr.as_str(),
+        "bridge": bridge,
+        "ip": ctx.ip.to_string(),
+        "outcome": if err.is_none() { "success" } else { "failure" },
+    });
+    if let Some(err) = err {
+        event["reason"] = err.to_string().into();
+    }
+    audit_log.send(WriteAuditEvent(event)).await;
+}
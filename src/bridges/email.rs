@@ -1,8 +1,11 @@
 use crate::agents::mailer::SendMail;
+use crate::audit;
 use crate::bridges::{complete_auth, BridgeData};
 use crate::crypto::random_zbase32;
 use crate::email_address::EmailAddress;
 use crate::error::BrokerError;
+use crate::metrics;
+use crate::telemetry;
 use crate::web::{html_response, json_response, Context, HandlerResult};
 use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
 use serde::{Deserialize, Serialize};
@@ -77,19 +80,18 @@ pub async fn auth(ctx: &mut Context, email_addr: EmailAddress) -> HandlerResult
     }
 
     // Send the mail.
-    let ok = ctx
-        .app
-        .mailer
-        .send(SendMail {
+    let ok = telemetry::span("mailer.send_mail")
+        .run(ctx.app.mailer.send(SendMail {
             to: email_addr,
             subject,
             html_body,
             text_body,
-        })
+        }))
         .await;
     if !ok {
         return Err(BrokerError::Internal("Failed to send mail".to_owned()));
     }
+    metrics::BRIDGE_STARTED.with_label_values(&["email"]).inc();
 
     // Render a form for the user.
     if ctx.want_json() {
@@ -142,8 +144,13 @@ pub async fn confirmation(ctx: &mut Context) -> HandlerResult {
     };
 
     if code != bridge_data.code {
-        return Err(BrokerError::ProviderInput("incorrect code".to_owned()));
+        let err = BrokerError::ProviderInput("incorrect code".to_owned());
+        audit::record(ctx, "email", Some(&err)).await;
+        return Err(err);
     }
 
-    complete_auth(ctx).await
+    metrics::BRIDGE_COMPLETED
+        .with_label_values(&["email"])
+        .inc();
+    complete_auth(ctx, "email").await
 }
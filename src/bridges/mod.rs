@@ -1,31 +1,54 @@
-use crate::agents::{DecrLimits, DeleteSession};
+use crate::agents::{DecrLimits, DeleteSession, SaveSession};
+use crate::audit;
 use crate::config::LimitInput;
 use crate::crypto;
 use crate::error::BrokerError;
-use crate::web::{json_response, return_to_relier, Context, HandlerResult};
+use crate::telemetry;
+use crate::utils::{base64url, unix_timestamp};
+use crate::web::{json_response, return_to_relier, Context, HandlerResult, Session};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
+/// How long an authorization code issued to a relying party is valid, in seconds.
+pub const CODE_TTL: u64 = 60;
+
 /// Session data stored by bridges.
 #[derive(Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum BridgeData {
     Email(email::EmailBridgeData),
     Oidc(oidc::OidcBridgeData),
+    Code(CodeData),
+}
+
+/// Session data stored for an authorization code, until the relying party redeems it.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CodeData {
+    pub id_token: String,
+    pub code_challenge: String,
+    pub expires: u64,
+}
+
+/// The session ID under which an authorization code is stored.
+///
+/// Codes use their own namespace, so redeeming a code can never touch a login in progress.
+pub fn code_session_id(code: &str) -> String {
+    format!("code:{}", code)
 }
 
 /// Once a bridge has authenticated the user, this function can be used to finish up the redirect
 /// to the relying party with a token generated by us.
-pub async fn complete_auth(ctx: &mut Context) -> HandlerResult {
+///
+/// The `bridge` name is recorded in the audit log.
+pub async fn complete_auth(ctx: &mut Context, bridge: &str) -> HandlerResult {
     let data = ctx
         .session_data
         .as_ref()
         .expect("complete_auth called without a session");
-    ctx.app
-        .store
-        .send(DeleteSession {
+    telemetry::span("store.delete_session")
+        .run(ctx.app.store.send(DeleteSession {
             session_id: ctx.session_id.clone(),
-        })
+        }))
         .await
         .map_err(|e| BrokerError::Internal(format!("could not remove a session: {}", e)))?;
 
@@ -50,18 +73,54 @@ pub async fn complete_auth(ctx: &mut Context) -> HandlerResult {
         BrokerError::Internal(format!("Could not create a JWT: {:?}", err))
     })?;
 
-    ctx.app
-        .store
-        .send(DecrLimits {
-            input: LimitInput {
-                email_addr: data.email_addr.clone(),
-                origin,
-                ip: data.original_ip,
-            },
-        })
+    telemetry::span("store.decr_limits")
+        .run(ctx.app.store.send(DecrLimits {
+            input: LimitInput::new(
+                &data.email_addr,
+                &origin,
+                data.original_ip,
+                ctx.app.limit_key_hasher.as_ref(),
+            ),
+        }))
         .await
         .map_err(|e| BrokerError::Internal(format!("could not decrement rate limits: {}", e)))?;
 
+    audit::record(ctx, bridge, None).await;
+
+    // With the code flow, store the token under a new code for the relying party to redeem.
+    if let Some(ref code_challenge) = data.return_params.code_challenge {
+        let code = base64url::encode(&ctx.app.rng.generate_async(32).await);
+        telemetry::span("store.save_session")
+            .run(ctx.app.store.send(SaveSession {
+                session_id: code_session_id(&code),
+                data: Session {
+                    data: data.clone(),
+                    bridge_data: BridgeData::Code(CodeData {
+                        id_token: jwt,
+                        code_challenge: code_challenge.clone(),
+                        expires: unix_timestamp() + CODE_TTL,
+                    }),
+                },
+            }))
+            .await
+            .map_err(|e| BrokerError::Internal(format!("could not save a code: {}", e)))?;
+
+        return if ctx.want_json() {
+            Ok(json_response(
+                &json!({
+                    "code": &code,
+                    "state": &data.return_params.state,
+                }),
+                None,
+            ))
+        } else {
+            Ok(return_to_relier(
+                ctx,
+                &[("code", &code), ("state", &data.return_params.state)],
+            ))
+        };
+    }
+
     if ctx.want_json() {
         Ok(json_response(
             &json!({
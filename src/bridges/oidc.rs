@@ -1,16 +1,22 @@
-use crate::agents::FetchUrlCached;
+use crate::agents::{FetchUrl, FetchUrlCached};
+use crate::audit;
 use crate::bridges::{complete_auth, BridgeData};
+use crate::config::Config;
 use crate::crypto::{self, SigningAlgorithm};
 use crate::email_address::EmailAddress;
 use crate::error::BrokerError;
-use crate::utils::{http::ResponseExt, unix_timestamp};
+use crate::metrics;
+use crate::telemetry;
+use crate::utils::{base64url, http::ResponseExt, unix_timestamp};
 use crate::validation;
 use crate::web::{empty_response, json_response, Context, HandlerResult};
 use crate::webfinger::{Link, Relation};
-use http::StatusCode;
+use http::{Method, Request, StatusCode};
+use hyper::Body;
+use ring::digest;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use url::Url;
+use url::{form_urlencoded, Url};
 
 /// The origin of the Google identity provider.
 pub const GOOGLE_IDP_ORIGIN: &str = "https://accounts.google.com";
@@ -21,26 +27,42 @@ pub const LEEWAY: u64 = 30;
 #[derive(Clone, Serialize, Deserialize)]
 pub struct OidcBridgeData {
     pub link: Link,
+    /// Index of `link` among the links found through webfinger.
+    #[serde(default)]
+    pub link_index: usize,
+    /// The issuer identifier, which is just the origin for Portier and Google providers.
     pub origin: String,
     pub client_id: String,
     pub nonce: String,
     pub signing_alg: SigningAlgorithm,
+    /// PKCE code verifier, if using the authorization code flow.
+    #[serde(default)]
+    pub code_verifier: Option<String>,
 }
 
 /// OpenID Connect configuration document.
 #[derive(Deserialize)]
 struct ProviderConfig {
     authorization_endpoint: Url,
+    token_endpoint: Option<Url>,
     jwks_uri: Url,
+    #[serde(default = "default_response_types_supported")]
+    response_types_supported: Vec<String>,
     #[serde(default = "default_response_modes_supported")]
     response_modes_supported: Vec<String>,
     #[serde(default = "default_id_token_signing_alg_values_supported")]
     id_token_signing_alg_values_supported: Vec<String>,
+    #[serde(default = "default_token_endpoint_auth_methods_supported")]
+    token_endpoint_auth_methods_supported: Vec<String>,
     // NOTE: This field is non-standard.
     #[serde(default)]
     accepts_id_token_signing_alg_query_param: bool,
 }
 
+fn default_response_types_supported() -> Vec<String> {
+    vec!["id_token".to_owned()]
+}
+
 fn default_response_modes_supported() -> Vec<String> {
     vec!["fragment".to_owned()]
 }
@@ -49,6 +71,16 @@ fn default_id_token_signing_alg_values_supported() -> Vec<String> {
     vec!["RS256".to_owned()]
 }
 
+fn default_token_endpoint_auth_methods_supported() -> Vec<String> {
+    vec!["client_secret_basic".to_owned()]
+}
+
+/// OpenID Connect token endpoint response.
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
 /// OpenID Connect key set document.
 #[derive(Deserialize)]
 struct ProviderKeys {
@@ -81,69 +113,90 @@ pub struct ProviderKey {
 /// the providers configuration document. Included in the request is a nonce which we can later use
 /// to definitively match the callback to this request.
 ///
-/// This function handles both Portier providers, which works without registration, as well as
-/// the Google provider, for which we have a preregistered `client_id`.
-pub async fn auth(ctx: &mut Context, email_addr: &EmailAddress, link: &Link) -> HandlerResult {
+/// Configured upstream providers use the authorization code flow with PKCE if they have a client
+/// secret, or if they don't support the implicit flow. Other providers always use the implicit
+/// flow.
+///
+/// This function handles Portier providers, which works without registration, as well as the
+/// Google provider and configured upstream providers, for which we have a preregistered
+/// `client_id`.
+///
+/// The `link_index` is the position of the link in the webfinger response, and is recorded in the
+/// session.
+pub async fn auth(
+    ctx: &mut Context,
+    email_addr: &EmailAddress,
+    link: &Link,
+    link_index: usize,
+) -> HandlerResult {
     // Generate a nonce for the provider.
     let provider_nonce = crypto::nonce(&ctx.app.rng).await;
 
     // Determine the parameters to use, based on the webfinger link.
-    let provider_origin = validation::parse_oidc_href(&link.href).ok_or_else(|| {
-        BrokerError::Provider(format!("invalid href (validation failed): {}", link.href))
-    })?;
-    let mut bridge_data = match link.rel {
-        Relation::Portier => {
-            #[cfg(not(feature = "insecure"))]
-            {
-                if link.href.scheme() != "https" {
-                    return Err(BrokerError::Provider(format!(
-                        "invalid href (not HTTPS): {}",
-                        link.href
-                    )));
-                }
-            }
-            OidcBridgeData {
-                link: link.clone(),
-                origin: provider_origin,
-                client_id: ctx.app.public_url.clone(),
-                nonce: provider_nonce,
-                signing_alg: SigningAlgorithm::Rs256,
-            }
-        }
-        // Delegate to the OpenID Connect bridge for Google, if configured.
-        Relation::Google => {
-            let client_id = ctx
-                .app
-                .google_client_id
-                .as_ref()
-                .ok_or(BrokerError::ProviderCancelled)?;
-            if provider_origin != GOOGLE_IDP_ORIGIN {
-                return Err(BrokerError::Provider(format!(
-                    "invalid href: Google provider only supports {}",
-                    GOOGLE_IDP_ORIGIN
-                )));
-            }
-            OidcBridgeData {
-                link: link.clone(),
-                origin: provider_origin,
-                client_id: client_id.clone(),
-                nonce: provider_nonce,
-                signing_alg: SigningAlgorithm::Rs256,
+    let provider = resolve_link(&ctx.app, email_addr, link)?;
+    let mut use_code_flow = provider.has_client_secret;
+    let mut bridge_data = OidcBridgeData {
+        link: link.clone(),
+        link_index,
+        origin: provider.origin,
+        client_id: provider.client_id,
+        nonce: provider_nonce,
+        signing_alg: SigningAlgorithm::Rs256,
+        code_verifier: None,
+    };
+
+    // Retrieve the provider's configuration. If there is room for background discovery of this
+    // provider, this runs as a separate task, so that it still completes and fills the cache if
+    // discovery times out.
+    let fetch_future = async {
+        match ctx.app.background_discovery.try_start(&bridge_data.origin) {
+            Some(guard) => {
+                let app = ctx.app.clone();
+                let origin = bridge_data.origin.clone();
+                telemetry::spawn(async move {
+                    let _guard = guard;
+                    fetch_config(&app, &origin).await
+                })
+                .await
+                .unwrap_or_else(|e| {
+                    Err(BrokerError::Internal(format!(
+                        "provider configuration task failed: {}",
+                        e
+                    )))
+                })
             }
+            None => fetch_config(&ctx.app, &bridge_data.origin).await,
         }
     };
-
-    // Retrieve the provider's configuration.
     let (
         ProviderConfig {
             authorization_endpoint: mut auth_url,
+            token_endpoint,
+            response_types_supported: response_types,
             response_modes_supported: response_modes,
             id_token_signing_alg_values_supported: signing_algs,
             accepts_id_token_signing_alg_query_param: accepts_signing_alg,
             ..
         },
         key_set,
-    ) = fetch_config(ctx, &bridge_data).await?;
+    ) = telemetry::span("oidc.fetch_config")
+        .attr("origin", bridge_data.origin.as_str())
+        .run(fetch_future)
+        .await?;
+
+    if link.rel == Relation::Oidc && !response_types.iter().any(|rt| rt == "id_token") {
+        use_code_flow = true;
+    }
+    if use_code_flow {
+        if token_endpoint.is_none() {
+            return Err(BrokerError::Provider(format!(
+                "{}'s configuration has no token_endpoint",
+                bridge_data.origin
+            )));
+        }
+        let code_verifier = base64url::encode(&ctx.app.rng.generate_async(32).await);
+        bridge_data.code_verifier = Some(code_verifier);
+    }
 
     {
         // Create the URL to redirect to.
@@ -153,15 +206,28 @@ pub async fn auth(ctx: &mut Context, email_addr: &EmailAddress, link: &Link) ->
             ("scope", "openid email"),
             ("nonce", &bridge_data.nonce),
             ("state", &ctx.session_id),
-            ("response_type", "id_token"),
             ("client_id", &bridge_data.client_id),
             ("redirect_uri", &format!("{}/callback", &ctx.app.public_url)),
         ]);
 
-        // Prefer `form_post` response mode, otherwise use `fragment`.
+        if let Some(ref code_verifier) = bridge_data.code_verifier {
+            let challenge = digest::digest(&digest::SHA256, code_verifier.as_bytes());
+            query.extend_pairs(&[
+                ("response_type", "code"),
+                ("code_challenge", &base64url::encode(challenge.as_ref())),
+                ("code_challenge_method", "S256"),
+            ]);
+        } else {
+            query.append_pair("response_type", "id_token");
+        }
+
+        // Prefer `form_post` response mode, otherwise use the default for the response type,
+        // which is `fragment` for the implicit flow, and `query` for the code flow.
         if response_modes.iter().any(|mode| mode == "form_post") {
             query.append_pair("response_mode", "form_post");
-        } else if !response_modes.iter().any(|mode| mode == "fragment") {
+        } else if bridge_data.code_verifier.is_none()
+            && !response_modes.iter().any(|mode| mode == "fragment")
+        {
             return Err(BrokerError::Provider(format!(
                 "neither form_post nor fragment response modes supported by {}'s IdP ",
                 email_addr.domain()
@@ -200,6 +266,7 @@ pub async fn auth(ctx: &mut Context, email_addr: &EmailAddress, link: &Link) ->
     if !ctx.save_session(BridgeData::Oidc(bridge_data)).await? {
         return Err(BrokerError::ProviderCancelled);
     }
+    metrics::BRIDGE_STARTED.with_label_values(&["oidc"]).inc();
 
     if ctx.want_json() {
         Ok(json_response(
@@ -222,9 +289,13 @@ pub async fn auth(ctx: &mut Context, email_addr: &EmailAddress, link: &Link) ->
 /// token returned by the provider and verify it. Return an identity token for the relying party if
 /// successful, or an error message otherwise.
 pub async fn callback(ctx: &mut Context) -> HandlerResult {
-    let mut params = ctx.form_params();
+    // Parameters are in the query string if the provider used the `query` response mode.
+    let mut params = if ctx.method == Method::GET {
+        ctx.query_params()
+    } else {
+        ctx.form_params()
+    };
     let session_id = try_get_provider_param!(params, "state");
-    let id_token = try_get_provider_param!(params, "id_token");
 
     #[allow(clippy::match_wildcard_for_single_variants)]
     let bridge_data = match ctx.load_session(&session_id).await? {
@@ -232,8 +303,58 @@ pub async fn callback(ctx: &mut Context) -> HandlerResult {
         _ => return Err(BrokerError::ProviderInput("invalid session".to_owned())),
     };
 
+    // With the code flow, we receive a code, which `verify_token` exchanges for a token.
+    let token_or_code = if bridge_data.code_verifier.is_some() {
+        try_get_provider_param!(params, "code")
+    } else {
+        try_get_provider_param!(params, "id_token")
+    };
+
+    // Verify the token, and record failures in the audit log.
+    let bridge = match bridge_data.link.rel {
+        Relation::Portier => "oidc",
+        Relation::Google => "google",
+        Relation::Oidc => "oidc_provider",
+    };
+    if let Err(err) = verify_token(ctx, &bridge_data, token_or_code).await {
+        audit::record(ctx, bridge, Some(&err)).await;
+        return Err(err);
+    }
+
+    // Everything is okay. Build a new identity token and send it to the relying party.
+    metrics::BRIDGE_COMPLETED.with_label_values(&["oidc"]).inc();
+    complete_auth(ctx, bridge).await
+}
+
+/// Verify the identity token received from the provider in a callback.
+///
+/// With the code flow, the code received is first exchanged for the identity token.
+async fn verify_token(
+    ctx: &mut Context,
+    bridge_data: &OidcBridgeData,
+    token_or_code: String,
+) -> Result<(), BrokerError> {
     // Retrieve the provider's configuration.
-    let (_, key_set) = fetch_config(ctx, &bridge_data).await?;
+    let (provider_config, key_set) = telemetry::span("oidc.fetch_config")
+        .attr("origin", bridge_data.origin.as_str())
+        .run(fetch_config(&ctx.app, &bridge_data.origin))
+        .await?;
+
+    let id_token = match bridge_data.code_verifier {
+        Some(ref code_verifier) => {
+            telemetry::span("oidc.exchange_code")
+                .attr("origin", bridge_data.origin.as_str())
+                .run(exchange_code(
+                    ctx,
+                    bridge_data,
+                    &provider_config,
+                    &token_or_code,
+                    code_verifier,
+                ))
+                .await?
+        }
+        None => token_or_code,
+    };
 
     // Verify the signature.
     let jwt_payload = crypto::verify_jws(&id_token, &key_set.keys, bridge_data.signing_alg)
@@ -289,75 +410,250 @@ pub async fn callback(ctx: &mut Context) -> HandlerResult {
             let expected = data.email_addr.normalize_google();
             check_token_field!(google_email_addr == expected, "email", descr);
         }
+        Relation::Oidc => {
+            // The provider may not normalize the address the way we do.
+            let email_addr: EmailAddress = email.parse().map_err(|err| {
+                BrokerError::ProviderInput(format!("failed to parse email in {}: {}", descr, err))
+            })?;
+            check_token_field!(email_addr == data.email_addr, "email", descr);
+            // Providers that let users change their address mark it as unverified.
+            let email_verified = jwt_payload.get("email_verified").and_then(Value::as_bool);
+            check_token_field!(email_verified != Some(false), "email_verified", descr);
+        }
     }
 
-    // Everything is okay. Build a new identity token and send it to the relying party.
-    complete_auth(ctx).await
+    Ok(())
 }
 
-// Retrieve and verify the provider's configuration.
-async fn fetch_config(
+/// Exchange an authorization code for an identity token at the provider's token endpoint.
+async fn exchange_code(
     ctx: &mut Context,
     bridge_data: &OidcBridgeData,
-) -> Result<(ProviderConfig, ProviderKeys), BrokerError> {
-    let config_url = format!("{}/.well-known/openid-configuration", bridge_data.origin)
-        .parse()
-        .expect("could not build the OpenID Connect configuration URL");
+    provider_config: &ProviderConfig,
+    code: &str,
+    code_verifier: &str,
+) -> Result<String, BrokerError> {
+    let token_endpoint = provider_config.token_endpoint.as_ref().ok_or_else(|| {
+        BrokerError::Provider(format!(
+            "{}'s configuration has no token_endpoint",
+            bridge_data.origin
+        ))
+    })?;
+    let client_secret = ctx
+        .app
+        .oidc_providers
+        .iter()
+        .find(|provider| {
+            provider.issuer == bridge_data.origin && provider.client_id == bridge_data.client_id
+        })
+        .and_then(|provider| provider.client_secret.as_ref());
+
+    // Note: the serializer is not `Send`, so must be dropped before awaiting.
+    let request = {
+        let mut body = form_urlencoded::Serializer::new(String::new());
+        body.extend_pairs(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &format!("{}/callback", &ctx.app.public_url)),
+            ("code_verifier", code_verifier),
+            ("client_id", &bridge_data.client_id),
+        ]);
+        let mut request = Request::post(token_endpoint.as_str())
+            .header("Accept", "application/json")
+            .header("Content-Type", "application/x-www-form-urlencoded");
+        if let Some(client_secret) = client_secret {
+            // Prefer `client_secret_basic`, which is the default, over `client_secret_post`.
+            let methods = &provider_config.token_endpoint_auth_methods_supported;
+            if methods.iter().any(|method| method == "client_secret_basic")
+                || !methods.iter().any(|method| method == "client_secret_post")
+            {
+                let credentials = format!(
+                    "{}:{}",
+                    form_urlencoded::byte_serialize(bridge_data.client_id.as_bytes())
+                        .collect::<String>(),
+                    form_urlencoded::byte_serialize(client_secret.as_bytes()).collect::<String>()
+                );
+                let mut auth = String::from("Basic ");
+                base64::encode_config_buf(credentials, base64::STANDARD, &mut auth);
+                request = request.header("Authorization", auth);
+            } else {
+                body.append_pair("client_secret", client_secret);
+            }
+        }
+        request
+            .body(Body::from(body.finish()))
+            .expect("could not build token request")
+    };
 
-    let provider_config = ctx
+    let response = ctx
         .app
-        .store
-        .send(FetchUrlCached { url: config_url })
+        .fetcher
+        .send(FetchUrl { request })
         .await
         .map_err(|e| {
             BrokerError::Provider(format!(
-                "could not fetch {}'s configuration: {}",
+                "could not exchange code with {}: {}",
                 bridge_data.origin, e
             ))
         })?;
-    let provider_config: ProviderConfig = serde_json::from_str(&provider_config).map_err(|e| {
+    let response: TokenResponse = serde_json::from_str(&response.data).map_err(|e| {
         BrokerError::Provider(format!(
-            "could not parse {}'s configuration: {}",
+            "could not parse {}'s token response: {}",
             bridge_data.origin, e
         ))
     })?;
+    Ok(response.id_token)
+}
+
+/// Provider details determined from a webfinger link.
+struct LinkProvider {
+    /// The issuer identifier, which is just the origin for Portier and Google providers.
+    origin: String,
+    client_id: String,
+    has_client_secret: bool,
+}
+
+/// Validate a webfinger link, and determine the provider details to use with it.
+fn resolve_link(
+    app: &Config,
+    email_addr: &EmailAddress,
+    link: &Link,
+) -> Result<LinkProvider, BrokerError> {
+    let parse_origin = || {
+        validation::parse_oidc_href(&link.href).ok_or_else(|| {
+            BrokerError::Provider(format!("invalid href (validation failed): {}", link.href))
+        })
+    };
+    match link.rel {
+        Relation::Portier => {
+            let provider_origin = parse_origin()?;
+            #[cfg(not(feature = "insecure"))]
+            {
+                if link.href.scheme() != "https" {
+                    return Err(BrokerError::Provider(format!(
+                        "invalid href (not HTTPS): {}",
+                        link.href
+                    )));
+                }
+            }
+            Ok(LinkProvider {
+                origin: provider_origin,
+                client_id: app.public_url.clone(),
+                has_client_secret: false,
+            })
+        }
+        // Delegate to the OpenID Connect bridge for Google, if configured.
+        Relation::Google => {
+            let client_id = app
+                .google_client_id
+                .as_ref()
+                .ok_or(BrokerError::ProviderCancelled)?;
+            let provider_origin = parse_origin()?;
+            if provider_origin != GOOGLE_IDP_ORIGIN {
+                return Err(BrokerError::Provider(format!(
+                    "invalid href: Google provider only supports {}",
+                    GOOGLE_IDP_ORIGIN
+                )));
+            }
+            Ok(LinkProvider {
+                origin: provider_origin,
+                client_id: client_id.clone(),
+                has_client_secret: false,
+            })
+        }
+        // Configured providers may only be used for the domains they are configured for.
+        Relation::Oidc => {
+            let provider = app
+                .oidc_providers
+                .iter()
+                .find(|provider| {
+                    provider.matches_link(&link.href) && provider.has_domain(email_addr)
+                })
+                .ok_or_else(|| {
+                    BrokerError::Provider(format!(
+                        "no OpenID Connect provider configured for {} with issuer {}",
+                        email_addr.domain(),
+                        link.href
+                    ))
+                })?;
+            Ok(LinkProvider {
+                origin: provider.issuer.clone(),
+                client_id: provider.client_id.clone(),
+                has_client_secret: provider.client_secret.is_some(),
+            })
+        }
+    }
+}
+
+/// Fetch the configuration and keys of the provider for a webfinger link, filling the cache.
+///
+/// This is used to continue discovery in the background after it timed out.
+pub async fn prefetch(
+    app: &Config,
+    email_addr: &EmailAddress,
+    link: &Link,
+) -> Result<(), BrokerError> {
+    let provider = resolve_link(app, email_addr, link)?;
+    fetch_config(app, &provider.origin).await?;
+    Ok(())
+}
+
+// Retrieve and verify the provider's configuration.
+async fn fetch_config(
+    app: &Config,
+    origin: &str,
+) -> Result<(ProviderConfig, ProviderKeys), BrokerError> {
+    // Configured issuers may end with a slash, which is not repeated here.
+    let config_url: Url = format!(
+        "{}/.well-known/openid-configuration",
+        origin.trim_end_matches('/')
+    )
+    .parse()
+    .expect("could not build the OpenID Connect configuration URL");
+
+    let provider_config = telemetry::span("store.fetch_url_cached")
+        .attr("url", config_url.as_str())
+        .run(app.store.send(FetchUrlCached { url: config_url }))
+        .await
+        .map_err(|e| {
+            BrokerError::Provider(format!("could not fetch {}'s configuration: {}", origin, e))
+        })?;
+    let provider_config: ProviderConfig = serde_json::from_str(&provider_config).map_err(|e| {
+        BrokerError::Provider(format!("could not parse {}'s configuration: {}", origin, e))
+    })?;
 
     #[cfg(not(feature = "insecure"))]
     {
         if provider_config.authorization_endpoint.scheme() != "https" {
             return Err(BrokerError::Provider(format!(
                 "{}'s authorization_endpoint is not HTTPS",
-                bridge_data.origin
+                origin
             )));
         }
         if provider_config.jwks_uri.scheme() != "https" {
             return Err(BrokerError::Provider(format!(
                 "{}'s jwks_uri is not HTTPS",
-                bridge_data.origin
+                origin
+            )));
+        }
+        if matches!(provider_config.token_endpoint, Some(ref url) if url.scheme() != "https") {
+            return Err(BrokerError::Provider(format!(
+                "{}'s token_endpoint is not HTTPS",
+                origin
             )));
         }
     }
 
     // Grab the keys from the provider.
-    let key_set = ctx
-        .app
-        .store
-        .send(FetchUrlCached {
+    let key_set = telemetry::span("store.fetch_url_cached")
+        .attr("url", provider_config.jwks_uri.as_str())
+        .run(app.store.send(FetchUrlCached {
             url: provider_config.jwks_uri.clone(),
-        })
+        }))
         .await
-        .map_err(|e| {
-            BrokerError::Provider(format!(
-                "could not fetch {}'s keys: {}",
-                bridge_data.origin, e
-            ))
-        })?;
-    let key_set: ProviderKeys = serde_json::from_str(&key_set).map_err(|e| {
-        BrokerError::Provider(format!(
-            "could not parse{}'s keys: {}",
-            bridge_data.origin, e
-        ))
-    })?;
+        .map_err(|e| BrokerError::Provider(format!("could not fetch {}'s keys: {}", origin, e)))?;
+    let key_set: ProviderKeys = serde_json::from_str(&key_set)
+        .map_err(|e| BrokerError::Provider(format!("could not parse{}'s keys: {}", origin, e)))?;
 
     Ok((provider_config, key_set))
 }
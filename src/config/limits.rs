@@ -1,4 +1,5 @@
 use crate::email_address::EmailAddress;
+use crate::utils::keyed_hash::KeyedHasher;
 use serde::de::{Deserialize, Deserializer, Error as DeError};
 use std::{net::IpAddr, num::ParseIntError, str::FromStr, time::Duration};
 use thiserror::Error;
@@ -108,14 +109,40 @@ serde_from_str!(LimitConfig);
 /// Input values for limit operations.
 pub struct LimitInput {
     /// The email address of the user.
-    pub email_addr: EmailAddress,
+    pub email_addr: String,
+    /// The domain of the email address of the user.
+    pub email_domain: String,
     /// The origin of the relying party.
     pub origin: String,
     /// The IP address of the user agent.
-    pub ip: IpAddr,
+    pub ip: String,
 }
 
 impl LimitInput {
+    /// Collect input values, hashing each of them if `limit_key_secret` is set.
+    pub fn new(
+        email_addr: &EmailAddress,
+        origin: &str,
+        ip: IpAddr,
+        hasher: Option<&KeyedHasher>,
+    ) -> Self {
+        let ip = ip.to_string();
+        match hasher {
+            Some(hasher) => LimitInput {
+                email_addr: hasher.short(email_addr.as_str()),
+                email_domain: hasher.short(email_addr.domain()),
+                origin: hasher.short(origin),
+                ip: hasher.short(&ip),
+            },
+            None => LimitInput {
+                email_addr: email_addr.as_str().to_owned(),
+                email_domain: email_addr.domain().to_owned(),
+                origin: origin.to_owned(),
+                ip,
+            },
+        }
+    }
+
     /// Build a string key for these values and the given config.
     ///
     /// The prefix can be used to add additional namespacing to the key, for usage with Redis for
@@ -124,15 +151,15 @@ impl LimitInput {
         let mut result = format!("{}{}", prefix, config.id);
         if config.with_ip {
             result.push_str(sep);
-            result.push_str(&format!("{}", self.ip));
+            result.push_str(&self.ip);
         }
         if config.with_email_addr {
             result.push_str(sep);
-            result.push_str(self.email_addr.as_str());
+            result.push_str(&self.email_addr);
         }
         if config.with_email_domain {
             result.push_str(sep);
-            result.push_str(self.email_addr.domain());
+            result.push_str(&self.email_domain);
         }
         if config.with_origin {
             result.push_str(sep);
@@ -155,7 +182,7 @@ impl<'de> Deserialize<'de> for LegacyLimitPerEmail {
 
 #[cfg(test)]
 mod tests {
-    use super::LimitConfig;
+    use super::{KeyedHasher, LimitConfig, LimitInput};
     use std::time::Duration;
 
     #[test]
@@ -207,4 +234,26 @@ mod tests {
             })
         );
     }
+
+    #[test]
+    fn test_build_key() {
+        let config: LimitConfig = "ip:email:domain:origin:1/s".parse().unwrap();
+        let email_addr = "john@example.com".parse().unwrap();
+        let ip = "127.0.0.1".parse().unwrap();
+
+        let input = LimitInput::new(&email_addr, "https://rp.example", ip, None);
+        assert_eq!(
+            input.build_key(&config, "limit:", "|"),
+            "limit:0|127.0.0.1|john@example.com|example.com|https://rp.example"
+        );
+
+        let hasher = KeyedHasher::new("correct horse battery staple");
+        let input = LimitInput::new(&email_addr, "https://rp.example", ip, Some(&hasher));
+        let key = input.build_key(&config, "limit:", "|");
+        assert!(!key.contains("john") && !key.contains("example") && !key.contains("127."));
+        assert_eq!(
+            key.split('|').nth(2),
+            Some(&*hasher.short("john@example.com"))
+        );
+    }
 }
@@ -11,34 +11,51 @@ use self::i18n::I18n;
 use self::templates::Templates;
 use self::toml::TomlConfig;
 use crate::agents::{
-    self, FetchAgent, KeyManagerSender, ManualKeys, ManualKeysError, RotatingKeys, SendMail,
+    self, FetchAgent, KeyManagerSender, MailerSender, ManualKeys, ManualKeysError, RotatingKeys,
     StoreSender,
 };
+use crate::audit::AuditLog;
 use crate::bridges::oidc::GOOGLE_IDP_ORIGIN;
 use crate::crypto::SigningAlgorithm;
 use crate::email_address::EmailAddress;
+use crate::telemetry::{SpanExporter, Tracer};
+#[cfg(feature = "redis")]
+use crate::utils::redis::connection::TlsConfig;
 use crate::utils::{
-    agent::{spawn_agent, Addr, Sender},
+    agent::{spawn_agent, Addr},
+    background::BackgroundTasks,
+    keyed_hash::KeyedHasher,
+    listener::TlsFiles,
+    logger::LogFormat,
+    storage_encryption::StorageEncryption,
     SecureRandom,
 };
 use crate::webfinger::{Link, ParseLinkError, Relation};
 use ipnetwork::IpNetwork;
+use serde::Deserialize;
 use std::{
     borrow::ToOwned,
     collections::HashMap,
     env::var as env_var,
     io::Error as IoError,
+    net::SocketAddr,
     path::{Path, PathBuf},
     sync::Arc,
     time::Duration,
 };
 use thiserror::Error;
+use url::Url;
 
 /// Union of all possible error types seen while parsing.
 #[derive(Debug, Error)]
 pub enum ConfigError {
     #[error("configuration error: {0}")]
     Custom(&'static str),
+    #[error(
+        "configuration error: {0} must be at least {} characters long",
+        MIN_SECRET_LEN
+    )]
+    SecretTooShort(&'static str),
     #[error("IO error: {0}")]
     Io(#[from] IoError),
     #[error("TOML error: {0}")]
@@ -57,25 +74,42 @@ impl From<&'static str> for ConfigError {
 
 pub type ConfigRc = Arc<Config>;
 
+/// Maximum number of discovery tasks that may continue in the background at the same time.
+const MAX_BACKGROUND_DISCOVERY: usize = 32;
+
 pub struct Config {
     pub listen_ip: String,
     pub listen_port: u16,
+    pub listen_socket: Option<PathBuf>,
+    pub listen_socket_mode: Option<u32>,
+    pub tls_files: Option<TlsFiles>,
+    pub metrics_listen: Option<SocketAddr>,
+    pub tracer: Option<Tracer>,
+    pub audit_log: Option<Addr<AuditLog>>,
     pub public_url: String,
     pub trusted_proxies: Vec<IpNetwork>,
     pub allowed_origins: Option<Vec<String>>,
+    pub enable_code_flow: bool,
 
     pub static_ttl: Duration,
     pub discovery_ttl: Duration,
     pub keys_ttl: Duration,
     pub token_ttl: Duration,
+    pub shutdown_timeout: Duration,
+    pub discovery_timeout: Duration,
+    pub background_discovery: BackgroundTasks,
 
     pub key_manager: Box<dyn KeyManagerSender>,
     pub signing_algs: Vec<SigningAlgorithm>,
 
     pub store: Arc<dyn StoreSender>,
-    pub mailer: Box<dyn Sender<SendMail>>,
+    pub mailer: Box<dyn MailerSender>,
+    pub fetcher: Addr<FetchAgent>,
+
+    pub limit_key_hasher: Option<KeyedHasher>,
 
     pub google_client_id: Option<String>,
+    pub oidc_providers: Vec<OidcProvider>,
     pub domain_overrides: HashMap<String, Vec<Link>>,
 
     pub res_dir: PathBuf,
@@ -84,6 +118,68 @@ pub struct Config {
     pub rng: SecureRandom,
 }
 
+/// An upstream OpenID Connect provider, used instead of the email loop for some domains.
+#[derive(Clone, Deserialize)]
+pub struct OidcProvider {
+    /// Issuer identifier, which must exactly match the `iss` claim in tokens.
+    pub issuer: String,
+    /// Client ID registered with the provider.
+    pub client_id: String,
+    /// Client secret, which enables the authorization code flow.
+    #[serde(default)]
+    pub client_secret: Option<String>,
+    /// Email domains that authenticate with this provider.
+    pub domains: Vec<String>,
+}
+
+impl OidcProvider {
+    /// Whether this provider is configured for the domain of an email address.
+    pub fn has_domain(&self, email_addr: &EmailAddress) -> bool {
+        self.domains
+            .iter()
+            .any(|domain| domain == email_addr.domain())
+    }
+
+    /// Whether a webfinger link refers to this provider, ignoring any trailing slash.
+    pub fn matches_link(&self, href: &Url) -> bool {
+        self.issuer.parse::<Url>().map_or(false, |issuer| {
+            issuer.as_str().trim_end_matches('/') == href.as_str().trim_end_matches('/')
+        })
+    }
+
+    /// Validate the configured values, and normalize domains.
+    ///
+    /// The issuer is kept as is, because the `iss` claim is compared to it exactly.
+    fn validate(mut self) -> Result<Self, ConfigError> {
+        let issuer: Url = self
+            .issuer
+            .parse()
+            .map_err(|_| "oidc_providers issuer must be a valid URL")?;
+        #[cfg(not(feature = "insecure"))]
+        {
+            if issuer.scheme() != "https" {
+                return Err("oidc_providers issuer must be an HTTPS URL".into());
+            }
+        }
+        if issuer.query().is_some() || issuer.fragment().is_some() {
+            return Err("oidc_providers issuer must not contain a query or fragment".into());
+        }
+        if self.client_id.is_empty() {
+            return Err("oidc_providers client_id must not be empty".into());
+        }
+        if self.domains.is_empty() {
+            return Err("oidc_providers entries must list at least one domain".into());
+        }
+        self.domains = self
+            .domains
+            .iter()
+            .map(|domain| idna::domain_to_ascii(domain))
+            .collect::<Result<_, _>>()
+            .map_err(|_| "oidc_providers domains must be valid domain names")?;
+        Ok(self)
+    }
+}
+
 /// Parameters for `StoreConfig::spawn_store`.
 struct StoreParams {
     session_ttl: Duration,
@@ -92,57 +188,133 @@ struct StoreParams {
     fetcher: Addr<FetchAgent>,
     #[allow(dead_code)]
     rng: SecureRandom,
+    encryption: StorageEncryption,
+    /// Whether the store is opened by a short-lived process, like an admin command, instead of
+    /// the broker itself. Such a memory store never writes snapshots.
+    read_only: bool,
+}
+
+/// Minimum length of secret options, like `storage_encryption_key`.
+const MIN_SECRET_LEN: usize = 32;
+
+/// Check that a secret option is long enough, if it is set.
+fn check_secret<'a>(
+    name: &'static str,
+    secret: Option<&'a str>,
+) -> Result<Option<&'a str>, ConfigError> {
+    match secret {
+        Some(secret) if secret.len() < MIN_SECRET_LEN => Err(ConfigError::SecretTooShort(name)),
+        secret => Ok(secret),
+    }
+}
+
+/// Build `StoreParams::encryption` from the `storage_encryption_key` option.
+fn storage_encryption(
+    key: Option<String>,
+    rng: &SecureRandom,
+) -> Result<StorageEncryption, ConfigError> {
+    let key = check_secret("storage_encryption_key", key.as_deref())?;
+    Ok(StorageEncryption::new(key, rng.clone()))
 }
 
 /// Store configuration is first translated into this intermediate enum.
 enum StoreConfig {
     #[cfg(feature = "redis")]
-    Redis(String),
+    Redis(String, TlsConfig),
     #[cfg(feature = "rusqlite")]
-    Rusqlite(PathBuf),
-    Memory,
+    Rusqlite(PathBuf, agents::SqliteOptions),
+    #[cfg(feature = "postgres")]
+    Postgres(String),
+    Memory(Option<PathBuf>),
 }
 
 impl StoreConfig {
+    #[cfg_attr(
+        any(not(feature = "redis"), not(feature = "rusqlite")),
+        allow(unused_variables)
+    )]
     fn from_options(
         redis_url: Option<String>,
+        redis_tls_ca_file: Option<PathBuf>,
+        redis_tls_cert_file: Option<PathBuf>,
+        redis_tls_key_file: Option<PathBuf>,
         sqlite_db: Option<PathBuf>,
+        sqlite_busy_timeout: Duration,
+        sqlite_read_connections: usize,
+        postgres_url: Option<String>,
         memory_storage: bool,
+        memory_snapshot_file: Option<PathBuf>,
     ) -> Result<Self, ConfigError> {
-        match (redis_url, sqlite_db, memory_storage) {
+        if memory_snapshot_file.is_some() && !memory_storage {
+            return Err("memory_snapshot_file can only be used with memory_storage".into());
+        }
+        match (redis_url, sqlite_db, postgres_url, memory_storage) {
             #[cfg(feature = "redis")]
-            (Some(redis_url), None, false) => Ok(StoreConfig::Redis(redis_url)),
+            (Some(redis_url), None, None, false) => {
+                if redis_tls_cert_file.is_some() != redis_tls_key_file.is_some() {
+                    return Err("only one of Redis TLS certificate and key specified; \
+                                provide both or neither"
+                        .into());
+                }
+                let tls = TlsConfig {
+                    ca_file: redis_tls_ca_file,
+                    cert_file: redis_tls_cert_file,
+                    key_file: redis_tls_key_file,
+                };
+                Ok(StoreConfig::Redis(redis_url, tls))
+            }
             #[cfg(not(feature = "redis"))]
-            (Some(_), None, false) => {
+            (Some(_), None, None, false) => {
                 Err("Redis storage requested, but this build does not support it.".into())
             }
 
             #[cfg(feature = "rusqlite")]
-            (None, Some(sqlite_db), false) => Ok(StoreConfig::Rusqlite(sqlite_db)),
+            (None, Some(sqlite_db), None, false) => {
+                if sqlite_read_connections == 0 {
+                    return Err("sqlite_read_connections must be at least 1".into());
+                }
+                let options = agents::SqliteOptions {
+                    busy_timeout: sqlite_busy_timeout,
+                    read_connections: sqlite_read_connections,
+                };
+                Ok(StoreConfig::Rusqlite(sqlite_db, options))
+            }
             #[cfg(not(feature = "rusqlite"))]
-            (None, Some(_), false) => {
+            (None, Some(_), None, false) => {
                 Err("SQLite storage requested, but this build does not support it.".into())
             }
 
-            (None, None, true) => Ok(StoreConfig::Memory),
-
-            (None, None, false) => {
-                Err("Must specify one of redis_url, sqlite_db or memory_storage".into())
+            #[cfg(feature = "postgres")]
+            (None, None, Some(postgres_url), false) => Ok(StoreConfig::Postgres(postgres_url)),
+            #[cfg(not(feature = "postgres"))]
+            (None, None, Some(_), false) => {
+                Err("PostgreSQL storage requested, but this build does not support it.".into())
             }
 
-            _ => Err("Can only specify one of redis_url, sqlite_db or memory_storage".into()),
+            (None, None, None, true) => Ok(StoreConfig::Memory(memory_snapshot_file)),
+
+            (None, None, None, false) => Err(
+                "Must specify one of redis_url, sqlite_db, postgres_url or memory_storage".into(),
+            ),
+
+            _ => Err(
+                "Can only specify one of redis_url, sqlite_db, postgres_url or memory_storage"
+                    .into(),
+            ),
         }
     }
 
     async fn spawn_store(self, params: StoreParams) -> Arc<dyn StoreSender> {
         match self {
             #[cfg(feature = "redis")]
-            StoreConfig::Redis(redis_url) => {
+            StoreConfig::Redis(redis_url, tls) => {
                 let store = agents::RedisStore::new(
                     redis_url,
+                    tls,
                     params.session_ttl,
                     params.cache_ttl,
                     params.limit_configs,
+                    params.encryption,
                     params.fetcher,
                     params.rng,
                 )
@@ -151,25 +323,46 @@ impl StoreConfig {
                 Arc::new(spawn_agent(store).await)
             }
             #[cfg(feature = "rusqlite")]
-            StoreConfig::Rusqlite(sqlite_db) => {
+            StoreConfig::Rusqlite(sqlite_db, options) => {
                 let store = agents::RusqliteStore::new(
                     sqlite_db,
+                    options,
                     params.session_ttl,
                     params.cache_ttl,
                     params.limit_configs,
+                    params.encryption,
                     params.fetcher,
                 )
                 .await
                 .expect("unable to initialize SQLite store");
                 Arc::new(spawn_agent(store).await)
             }
-            StoreConfig::Memory => {
+            #[cfg(feature = "postgres")]
+            StoreConfig::Postgres(postgres_url) => {
+                let store = agents::PostgresStore::new(
+                    &postgres_url,
+                    params.session_ttl,
+                    params.cache_ttl,
+                    params.limit_configs,
+                    params.encryption,
+                    params.fetcher,
+                    params.rng,
+                )
+                .await
+                .expect("unable to initialize PostgreSQL store");
+                Arc::new(spawn_agent(store).await)
+            }
+            StoreConfig::Memory(snapshot_file) => {
                 let store = agents::MemoryStore::new(
                     params.session_ttl,
                     params.cache_ttl,
                     params.limit_configs,
+                    params.encryption,
+                    snapshot_file,
+                    params.read_only,
                     params.fetcher,
-                );
+                )
+                .expect("unable to initialize memory store");
                 Arc::new(spawn_agent(store).await)
             }
         }
@@ -279,10 +472,7 @@ impl MailerConfig {
         }
     }
 
-    async fn spawn_mailer(
-        self,
-        #[allow(unused)] params: MailerParams,
-    ) -> Box<dyn Sender<SendMail>> {
+    async fn spawn_mailer(self, #[allow(unused)] params: MailerParams) -> Box<dyn MailerSender> {
         match self {
             #[cfg(feature = "lettre_smtp")]
             MailerConfig::LettreSmtp {
@@ -333,9 +523,21 @@ impl MailerConfig {
 pub struct ConfigBuilder {
     pub listen_ip: String,
     pub listen_port: u16,
+    pub listen_socket: Option<PathBuf>,
+    pub listen_socket_mode: Option<String>,
+    pub tls_cert_file: Option<PathBuf>,
+    pub tls_key_file: Option<PathBuf>,
+    pub metrics_listen: Option<String>,
+    pub log_format: LogFormat,
+    pub trace_file: Option<PathBuf>,
+    pub trace_otlp_url: Option<String>,
+    pub audit_log: Option<PathBuf>,
+    pub audit_hash_email: bool,
+    pub audit_hash_secret: Option<String>,
     pub public_url: Option<String>,
     pub trusted_proxies: Vec<IpNetwork>,
     pub allowed_origins: Option<Vec<String>>,
+    pub enable_code_flow: bool,
     pub data_dir: String,
 
     pub static_ttl: Duration,
@@ -344,6 +546,8 @@ pub struct ConfigBuilder {
     pub token_ttl: Duration,
     pub session_ttl: Duration,
     pub cache_ttl: Duration,
+    pub shutdown_timeout: Duration,
+    pub discovery_timeout: Duration,
 
     pub keyfiles: Vec<PathBuf>,
     pub keytext: Option<String>,
@@ -351,8 +555,16 @@ pub struct ConfigBuilder {
     pub generate_rsa_command: Vec<String>,
 
     pub redis_url: Option<String>,
+    pub redis_tls_ca_file: Option<PathBuf>,
+    pub redis_tls_cert_file: Option<PathBuf>,
+    pub redis_tls_key_file: Option<PathBuf>,
     pub sqlite_db: Option<PathBuf>,
+    pub sqlite_busy_timeout: Duration,
+    pub sqlite_read_connections: usize,
+    pub postgres_url: Option<String>,
     pub memory_storage: bool,
+    pub memory_snapshot_file: Option<PathBuf>,
+    pub storage_encryption_key: Option<String>,
 
     pub from_name: String,
     pub from_address: Option<String>,
@@ -371,8 +583,10 @@ pub struct ConfigBuilder {
     pub mailgun_domain: Option<String>,
 
     pub limits: Vec<LimitConfig>,
+    pub limit_key_secret: Option<String>,
 
     pub google_client_id: Option<String>,
+    pub oidc_providers: Vec<OidcProvider>,
     pub domain_overrides: HashMap<String, Vec<Link>>,
 }
 
@@ -381,12 +595,24 @@ impl ConfigBuilder {
         ConfigBuilder {
             listen_ip: "127.0.0.1".to_owned(),
             listen_port: 3333,
+            listen_socket: None,
+            listen_socket_mode: None,
+            tls_cert_file: None,
+            tls_key_file: None,
+            metrics_listen: None,
+            log_format: LogFormat::Text,
+            trace_file: None,
+            trace_otlp_url: None,
+            audit_log: None,
+            audit_hash_email: false,
+            audit_hash_secret: None,
             public_url: None,
             trusted_proxies: ["127.0.0.0/8", "::1"]
                 .iter()
                 .map(|v| v.parse().unwrap())
                 .collect(),
             allowed_origins: None,
+            enable_code_flow: false,
             data_dir: String::new(),
 
             static_ttl: Duration::from_secs(604_800),
@@ -395,6 +621,8 @@ impl ConfigBuilder {
             token_ttl: Duration::from_secs(600),
             session_ttl: Duration::from_secs(900),
             cache_ttl: Duration::from_secs(3600),
+            shutdown_timeout: Duration::from_secs(10),
+            discovery_timeout: Duration::from_secs(5),
 
             keyfiles: Vec::new(),
             keytext: None,
@@ -405,8 +633,16 @@ impl ConfigBuilder {
                 .collect(),
 
             redis_url: None,
+            redis_tls_ca_file: None,
+            redis_tls_cert_file: None,
+            redis_tls_key_file: None,
             sqlite_db: None,
+            sqlite_busy_timeout: Duration::from_millis(500),
+            sqlite_read_connections: 4,
+            postgres_url: None,
             memory_storage: false,
+            memory_snapshot_file: None,
+            storage_encryption_key: None,
 
             from_name: "Portier".to_owned(),
             from_address: None,
@@ -434,8 +670,10 @@ impl ConfigBuilder {
             .iter()
             .map(|value| value.parse().unwrap())
             .collect::<Vec<_>>(),
+            limit_key_secret: None,
 
             google_client_id: None,
+            oidc_providers: Vec::new(),
             domain_overrides: HashMap::new(),
         }
     }
@@ -484,9 +722,68 @@ impl ConfigBuilder {
         self
     }
 
+    /// Build the hasher for rate limit keys from the `limit_key_secret` option.
+    pub fn limit_key_hasher(&self) -> Result<Option<KeyedHasher>, ConfigError> {
+        let secret = check_secret("limit_key_secret", self.limit_key_secret.as_deref())?;
+        Ok(secret.map(KeyedHasher::new))
+    }
+
     pub async fn done(mut self) -> Result<Config, ConfigError> {
-        let store_config =
-            StoreConfig::from_options(self.redis_url, self.sqlite_db, self.memory_storage)?;
+        let limit_key_hasher = self.limit_key_hasher()?;
+        if self.discovery_timeout == Duration::from_secs(0) {
+            return Err("discovery_timeout must be at least 1 second".into());
+        }
+        let oidc_providers = self
+            .oidc_providers
+            .into_iter()
+            .map(OidcProvider::validate)
+            .collect::<Result<Vec<_>, _>>()?;
+        let listen_socket_mode = match self.listen_socket_mode {
+            Some(ref mode) => Some(
+                u32::from_str_radix(mode, 8)
+                    .ok()
+                    .filter(|mode| *mode <= 0o777)
+                    .ok_or("listen_socket_mode must be an octal file mode, like \"0660\"")?,
+            ),
+            None => None,
+        };
+        let tls_files = match (self.tls_cert_file, self.tls_key_file) {
+            (Some(cert_file), Some(key_file)) => Some(TlsFiles {
+                cert_file,
+                key_file,
+            }),
+            (None, None) => None,
+            _ => {
+                return Err(
+                    "only one of TLS certificate and key specified; provide both or neither".into(),
+                )
+            }
+        };
+        let metrics_listen = match self.metrics_listen {
+            Some(ref addr) => Some(addr.parse().map_err(|_| {
+                "metrics_listen must be an IP address and port, like \"127.0.0.1:9102\""
+            })?),
+            None => None,
+        };
+        let trace_otlp_url = match self.trace_otlp_url {
+            Some(ref url) => Some(
+                url.parse::<Url>()
+                    .map_err(|_| "trace_otlp_url must be a valid URL")?,
+            ),
+            None => None,
+        };
+        let store_config = StoreConfig::from_options(
+            self.redis_url,
+            self.redis_tls_ca_file,
+            self.redis_tls_cert_file,
+            self.redis_tls_key_file,
+            self.sqlite_db,
+            self.sqlite_busy_timeout,
+            self.sqlite_read_connections,
+            self.postgres_url,
+            self.memory_storage,
+            self.memory_snapshot_file,
+        )?;
         let mailer_config = MailerConfig::from_options(
             self.smtp_server,
             self.smtp_username,
@@ -506,7 +803,28 @@ impl ConfigBuilder {
 
         // Child structs
         let rng = SecureRandom::new().await;
+        let encryption = storage_encryption(self.storage_encryption_key, &rng)?;
         let fetcher = spawn_agent(FetchAgent::new()).await;
+        let tracer = if self.trace_file.is_some() || trace_otlp_url.is_some() {
+            let exporter = SpanExporter::new(self.trace_file, trace_otlp_url);
+            Some(Tracer::new(spawn_agent(exporter).await, rng.clone()))
+        } else {
+            None
+        };
+        let audit_log = match self.audit_log {
+            Some(ref path) => {
+                let secret = check_secret("audit_hash_secret", self.audit_hash_secret.as_deref())?;
+                let email_hasher = match secret {
+                    Some(secret) if self.audit_hash_email => Some(KeyedHasher::new(secret)),
+                    None if self.audit_hash_email => {
+                        return Err("audit_hash_email requires audit_hash_secret to be set".into())
+                    }
+                    _ => None,
+                };
+                Some(spawn_agent(AuditLog::new(path, email_hasher)?).await)
+            }
+            None => None,
+        };
         let store = store_config
             .spawn_store(StoreParams {
                 session_ttl: self.session_ttl,
@@ -514,6 +832,8 @@ impl ConfigBuilder {
                 limit_configs: self.limits,
                 fetcher: fetcher.clone(),
                 rng: rng.clone(),
+                encryption,
+                read_only: false,
             })
             .await;
         let key_manager: Box<dyn KeyManagerSender> =
@@ -542,7 +862,7 @@ impl ConfigBuilder {
             };
         let mailer = mailer_config
             .spawn_mailer(MailerParams {
-                fetcher,
+                fetcher: fetcher.clone(),
                 from_address: self
                     .from_address
                     .expect("No mail 'From' address configured")
@@ -565,6 +885,24 @@ impl ConfigBuilder {
             domain_overrides.insert("googlemail.com".to_owned(), links);
         }
 
+        // Configure domain overrides for upstream OpenID Connect providers. Providers sharing a
+        // domain are tried in the order they are configured.
+        for provider in &oidc_providers {
+            let link = Link {
+                rel: Relation::Oidc,
+                href: provider
+                    .issuer
+                    .parse()
+                    .expect("failed to parse the issuer URL"),
+            };
+            for domain in &provider.domains {
+                domain_overrides
+                    .entry(domain.clone())
+                    .or_insert_with(Vec::new)
+                    .push(link.clone());
+            }
+        }
+
         for (domain, links) in self.domain_overrides {
             domain_overrides.insert(domain, links);
         }
@@ -577,22 +915,36 @@ impl ConfigBuilder {
         Ok(Config {
             listen_ip: self.listen_ip,
             listen_port: self.listen_port,
+            listen_socket: self.listen_socket,
+            listen_socket_mode,
+            tls_files,
+            metrics_listen,
+            tracer,
+            audit_log,
             public_url: self.public_url.expect("no public url configured"),
             trusted_proxies: self.trusted_proxies,
             allowed_origins: self.allowed_origins,
+            enable_code_flow: self.enable_code_flow,
 
             static_ttl: self.static_ttl,
             discovery_ttl: self.discovery_ttl,
             keys_ttl: self.keys_ttl,
             token_ttl: self.token_ttl,
+            shutdown_timeout: self.shutdown_timeout,
+            discovery_timeout: self.discovery_timeout,
+            background_discovery: BackgroundTasks::new(MAX_BACKGROUND_DISCOVERY),
 
             key_manager,
             signing_algs: self.signing_algs,
 
             store,
             mailer,
+            fetcher,
+
+            limit_key_hasher,
 
             google_client_id: self.google_client_id,
+            oidc_providers,
             domain_overrides,
 
             res_dir,
@@ -603,10 +955,21 @@ impl ConfigBuilder {
     }
 
     pub async fn into_store(self) -> Result<Arc<dyn StoreSender>, ConfigError> {
-        let store_config =
-            StoreConfig::from_options(self.redis_url, self.sqlite_db, self.memory_storage)?;
+        let store_config = StoreConfig::from_options(
+            self.redis_url,
+            self.redis_tls_ca_file,
+            self.redis_tls_cert_file,
+            self.redis_tls_key_file,
+            self.sqlite_db,
+            self.sqlite_busy_timeout,
+            self.sqlite_read_connections,
+            self.postgres_url,
+            self.memory_storage,
+            self.memory_snapshot_file,
+        )?;
         let fetcher = spawn_agent(FetchAgent::new()).await;
         let rng = SecureRandom::new().await;
+        let encryption = storage_encryption(self.storage_encryption_key, &rng)?;
         let store = store_config
             .spawn_store(StoreParams {
                 session_ttl: self.session_ttl,
@@ -614,8 +977,46 @@ impl ConfigBuilder {
                 limit_configs: self.limits,
                 fetcher,
                 rng,
+                encryption,
+                read_only: true,
             })
             .await;
         Ok(store)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::OidcProvider;
+
+    fn provider(issuer: &str, domains: &[&str]) -> OidcProvider {
+        OidcProvider {
+            issuer: issuer.to_owned(),
+            client_id: "broker".to_owned(),
+            client_secret: None,
+            domains: domains.iter().map(|&domain| domain.to_owned()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_validate_oidc_provider() {
+        let valid = provider(
+            "https://idp.example.com/realms/test/",
+            &["Example.COM", "bücher.de"],
+        )
+        .validate()
+        .unwrap();
+        assert_eq!(valid.issuer, "https://idp.example.com/realms/test/");
+        assert!(valid.matches_link(&"https://idp.example.com/realms/test".parse().unwrap()));
+        assert!(!valid.matches_link(&"https://idp.example.com/realms".parse().unwrap()));
+        assert_eq!(valid.domains, vec!["example.com", "xn--bcher-kva.de"]);
+        assert!(valid.has_domain(&"john@EXAMPLE.com".parse().unwrap()));
+        assert!(!valid.has_domain(&"john@example.net".parse().unwrap()));
+
+        assert!(provider("https://idp.example.com", &[]).validate().is_err());
+        assert!(provider("https://idp.example.com?a=b", &["example.com"])
+            .validate()
+            .is_err());
+        assert!(provider("not a url", &["example.com"]).validate().is_err());
+    }
+}
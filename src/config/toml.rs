@@ -1,5 +1,6 @@
-use super::{ConfigBuilder, LegacyLimitPerEmail, LimitConfig};
+use super::{ConfigBuilder, LegacyLimitPerEmail, LimitConfig, OidcProvider};
 use crate::crypto::SigningAlgorithm;
+use crate::utils::logger::LogFormat;
 use crate::webfinger::Link;
 use serde::Deserialize;
 use std::collections::HashMap;
@@ -12,8 +13,20 @@ use std::time::Duration;
 pub struct TomlConfig {
     listen_ip: Option<String>,
     listen_port: Option<u16>,
+    listen_socket: Option<PathBuf>,
+    listen_socket_mode: Option<String>,
+    tls_cert_file: Option<PathBuf>,
+    tls_key_file: Option<PathBuf>,
+    metrics_listen: Option<String>,
+    log_format: Option<LogFormat>,
+    trace_file: Option<PathBuf>,
+    trace_otlp_url: Option<String>,
+    audit_log: Option<PathBuf>,
+    audit_hash_email: Option<bool>,
+    audit_hash_secret: Option<String>,
     public_url: Option<String>,
     allowed_origins: Option<Vec<String>>,
+    enable_code_flow: Option<bool>,
     data_dir: Option<String>,
 
     static_ttl: Option<u64>,
@@ -22,6 +35,8 @@ pub struct TomlConfig {
     token_ttl: Option<u64>,
     session_ttl: Option<u64>,
     cache_ttl: Option<u64>,
+    shutdown_timeout: Option<u64>,
+    discovery_timeout: Option<u64>,
 
     keyfiles: Option<Vec<PathBuf>>,
     keytext: Option<String>,
@@ -29,8 +44,16 @@ pub struct TomlConfig {
     generate_rsa_command: Option<Vec<String>>,
 
     redis_url: Option<String>,
+    redis_tls_ca_file: Option<PathBuf>,
+    redis_tls_cert_file: Option<PathBuf>,
+    redis_tls_key_file: Option<PathBuf>,
     sqlite_db: Option<PathBuf>,
+    sqlite_busy_timeout_ms: Option<u64>,
+    sqlite_read_connections: Option<usize>,
+    postgres_url: Option<String>,
     memory_storage: Option<bool>,
+    memory_snapshot_file: Option<PathBuf>,
+    storage_encryption_key: Option<String>,
 
     from_name: Option<String>,
     from_address: Option<String>,
@@ -49,8 +72,10 @@ pub struct TomlConfig {
 
     limits: Option<Vec<LimitConfig>>,
     limit_per_email: Option<LegacyLimitPerEmail>,
+    limit_key_secret: Option<String>,
 
     google_client_id: Option<String>,
+    oidc_providers: Option<Vec<OidcProvider>>,
     domain_overrides: Option<HashMap<String, Vec<Link>>>,
 
     // Deprecated.
@@ -230,12 +255,48 @@ impl TomlConfig {
         if let Some(val) = parsed.listen_port {
             builder.listen_port = val;
         }
+        if let Some(val) = parsed.listen_socket {
+            builder.listen_socket = Some(val);
+        }
+        if let Some(val) = parsed.listen_socket_mode {
+            builder.listen_socket_mode = Some(val);
+        }
+        if let Some(val) = parsed.tls_cert_file {
+            builder.tls_cert_file = Some(val);
+        }
+        if let Some(val) = parsed.tls_key_file {
+            builder.tls_key_file = Some(val);
+        }
+        if let Some(val) = parsed.metrics_listen {
+            builder.metrics_listen = Some(val);
+        }
+        if let Some(val) = parsed.log_format {
+            builder.log_format = val;
+        }
+        if let Some(val) = parsed.trace_file {
+            builder.trace_file = Some(val);
+        }
+        if let Some(val) = parsed.trace_otlp_url {
+            builder.trace_otlp_url = Some(val);
+        }
+        if let Some(val) = parsed.audit_log {
+            builder.audit_log = Some(val);
+        }
+        if let Some(val) = parsed.audit_hash_email {
+            builder.audit_hash_email = val;
+        }
+        if let Some(val) = parsed.audit_hash_secret {
+            builder.audit_hash_secret = Some(val);
+        }
         if let Some(val) = parsed.public_url {
             builder.public_url = Some(val);
         }
         if let Some(val) = parsed.allowed_origins {
             builder.allowed_origins = Some(val)
         };
+        if let Some(val) = parsed.enable_code_flow {
+            builder.enable_code_flow = val;
+        }
         if let Some(val) = parsed.data_dir {
             builder.data_dir = val;
         }
@@ -258,6 +319,12 @@ impl TomlConfig {
         if let Some(val) = parsed.cache_ttl {
             builder.cache_ttl = Duration::from_secs(val);
         }
+        if let Some(val) = parsed.shutdown_timeout {
+            builder.shutdown_timeout = Duration::from_secs(val);
+        }
+        if let Some(val) = parsed.discovery_timeout {
+            builder.discovery_timeout = Duration::from_secs(val);
+        }
 
         if let Some(mut val) = parsed.keyfiles {
             builder.keyfiles.append(&mut val);
@@ -275,12 +342,36 @@ impl TomlConfig {
         if let Some(val) = parsed.redis_url {
             builder.redis_url = Some(val);
         }
+        if let Some(val) = parsed.redis_tls_ca_file {
+            builder.redis_tls_ca_file = Some(val);
+        }
+        if let Some(val) = parsed.redis_tls_cert_file {
+            builder.redis_tls_cert_file = Some(val);
+        }
+        if let Some(val) = parsed.redis_tls_key_file {
+            builder.redis_tls_key_file = Some(val);
+        }
         if let Some(val) = parsed.sqlite_db {
             builder.sqlite_db = Some(val);
         }
+        if let Some(val) = parsed.sqlite_busy_timeout_ms {
+            builder.sqlite_busy_timeout = Duration::from_millis(val);
+        }
+        if let Some(val) = parsed.sqlite_read_connections {
+            builder.sqlite_read_connections = val;
+        }
+        if let Some(val) = parsed.postgres_url {
+            builder.postgres_url = Some(val);
+        }
         if let Some(val) = parsed.memory_storage {
             builder.memory_storage = val;
         }
+        if let Some(val) = parsed.memory_snapshot_file {
+            builder.memory_snapshot_file = Some(val);
+        }
+        if let Some(val) = parsed.storage_encryption_key {
+            builder.storage_encryption_key = Some(val);
+        }
 
         if let Some(val) = parsed.from_name {
             builder.from_name = val;
@@ -324,10 +415,16 @@ impl TomlConfig {
             log::warn!("TOML field 'limit_per_email' is deprecated. Please use 'limits' instead.");
             builder.limits = vec![val.0];
         }
+        if let Some(val) = parsed.limit_key_secret {
+            builder.limit_key_secret = Some(val);
+        }
 
         if let Some(val) = parsed.google_client_id {
             builder.google_client_id = Some(val);
         }
+        if let Some(val) = parsed.oidc_providers {
+            builder.oidc_providers = val;
+        }
         if let Some(val) = parsed.domain_overrides {
             for (domain, links) in val {
                 builder.domain_overrides.insert(domain, links);
@@ -24,6 +24,9 @@ pub enum SigningAlgorithm {
 }
 
 impl SigningAlgorithm {
+    /// All supported algorithms.
+    pub const ALL: [Self; 2] = [SigningAlgorithm::EdDsa, SigningAlgorithm::Rs256];
+
     /// Get the JWA string representation.
     pub fn as_str(self) -> &'static str {
         use SigningAlgorithm::*;
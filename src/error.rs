@@ -72,6 +72,19 @@ impl BrokerError {
         }
     }
 
+    /// Get a short name for the kind of error, used in metrics.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            BrokerError::Input(_) => "input",
+            BrokerError::Provider(_) => "provider",
+            BrokerError::ProviderInput(_) => "provider_input",
+            BrokerError::Internal(_) => "internal",
+            BrokerError::RateLimited => "rate_limited",
+            BrokerError::SessionExpired => "session_expired",
+            BrokerError::ProviderCancelled => "provider_cancelled",
+        }
+    }
+
     /// Get the OAuth2 error code for this error
     pub fn oauth_error_code(&self) -> &str {
         match *self {
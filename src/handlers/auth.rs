@@ -1,24 +1,34 @@
 use crate::agents::{GetPublicJwks, IncrAndTestLimits};
 use crate::bridges;
-use crate::config::LimitInput;
+use crate::config::{ConfigRc, LimitInput};
 use crate::crypto::SigningAlgorithm;
 use crate::email_address::EmailAddress;
 use crate::error::BrokerError;
+use crate::metrics;
+use crate::telemetry;
+use crate::utils::background::BackgroundGuard;
 use crate::validation::parse_redirect_uri;
-use crate::web::{html_response, json_response, Context, HandlerResult, ReturnParams};
-use crate::webfinger::{self, Relation};
+use crate::web::{
+    html_response, json_response, Context, HandlerResult, ResponseMode, ReturnParams,
+};
+use crate::webfinger::{self, Link, Relation};
 use http::Method;
 use log::info;
 use serde_json::{from_value, json, Value};
 use std::collections::HashSet;
-use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Maximum number of providers tried for an email domain, before falling back to email.
+///
+/// Each provider may take up to `discovery_timeout`, so this bounds the time spent on discovery.
+const MAX_DISCOVERY_LINKS: usize = 3;
 
 /// Request handler to return the OpenID Discovery document.
 ///
 /// Most of this is hard-coded for now, although the URLs are constructed by
 /// using the base URL as configured in the `public_url` configuration value.
 pub async fn discovery(ctx: &mut Context) -> HandlerResult {
-    let obj = json!({
+    let mut obj = json!({
         "issuer": ctx.app.public_url,
         "authorization_endpoint": format!("{}/auth", ctx.app.public_url),
         "jwks_uri": format!("{}/keys.json", ctx.app.public_url),
@@ -32,6 +42,33 @@ pub async fn discovery(ctx: &mut Context) -> HandlerResult {
         // NOTE: This field is non-standard.
         "accepts_id_token_signing_alg_query_param": true,
     });
+    if ctx.app.enable_code_flow {
+        let obj = obj.as_object_mut().unwrap();
+        obj.insert(
+            "token_endpoint".to_owned(),
+            json!(format!("{}/token", ctx.app.public_url)),
+        );
+        obj.insert(
+            "response_types_supported".to_owned(),
+            json!(["id_token", "code"]),
+        );
+        obj.insert(
+            "response_modes_supported".to_owned(),
+            json!(["form_post", "fragment", "query"]),
+        );
+        obj.insert(
+            "grant_types_supported".to_owned(),
+            json!(["implicit", "authorization_code"]),
+        );
+        obj.insert(
+            "code_challenge_methods_supported".to_owned(),
+            json!(["S256"]),
+        );
+        obj.insert(
+            "token_endpoint_auth_methods_supported".to_owned(),
+            json!(["none"]),
+        );
+    }
     Ok(json_response(&obj, Some(ctx.app.discovery_ttl)))
 }
 
@@ -64,7 +101,12 @@ pub async fn auth(ctx: &mut Context) -> HandlerResult {
 
     let redirect_uri = try_get_input_param!(params, "redirect_uri");
     let client_id = try_get_input_param!(params, "client_id");
-    let response_mode = try_get_input_param!(params, "response_mode", "fragment".to_owned());
+    // The default response_mode depends on the response_type, but we can only validate the latter
+    // once we are able to redirect errors to the RP.
+    let is_code_flow = params.get("response_type").map(String::as_str) == Some("code");
+    let default_response_mode = if is_code_flow { "query" } else { "fragment" };
+    let response_mode =
+        try_get_input_param!(params, "response_mode", default_response_mode.to_owned());
     let response_errors = try_get_input_param!(params, "response_errors", "true".to_owned());
     let state = try_get_input_param!(params, "state", "".to_owned());
 
@@ -80,7 +122,9 @@ pub async fn auth(ctx: &mut Context) -> HandlerResult {
     // Parse response_mode by wrapping it a JSON Value.
     // This has minimal overhead, and saves us a separate implementation.
     let response_mode = from_value(Value::String(response_mode)).map_err(|_err| {
-        BrokerError::Input("unsupported response_mode, must be fragment or form_post".to_owned())
+        BrokerError::Input(
+            "unsupported response_mode, must be fragment, form_post or query".to_owned(),
+        )
     })?;
 
     // NOTE: This query parameter is non-standard.
@@ -96,6 +140,7 @@ pub async fn auth(ctx: &mut Context) -> HandlerResult {
         response_mode,
         response_errors,
         state,
+        code_challenge: None,
     });
 
     if let Some(ref whitelist) = ctx.app.allowed_origins {
@@ -107,10 +152,44 @@ pub async fn auth(ctx: &mut Context) -> HandlerResult {
     }
 
     let nonce = try_get_input_param!(params, "nonce");
-    if try_get_input_param!(params, "response_type") != "id_token" {
-        return Err(BrokerError::Input(
-            "unsupported response_type, only id_token is supported".to_owned(),
-        ));
+    match try_get_input_param!(params, "response_type").as_str() {
+        "id_token" => {
+            // Never put tokens in the query, where they may end up in logs.
+            if matches!(response_mode, ResponseMode::Query) {
+                return Err(BrokerError::Input(
+                    "response_mode query is not supported for response_type id_token".to_owned(),
+                ));
+            }
+        }
+        "code" if ctx.app.enable_code_flow => {
+            // We only support public clients, so always require PKCE.
+            let code_challenge = try_get_input_param!(params, "code_challenge");
+            let code_challenge_method =
+                try_get_input_param!(params, "code_challenge_method", "plain".to_owned());
+            if code_challenge_method != "S256" {
+                return Err(BrokerError::Input(
+                    "unsupported code_challenge_method, must be S256".to_owned(),
+                ));
+            }
+            if !(43..=128).contains(&code_challenge.len()) {
+                return Err(BrokerError::Input("invalid code_challenge".to_owned()));
+            }
+            ctx.return_params
+                .as_mut()
+                .expect("return parameters were just set")
+                .code_challenge = Some(code_challenge);
+        }
+        _ => {
+            let supported = if ctx.app.enable_code_flow {
+                "id_token or code"
+            } else {
+                "id_token"
+            };
+            return Err(BrokerError::Input(format!(
+                "unsupported response_type, must be {}",
+                supported
+            )));
+        }
     }
 
     let scope = try_get_input_param!(params, "scope");
@@ -173,16 +252,15 @@ pub async fn auth(ctx: &mut Context) -> HandlerResult {
     })?;
 
     // Enforce rate limits.
-    match ctx
-        .app
-        .store
-        .send(IncrAndTestLimits {
-            input: LimitInput {
-                email_addr: email_addr.clone(),
-                origin: client_id.clone(),
-                ip: ctx.ip,
-            },
-        })
+    match telemetry::span("store.incr_and_test_limits")
+        .run(ctx.app.store.send(IncrAndTestLimits {
+            input: LimitInput::new(
+                &email_addr,
+                &client_id,
+                ctx.ip,
+                ctx.app.limit_key_hasher.as_ref(),
+            ),
+        }))
         .await
     {
         Ok(true) => {}
@@ -207,50 +285,104 @@ pub async fn auth(ctx: &mut Context) -> HandlerResult {
     .await;
 
     // Discover the authentication endpoints based on the email domain.
+    let discovery_timeout = ctx.app.discovery_timeout;
     let discovery_future = async {
-        let links = webfinger::query(&ctx.app, &email_addr).await?;
+        // If there is room for background discovery of this domain, the query runs as a separate
+        // task, so that it can continue if it times out.
+        let mut webfinger_task = ctx
+            .app
+            .background_discovery
+            .try_start(email_addr.domain())
+            .map(|guard| {
+                let app = ctx.app.clone();
+                let email_addr = email_addr.clone();
+                telemetry::spawn(async move { (webfinger::query(&app, &email_addr).await, guard) })
+            });
+        let webfinger_future = async {
+            match webfinger_task {
+                Some(ref mut task) => task
+                    .await
+                    .map_err(|e| {
+                        BrokerError::Internal(format!("webfinger query task failed: {}", e))
+                    })
+                    .and_then(|(links, _guard)| links),
+                None => webfinger::query(&ctx.app, &email_addr).await,
+            }
+        };
+        let links = match telemetry::span("webfinger.query")
+            .run(tokio::time::timeout(discovery_timeout, webfinger_future))
+            .await
+        {
+            Ok(links) => links?,
+            Err(_) => {
+                info!("webfinger query timed out for {}", email_addr);
+                metrics::DISCOVERY_TIMEOUTS.inc();
+                telemetry::set_attribute("discovery.timed_out", true);
+                if let Some(task) = webfinger_task {
+                    continue_discovery(ctx.app.clone(), email_addr.clone(), task);
+                }
+                return Ok(None);
+            }
+        };
 
-        // Try to authenticate with the first provider.
-        // TODO: Queue discovery of links and process in order, with individual timeouts.
-        let link = links.first().ok_or(BrokerError::ProviderCancelled)?;
-        match link.rel {
-            // Portier and Google providers share an implementation
-            Relation::Portier | Relation::Google => {
-                bridges::oidc::auth(ctx, &email_addr, link).await
+        // Try each provider in order, falling through to the next on errors and timeouts.
+        if links.len() > MAX_DISCOVERY_LINKS {
+            info!(
+                "only trying the first {} of {} providers for {}",
+                MAX_DISCOVERY_LINKS,
+                links.len(),
+                email_addr.domain()
+            );
+        }
+        for (link_index, link) in links.iter().enumerate().take(MAX_DISCOVERY_LINKS) {
+            let link_future = match link.rel {
+                // Portier, Google and configured providers share an implementation
+                Relation::Portier | Relation::Google | Relation::Oidc => {
+                    bridges::oidc::auth(ctx, &email_addr, link, link_index)
+                }
+            };
+            match tokio::time::timeout(discovery_timeout, link_future).await {
+                Err(_) => {
+                    // The bridge usually fetches the provider configuration in a separate task,
+                    // which continues in the background and fills the cache.
+                    info!(
+                        "discovery timed out for {} with provider {}",
+                        email_addr, link.href
+                    );
+                    metrics::DISCOVERY_TIMEOUTS.inc();
+                    telemetry::set_attribute("discovery.timed_out", true);
+                }
+                Ok(Ok(v)) => {
+                    telemetry::set_attribute("discovery.link_index", link_index);
+                    return Ok(Some(v));
+                }
+                Ok(Err(e @ BrokerError::Provider(_)))
+                | Ok(Err(e @ BrokerError::ProviderCancelled)) => {
+                    // Provider errors cause fallback to the next provider.
+                    e.log(None).await;
+                }
+                Ok(Err(e)) => return Err(e),
             }
         }
+        Ok(None)
     };
 
-    // Apply a timeout to discovery.
-    match tokio::time::timeout(Duration::from_secs(5), discovery_future).await {
-        Err(_) => {
-            // Timeout causes fall back to email loop auth.
-            info!("discovery timed out for {}", email_addr);
-
-            // TODO: We used to (before async) continue discovery in the background, using shared
-            // access to Context through RefCell. We could bring that back by decoupling auth
-            // mechanisms from Context.
-            //
-            // (The original idea was also for auth mechanisms to have a 'commit' action, to
-            // indicate a side-effect is about the happen. From this side, it'd effectively abort
-            // the timeout and bubble all errors. An intermediate AuthContext could provide this.)
-            /*
-            tokio::spawn(async {
-                if let Err(e) = f.await {
-                    e.log();
-                }
-            });
-            */
-        }
-        Ok(Ok(v)) => {
+    let discovery_future = telemetry::span("discovery")
+        .attr("domain", email_addr.domain())
+        .run(discovery_future);
+    match discovery_future.await {
+        Ok(Some(v)) => {
             // Discovery succeeded, simply return the response.
             return Ok(v);
         }
-        Ok(Err(e @ BrokerError::Provider(_))) | Ok(Err(e @ BrokerError::ProviderCancelled)) => {
+        Ok(None) => {
+            // No provider was available, or all timed out.
+        }
+        Err(e @ BrokerError::Provider(_)) | Err(e @ BrokerError::ProviderCancelled) => {
             // Provider errors cause fallback to email loop auth.
             e.log(None).await;
         }
-        Ok(Err(e)) => {
+        Err(e) => {
             // Other errors during discovery are bubbled.
             return Err(e);
         }
@@ -259,3 +391,30 @@ pub async fn auth(ctx: &mut Context) -> HandlerResult {
     // Fall back to email loop auth.
     bridges::email::auth(ctx, email_addr).await
 }
+
+/// Continue discovery in the background after the webfinger query timed out.
+///
+/// Waits for the query to finish, then fetches the configuration of each provider found. This
+/// fills the cache, so a next authentication request for the domain can be fast. The slot in
+/// `background_discovery` claimed for the query is held until this is done.
+fn continue_discovery(
+    app: ConfigRc,
+    email_addr: EmailAddress,
+    webfinger_task: JoinHandle<(Result<Vec<Link>, BrokerError>, BackgroundGuard)>,
+) {
+    telemetry::spawn(async move {
+        let (links, _guard) = match webfinger_task.await {
+            Ok((Ok(links), guard)) => (links, guard),
+            Ok((Err(e), _)) => {
+                e.log(None).await;
+                return;
+            }
+            Err(_) => return,
+        };
+        for link in links.iter().take(MAX_DISCOVERY_LINKS) {
+            if let Err(e) = bridges::oidc::prefetch(&app, &email_addr, link).await {
+                e.log(None).await;
+            }
+        }
+    });
+}
@@ -0,0 +1,77 @@
+use crate::agents::{CheckKeys, CheckMailer, CheckStore};
+use crate::crypto::SigningAlgorithm;
+use crate::utils::http::ResponseExt;
+use crate::web::{json_response, Context, HandlerResult};
+use headers::CacheControl;
+use http::StatusCode;
+use serde_json::{json, Value};
+use std::future::Future;
+use std::time::Duration;
+
+/// How long to wait for an agent to respond to a check.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Request handler for the health and readiness endpoints.
+///
+/// Checks that the store can reach its backend, that keys are active for every configured signing
+/// algorithm, and that a mailer is configured. The response lists the status of each component,
+/// and the status code is 503 if any check failed.
+pub async fn health(ctx: &mut Context) -> HandlerResult {
+    let store = check(ctx.app.store.send(CheckStore), |result| {
+        result.map(|()| json!({})).map_err(|err| err.to_string())
+    });
+    let keys = check(ctx.app.key_manager.send(CheckKeys), |missing| {
+        if missing.is_empty() {
+            Ok(json!({}))
+        } else {
+            Err(format!(
+                "no active keys for: {}",
+                SigningAlgorithm::format_list(&missing)
+            ))
+        }
+    });
+    let mailer = check(ctx.app.mailer.send(CheckMailer), |backend| {
+        Ok(json!({ "backend": backend }))
+    });
+    let (store, keys, mailer) = futures_util::join!(store, keys, mailer);
+
+    let healthy = [&store, &keys, &mailer]
+        .iter()
+        .all(|component| component["status"] == "ok");
+    let mut res = json_response(
+        &json!({
+            "status": if healthy { "ok" } else { "error" },
+            "components": {
+                "store": store,
+                "keys": keys,
+                "mailer": mailer,
+            },
+        }),
+        None,
+    );
+    res.typed_header(CacheControl::new().with_no_cache().with_no_store());
+    if !healthy {
+        *res.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    }
+    Ok(res)
+}
+
+/// Wait for the reply to a check message, and describe the result as a JSON object.
+///
+/// The function translates the reply to either an object with extra details, or an error message.
+async fn check<T, F>(reply: impl Future<Output = T>, f: F) -> Value
+where
+    F: FnOnce(T) -> Result<Value, String>,
+{
+    let result = match tokio::time::timeout(CHECK_TIMEOUT, reply).await {
+        Ok(reply) => f(reply),
+        Err(_) => Err("timed out".to_owned()),
+    };
+    match result {
+        Ok(mut details) => {
+            details["status"] = "ok".into();
+            details
+        }
+        Err(err) => json!({ "status": "error", "error": err }),
+    }
+}
@@ -1,4 +1,6 @@
 pub mod auth;
+pub mod health;
 pub mod normalize;
 pub mod pages;
 pub mod rewrite_to_post;
+pub mod token;
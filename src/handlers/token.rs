@@ -0,0 +1,125 @@
+use crate::agents::TakeSession;
+use crate::bridges::{code_session_id, BridgeData};
+use crate::error::BrokerError;
+use crate::telemetry;
+use crate::utils::http::ResponseExt;
+use crate::utils::{base64url, unix_timestamp};
+use crate::web::{json_response, Context, HandlerResult, Response, Session};
+use headers::CacheControl;
+use http::StatusCode;
+use ring::{constant_time, digest};
+use serde_json::json;
+
+/// Request handler for the token endpoint.
+///
+/// Relying parties using the authorization code flow redeem the code they received here for an
+/// identity token. Codes can only be used once, and the PKCE verifier must match the challenge
+/// sent in the authentication request.
+pub async fn token(ctx: &mut Context) -> HandlerResult {
+    if !ctx.app.enable_code_flow {
+        return Ok(token_error(
+            "unsupported_grant_type",
+            "the authorization code flow is not enabled",
+        ));
+    }
+
+    let mut params = ctx.form_params();
+    if params.remove("grant_type").as_deref() != Some("authorization_code") {
+        return Ok(token_error(
+            "unsupported_grant_type",
+            "grant_type must be authorization_code",
+        ));
+    }
+    let (code, redirect_uri, client_id, code_verifier) = match (
+        params.remove("code"),
+        params.remove("redirect_uri"),
+        params.remove("client_id"),
+        params.remove("code_verifier"),
+    ) {
+        (Some(code), Some(redirect_uri), Some(client_id), Some(code_verifier)) => {
+            (code, redirect_uri, client_id, code_verifier)
+        }
+        _ => {
+            return Ok(token_error(
+                "invalid_request",
+                "missing one of code, redirect_uri, client_id or code_verifier",
+            ))
+        }
+    };
+
+    // Codes are single use, so take the session right away, even if verification fails below.
+    // When the same code is redeemed concurrently, only one request receives the session.
+    let session = telemetry::span("store.take_session")
+        .run(ctx.app.store.send(TakeSession {
+            session_id: code_session_id(&code),
+        }))
+        .await
+        .map_err(|e| BrokerError::Internal(format!("could not load a code: {}", e)))?;
+    let Session { data, bridge_data } = match session {
+        Some(session) => session,
+        None => {
+            return Ok(token_error(
+                "invalid_grant",
+                "the code is invalid or expired",
+            ))
+        }
+    };
+    #[allow(clippy::match_wildcard_for_single_variants)]
+    let code_data = match bridge_data {
+        BridgeData::Code(code_data) if code_data.expires > unix_timestamp() => code_data,
+        _ => {
+            return Ok(token_error(
+                "invalid_grant",
+                "the code is invalid or expired",
+            ))
+        }
+    };
+
+    let return_params = &data.return_params;
+    if client_id != return_params.redirect_uri.origin().ascii_serialization()
+        || redirect_uri != return_params.redirect_uri.as_str()
+    {
+        return Ok(token_error(
+            "invalid_grant",
+            "the client_id and redirect_uri must match the authentication request",
+        ));
+    }
+
+    let challenge = digest::digest(&digest::SHA256, code_verifier.as_bytes());
+    let challenge = base64url::encode(challenge.as_ref());
+    if constant_time::verify_slices_are_equal(
+        challenge.as_bytes(),
+        code_data.code_challenge.as_bytes(),
+    )
+    .is_err()
+    {
+        return Ok(token_error(
+            "invalid_grant",
+            "the code_verifier does not match the code_challenge",
+        ));
+    }
+
+    let mut res = json_response(
+        &json!({
+            "id_token": &code_data.id_token,
+            "token_type": "Bearer",
+        }),
+        None,
+    );
+    res.typed_header(CacheControl::new().with_no_store());
+    Ok(res)
+}
+
+/// Build an error response as described in RFC 6749 section 5.2.
+fn token_error(error: &str, description: &str) -> Response {
+    let mut res = json_response(
+        &json!({
+            "error": error,
+            "error_description": description,
+        }),
+        None,
+    );
+    *res.status_mut() = StatusCode::BAD_REQUEST;
+    res.typed_header(CacheControl::new().with_no_store());
+    res
+}
@@ -13,30 +13,36 @@
 #[macro_use]
 mod macros;
 
+mod admin;
 mod agents;
+mod audit;
 mod bridges;
 mod config;
 mod crypto;
 mod email_address;
 mod error;
 mod handlers;
+mod metrics;
 mod router;
+mod telemetry;
 mod utils;
 mod validation;
 mod web;
 mod webfinger;
 
 use crate::agents::{Expiring, ImportKeySet, KeySet};
-use crate::config::{ConfigBuilder, ConfigRc};
+use crate::config::{Config, ConfigBuilder, ConfigRc};
 use crate::crypto::SigningAlgorithm;
 use crate::utils::{
+    key_backup,
+    listener::{accept_connections, Connection, ConnectionTasks, Listener, ReloadableTlsAcceptor},
     pem::{self, ParsedKeyPair},
     BoxError,
 };
 use crate::web::Service;
 use futures_util::future;
 use hyper::{server::Server, service::make_service_fn};
-use log::info;
+use log::{error, info, warn};
 use serde::Deserialize;
 use std::{
     io::{Cursor, Read},
@@ -44,6 +50,13 @@ use std::{
     path::{Path, PathBuf},
     time::SystemTime,
 };
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::sync::oneshot;
+
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
 
 /// Defines the program's version, as set by Cargo at compile time.
 const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -56,14 +69,38 @@ Portier Broker
 
 Usage:
   portier-broker [CONFIG]
-  portier-broker [CONFIG] --import-key FILE
+  portier-broker [CONFIG] --import-key FILE [--passphrase-file PASSFILE]
+  portier-broker [CONFIG] sessions list
+  portier-broker [CONFIG] sessions expire SESSION
+  portier-broker [CONFIG] limits show [KEY]
+  portier-broker [CONFIG] limits reset KEY
+  portier-broker [CONFIG] limits hash VALUE
+  portier-broker [CONFIG] keys show
+  portier-broker [CONFIG] keys rotate
+  portier-broker [CONFIG] keys export OUTPUT [--passphrase-file PASSFILE]
+  portier-broker [CONFIG] cache flush
   portier-broker --version
   portier-broker --help
 
 Options:
   --version          Print version information and exit
   --help             Print this help message and exit
-  --import-key FILE  Import a PEM private key, for migrating to rotating keys
+  --import-key FILE  Import a PEM private key, for migrating to rotating keys,
+                     or a key backup created with `keys export`
+  --passphrase-file PASSFILE
+                     Read the passphrase of an encrypted key backup from a file
+
+Commands:
+  sessions list      List active sessions
+  sessions expire    Expire a session by its ID
+  limits show        Show rate limit counters, optionally only those starting with KEY
+  limits reset       Reset the rate limit counter with key KEY
+  limits hash        Print the hash of VALUE as used in rate limit keys, when
+                     limit_key_secret is set
+  keys show          Show the stored keys for each signing algorithm
+  keys rotate        Force a key rotation for each signing algorithm
+  keys export        Write all stored keys to a backup file, or stdout if OUTPUT is -
+  cache flush        Remove all entries from the fetch cache
 "#;
 
 /// Holds parsed command line parameters.
@@ -71,7 +108,56 @@ Options:
 #[allow(non_snake_case)]
 struct Args {
     arg_CONFIG: Option<PathBuf>,
+    arg_SESSION: Option<String>,
+    arg_KEY: Option<String>,
+    arg_VALUE: Option<String>,
+    arg_OUTPUT: Option<PathBuf>,
     flag_import_key: Option<PathBuf>,
+    flag_passphrase_file: Option<PathBuf>,
+    cmd_sessions: bool,
+    cmd_list: bool,
+    cmd_expire: bool,
+    cmd_limits: bool,
+    cmd_show: bool,
+    cmd_reset: bool,
+    cmd_hash: bool,
+    cmd_keys: bool,
+    cmd_rotate: bool,
+    cmd_export: bool,
+    cmd_cache: bool,
+    cmd_flush: bool,
+}
+
+impl Args {
+    /// The admin command to run, if any.
+    fn admin_command(&self) -> Option<admin::Command> {
+        use admin::Command::*;
+        let command = if self.cmd_sessions && self.cmd_list {
+            ListSessions
+        } else if self.cmd_sessions && self.cmd_expire {
+            ExpireSession(self.arg_SESSION.clone().unwrap())
+        } else if self.cmd_limits && self.cmd_show {
+            ShowLimits(self.arg_KEY.clone())
+        } else if self.cmd_limits && self.cmd_reset {
+            ResetLimit(self.arg_KEY.clone().unwrap())
+        } else if self.cmd_limits && self.cmd_hash {
+            HashLimitValue(self.arg_VALUE.clone().unwrap())
+        } else if self.cmd_keys && self.cmd_show {
+            ShowKeys
+        } else if self.cmd_keys && self.cmd_rotate {
+            RotateKeys
+        } else if self.cmd_keys && self.cmd_export {
+            ExportKeys {
+                output: self.arg_OUTPUT.clone().unwrap(),
+                passphrase_file: self.flag_passphrase_file.clone(),
+            }
+        } else if self.cmd_cache && self.cmd_flush {
+            FlushCache
+        } else {
+            return None;
+        };
+        Some(command)
+    }
 }
 
 /// The `main()` method. Will loop forever to serve HTTP requests.
@@ -98,9 +184,12 @@ async fn main() {
     }
     builder.update_from_common_env();
     builder.update_from_broker_env();
+    crate::utils::logger::set_format(builder.log_format);
 
     if let Some(ref path) = args.flag_import_key {
-        import_key(builder, path).await;
+        import_key(builder, path, args.flag_passphrase_file.as_deref()).await;
+    } else if let Some(command) = args.admin_command() {
+        admin::run(builder, command).await;
     } else {
         start_server(builder).await;
     }
@@ -114,40 +203,186 @@ async fn start_server(builder: ConfigBuilder) {
             .unwrap_or_else(|err| panic!(format!("failed to build configuration: {}", err))),
     );
 
-    // TODO: Add unix socket support.
-    let builder = match listenfd::ListenFd::from_env().take_tcp_listener(0) {
-        Ok(Some(tcp_listener)) => {
-            let builder = Server::from_tcp(tcp_listener).expect("Socket activation failed");
-            info!("Listening on the socket received from the service manager");
-            builder
-        }
-        Ok(None) => {
-            let ip_addr = app
-                .listen_ip
-                .parse()
-                .expect("Unable to parse listen address");
-            let addr = SocketAddr::new(ip_addr, app.listen_port);
-            let builder = Server::bind(&addr);
-            info!("Listening on {}", addr);
-            builder
-        }
-        Err(err) => {
-            panic!("Socket activation failed: {}", err);
+    let tls = app.tls_files.clone().map(|files| {
+        ReloadableTlsAcceptor::new(files)
+            .unwrap_or_else(|err| panic!("Failed to initialize TLS: {}", err))
+    });
+    #[cfg(unix)]
+    {
+        if let Some(ref tls) = tls {
+            reload_tls_on_sighup(tls.clone());
         }
+    }
+
+    let mut listenfd = listenfd::ListenFd::from_env();
+    let mut socket_file = None;
+    let listener = if let Some(unix_listener) = take_unix_listener(&mut listenfd) {
+        info!("Listening on the Unix socket received from the service manager");
+        unix_listener
+    } else if let Some(tcp_listener) = listenfd
+        .take_tcp_listener(0)
+        .unwrap_or_else(|err| panic!("Socket activation failed: {}", err))
+    {
+        tcp_listener
+            .set_nonblocking(true)
+            .expect("Socket activation failed");
+        let listener = TcpListener::from_std(tcp_listener).expect("Socket activation failed");
+        info!("Listening on the socket received from the service manager");
+        Listener::Tcp(listener)
+    } else if let Some(ref path) = app.listen_socket {
+        let listener = bind_unix(path, app.listen_socket_mode);
+        info!("Listening on {}", path.display());
+        socket_file = Some(path);
+        listener
+    } else {
+        let ip_addr = app
+            .listen_ip
+            .parse()
+            .expect("Unable to parse listen address");
+        let addr = SocketAddr::new(ip_addr, app.listen_port);
+        let listener = TcpListener::bind(addr)
+            .await
+            .unwrap_or_else(|err| panic!("Unable to bind to {}: {}", addr, err));
+        info!("Listening on {}", addr);
+        Listener::Tcp(listener)
     };
+    if tls.is_some() {
+        info!("Serving HTTPS using the configured certificate");
+    }
+
+    metrics::init();
+    if let Some(addr) = app.metrics_listen {
+        metrics::spawn_server(addr);
+    }
 
     #[cfg(unix)]
     sd_notify::notify(true, &[sd_notify::NotifyState::Ready])
         .expect("Failed to signal ready to the service manager");
 
-    let make_service = make_service_fn(|stream| {
+    let make_service = make_service_fn(|conn: &Connection| {
         let app = ConfigRc::clone(&app);
-        future::ok::<_, BoxError>(Service::new(app, stream))
+        future::ok::<_, BoxError>(Service::new(app, conn.remote_addr()))
     });
-    builder.serve(make_service).await.expect("Server error");
+    let (draining_tx, draining_rx) = oneshot::channel();
+    let connections = ConnectionTasks::default();
+    let server = Server::builder(accept_connections(listener, tls))
+        .executor(connections.clone())
+        .serve(make_service)
+        .with_graceful_shutdown(async move {
+            wait_for_shutdown_signal().await;
+            info!("Shutting down, waiting for requests to finish");
+            #[cfg(unix)]
+            let _ = sd_notify::notify(false, &[sd_notify::NotifyState::Stopping]);
+            let _ = draining_tx.send(());
+        });
+    let shutdown_timeout = app.shutdown_timeout;
+    let drain_timeout = async move {
+        if draining_rx.await.is_ok() {
+            tokio::time::sleep(shutdown_timeout).await;
+        } else {
+            future::pending::<()>().await;
+        }
+    };
+    tokio::select! {
+        res = server => res.expect("Server error"),
+        () = drain_timeout => warn!("Timed out waiting for requests to finish"),
+    }
+
+    // Abort remaining requests, which would otherwise fail once agents are stopped.
+    connections.abort_all();
+    stop_agents(&app).await;
+    if let Some(path) = socket_file {
+        if let Err(err) = std::fs::remove_file(path) {
+            warn!("Could not remove {}: {}", path.display(), err);
+        }
+    }
+    info!("Shutdown complete");
+}
+
+/// Wait for a signal that asks us to shut down.
+#[cfg(unix)]
+async fn wait_for_shutdown_signal() {
+    let mut terminate =
+        signal(SignalKind::terminate()).expect("Failed to install the SIGTERM handler");
+    let mut interrupt =
+        signal(SignalKind::interrupt()).expect("Failed to install the SIGINT handler");
+    tokio::select! {
+        _ = terminate.recv() => {},
+        _ = interrupt.recv() => {},
+    }
 }
 
-async fn import_key(builder: ConfigBuilder, file: &Path) {
+#[cfg(not(unix))]
+async fn wait_for_shutdown_signal() {
+    tokio::signal::ctrl_c()
+        .await
+        .expect("Failed to install the Ctrl-C handler");
+}
+
+/// Stop all agents, in an order that allows them to finish outstanding work.
+///
+/// The key manager goes first, so it no longer starts key rotations in the store. The mailer goes
+/// last, after the store, so mails that are still queued are sent. Finally, remaining spans are
+/// exported, if tracing is enabled.
+async fn stop_agents(app: &Config) {
+    app.key_manager.stop().await;
+    app.store.stop().await;
+    app.mailer.stop().await;
+    if let Some(ref tracer) = app.tracer {
+        tracer.stop().await;
+    }
+}
+
+/// Take a Unix socket passed in by the service manager, if any.
+///
+/// If the service manager passed in some other kind of socket, it is left in place.
+#[cfg(unix)]
+fn take_unix_listener(listenfd: &mut listenfd::ListenFd) -> Option<Listener> {
+    let unix_listener = listenfd.take_unix_listener(0).ok()??;
+    unix_listener
+        .set_nonblocking(true)
+        .expect("Socket activation failed");
+    let listener = UnixListener::from_std(unix_listener).expect("Socket activation failed");
+    Some(Listener::Unix(listener))
+}
+
+#[cfg(not(unix))]
+fn take_unix_listener(_listenfd: &mut listenfd::ListenFd) -> Option<Listener> {
+    None
+}
+
+/// Bind the Unix socket configured with `listen_socket`.
+#[cfg(unix)]
+fn bind_unix(path: &Path, mode: Option<u32>) -> Listener {
+    Listener::bind_unix(path, mode)
+        .unwrap_or_else(|err| panic!("Unable to bind to {}: {}", path.display(), err))
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_path: &Path, _mode: Option<u32>) -> Listener {
+    panic!("Unix sockets are not supported on this platform");
+}
+
+/// Reload the TLS certificate and key whenever we receive `SIGHUP`.
+#[cfg(unix)]
+fn reload_tls_on_sighup(tls: ReloadableTlsAcceptor) {
+    let mut hangup = signal(SignalKind::hangup()).expect("Failed to install the SIGHUP handler");
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            match tls.reload() {
+                Ok(()) => info!("Reloaded the TLS certificate and key"),
+                Err(err) => error!("Failed to reload the TLS certificate and key: {}", err),
+            }
+        }
+    });
+}
+
+async fn import_key(builder: ConfigBuilder, file: &Path, passphrase_file: Option<&Path>) {
+    // Memory storage lives in the running broker, so there is nothing to import into.
+    if builder.memory_storage {
+        panic!("Keys cannot be imported into memory_storage, only a running broker can change it");
+    }
+
     let contents = if file == Path::new("-") {
         let mut buf = Vec::new();
         if let Err(err) = std::io::stdin().read_to_end(&mut buf) {
@@ -166,6 +401,24 @@ async fn import_key(builder: ConfigBuilder, file: &Path) {
         Err(err) => panic!("Key file '{}' is not valid UTF-8: {}", file.display(), err),
     };
 
+    if key_backup::is_backup(&contents) {
+        let passphrase = passphrase_file.map(admin::read_passphrase);
+        let key_sets = key_backup::decode(&contents, passphrase.as_deref()).unwrap_or_else(|err| {
+            panic!("Could not read key backup '{}': {}", file.display(), err)
+        });
+        let store = builder
+            .into_store()
+            .await
+            .unwrap_or_else(|err| panic!("failed to build configuration: {}", err));
+        for key_set in key_sets {
+            let signing_alg = key_set.signing_alg;
+            store.send(ImportKeySet(key_set)).await;
+            eprintln!("Successfully imported {} keys", signing_alg);
+        }
+        store.stop().await;
+        return;
+    }
+
     let key = match pem::parse_key_pairs(Cursor::new(contents.as_bytes())) {
         Ok(keys) if keys.len() == 1 => keys.into_iter().next().unwrap(),
         Ok(_) => panic!(
@@ -202,7 +455,5 @@ async fn import_key(builder: ConfigBuilder, file: &Path) {
         .await;
     eprintln!("Successfully imported {} key", signing_alg);
 
-    // TODO: This is a little hacky, but we don't have code to shutdown gracefully.
-    // (Currently, if a Redis store is simply dropped, the pubsub task panics.)
-    std::process::exit(0);
+    store.stop().await;
 }
@@ -0,0 +1,152 @@
+//! Prometheus metrics.
+//!
+//! Metrics are collected in a global registry, and can be served in the Prometheus text format on
+//! a separate listener, configured using `metrics_listen`. A separate listener makes it easy to
+//! keep metrics private, while the broker itself is public.
+
+use crate::web::HandlerResult;
+use futures_util::future;
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use prometheus::{
+    core::Collector, Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, Opts, Registry,
+    TextEncoder,
+};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+
+lazy_static::lazy_static! {
+    static ref REGISTRY: Registry = Registry::new_custom(Some("portier".to_owned()), None)
+        .expect("Could not create the metrics registry");
+
+    /// Authentication requests, by outcome. The outcome is `ok`, or the kind of error.
+    pub static ref AUTH_REQUESTS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("auth_requests_total", "Authentication requests, by outcome."),
+        &["outcome"],
+    ));
+
+    /// Authentications handed to a bridge, by bridge.
+    pub static ref BRIDGE_STARTED: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("bridge_started_total", "Authentications handed to a bridge, by bridge."),
+        &["bridge"],
+    ));
+
+    /// Authentications completed by a bridge, by bridge.
+    pub static ref BRIDGE_COMPLETED: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("bridge_completed_total", "Authentications completed, by bridge."),
+        &["bridge"],
+    ));
+
+    /// Duration of webfinger queries.
+    pub static ref WEBFINGER_DURATION: Histogram = register(Histogram::with_opts(
+        HistogramOpts::new("webfinger_duration_seconds", "Duration of webfinger queries."),
+    ));
+
+    /// Discovery steps that timed out, causing a fallback to the next provider or email loop.
+    pub static ref DISCOVERY_TIMEOUTS: IntCounter = register(IntCounter::new(
+        "discovery_timeouts_total",
+        "Discovery attempts that timed out.",
+    ));
+
+    /// Errors returned to the client, by kind.
+    pub static ref ERRORS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("errors_total", "Errors returned to the client, by kind."),
+        &["kind"],
+    ));
+
+    /// Rate limit rejections, by the ID of the limit that was hit.
+    pub static ref RATE_LIMIT_REJECTIONS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("rate_limit_rejections_total", "Rate limit rejections, by limit."),
+        &["limit"],
+    ));
+
+    /// Cached fetches, by whether the result was found in cache.
+    pub static ref FETCH_CACHE: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("fetch_cache_total", "Cached fetches, by result (hit or miss)."),
+        &["result"],
+    ));
+
+    /// Mails that could not be sent, by mailer.
+    pub static ref MAIL_FAILURES: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("mail_failures_total", "Mails that could not be sent, by mailer."),
+        &["mailer"],
+    ));
+}
+
+/// Register a collector with our registry.
+fn register<T: Collector + Clone + 'static>(collector: prometheus::Result<T>) -> T {
+    let collector = collector.expect("Invalid metric definition");
+    REGISTRY
+        .register(Box::new(collector.clone()))
+        .expect("Could not register metric");
+    collector
+}
+
+/// Initialize all metrics.
+///
+/// Metrics are registered on first use, so this makes sure they are all present in the output
+/// from the start. Labels with a known set of values are also initialized to zero.
+pub fn init() {
+    for bridge in &["email", "oidc"] {
+        BRIDGE_STARTED.with_label_values(&[bridge]);
+        BRIDGE_COMPLETED.with_label_values(&[bridge]);
+    }
+    for result in &["hit", "miss"] {
+        FETCH_CACHE.with_label_values(&[result]);
+    }
+    lazy_static::initialize(&AUTH_REQUESTS);
+    lazy_static::initialize(&WEBFINGER_DURATION);
+    lazy_static::initialize(&DISCOVERY_TIMEOUTS);
+    lazy_static::initialize(&ERRORS);
+    lazy_static::initialize(&RATE_LIMIT_REJECTIONS);
+    lazy_static::initialize(&MAIL_FAILURES);
+}
+
+/// Count an authentication request by its outcome.
+pub fn record_auth_outcome(result: &HandlerResult) {
+    let outcome = match *result {
+        Ok(_) => "ok",
+        Err(ref err) => err.kind(),
+    };
+    AUTH_REQUESTS.with_label_values(&[outcome]).inc();
+}
+
+/// Start serving metrics on the given address.
+pub fn spawn_server(addr: SocketAddr) {
+    let server = Server::try_bind(&addr)
+        .unwrap_or_else(|err| panic!("Unable to bind metrics listener to {}: {}", addr, err))
+        .serve(make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|req| future::ok::<_, Infallible>(handle(&req))))
+        }));
+    log::info!("Serving metrics on {}", addr);
+    tokio::spawn(async move {
+        if let Err(err) = server.await {
+            log::error!("Metrics server error: {}", err);
+        }
+    });
+}
+
+/// Handle a request to the metrics listener.
+fn handle(req: &Request<Body>) -> Response<Body> {
+    if req.uri().path() != "/metrics" {
+        let mut res = Response::new(Body::empty());
+        *res.status_mut() = StatusCode::NOT_FOUND;
+        return res;
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buf = Vec::new();
+    encoder
+        .encode(&REGISTRY.gather(), &mut buf)
+        .expect("Could not encode metrics");
+    let mut res = Response::new(Body::from(buf));
+    res.headers_mut().insert(
+        CONTENT_TYPE,
+        encoder
+            .format_type()
+            .parse()
+            .expect("Invalid metrics content type"),
+    );
+    res
+}
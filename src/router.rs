@@ -1,5 +1,5 @@
 use crate::web::{empty_response, Context, HandlerResult};
-use crate::{bridges, handlers};
+use crate::{bridges, handlers, metrics};
 use http::{Method, StatusCode};
 
 /// Route the request, returning a handler
@@ -8,12 +8,21 @@ pub async fn router(ctx: &mut Context) -> HandlerResult {
         // Relying party endpoints
         (&Method::GET, "/.well-known/openid-configuration") => handlers::auth::discovery(ctx).await,
         (&Method::GET, "/keys.json") => handlers::auth::key_set(ctx).await,
-        (&Method::GET, "/auth") | (&Method::POST, "/auth") => handlers::auth::auth(ctx).await,
+        (&Method::GET, "/auth") | (&Method::POST, "/auth") => {
+            let result = handlers::auth::auth(ctx).await;
+            metrics::record_auth_outcome(&result);
+            result
+        }
         (&Method::POST, "/normalize") => handlers::normalize::normalize(ctx).await,
+        (&Method::POST, "/token") => handlers::token::token(ctx).await,
 
         // OpenID Connect endpoints
-        // For providers that don't support `response_mode=form_post`, we capture the fragment
+        // For providers that don't support `response_mode=form_post`, the code flow returns query
+        // parameters, which we handle directly. For the implicit flow, we capture the fragment
         // parameters in javascript and emulate the POST request.
+        (&Method::GET, "/callback") if ctx.query_params().contains_key("state") => {
+            bridges::oidc::callback(ctx).await
+        }
         (&Method::GET, "/callback") => handlers::rewrite_to_post::rewrite_to_post(ctx).await,
         (&Method::POST, "/callback") => bridges::oidc::callback(ctx).await,
 
@@ -26,6 +35,9 @@ pub async fn router(ctx: &mut Context) -> HandlerResult {
         // Misc endpoints
         (&Method::GET, "/") => handlers::pages::index(ctx).await,
         (&Method::GET, "/ver.txt") => handlers::pages::version(ctx).await,
+        (&Method::GET, "/healthz") | (&Method::GET, "/readyz") => {
+            handlers::health::health(ctx).await
+        }
 
         // Lastly, fall back to trying to serve static files out of ./res/
         (&Method::GET, _) | (&Method::HEAD, _) => handlers::pages::static_(ctx).await,
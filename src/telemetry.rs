@@ -0,0 +1,388 @@
+//! Tracing of requests, exported using the OTLP protocol.
+//!
+//! When enabled, each HTTP request becomes a trace, and parts of the login flow run in child spans.
+//! Finished spans are collected by the `SpanExporter` agent, which periodically writes them to a
+//! file and/or posts them to an OTLP/HTTP collector, using the JSON encoding.
+//!
+//! The current span is tracked in a task-local, so spans only nest within a single task, or tasks
+//! started with `spawn`. Work done inside agents is therefore not traced itself, but call sites
+//! wrap messages in spans.
+
+use crate::utils::agent::{Addr, Agent, AgentStarted, AgentStopping, Context, Handler, Message};
+use crate::utils::logger::REQUEST_ID;
+use crate::utils::{unix_duration, SecureRandom};
+use http::Request;
+use hyper::client::{Client, HttpConnector};
+use hyper::Body;
+use hyper_tls::HttpsConnector;
+use serde_json::{json, Value};
+use std::fs::OpenOptions;
+use std::future::Future;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use url::Url;
+
+/// Number of buffered spans that causes an immediate flush.
+const MAX_BUFFERED_SPANS: usize = 512;
+
+/// Interval at which buffered spans are flushed.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+tokio::task_local! {
+    /// The span the current task is running in.
+    static CURRENT_SPAN: Arc<ActiveSpan>;
+}
+
+/// Handle used to start traces.
+#[derive(Clone)]
+pub struct Tracer {
+    exporter: Addr<SpanExporter>,
+    rng: SecureRandom,
+}
+
+impl Tracer {
+    pub fn new(exporter: Addr<SpanExporter>, rng: SecureRandom) -> Self {
+        Tracer { exporter, rng }
+    }
+
+    /// Stop the exporter, flushing any remaining spans.
+    pub async fn stop(&self) {
+        self.exporter.stop().await;
+    }
+}
+
+/// A span that has started, but not yet finished.
+struct ActiveSpan {
+    tracer: Tracer,
+    trace_id: Vec<u8>,
+    span_id: Vec<u8>,
+    parent_span_id: Option<Vec<u8>>,
+    name: &'static str,
+    start: Duration,
+    attributes: Mutex<Vec<(&'static str, Value)>>,
+}
+
+/// A finished span, ready for export.
+pub struct FinishedSpan {
+    trace_id: Vec<u8>,
+    span_id: Vec<u8>,
+    parent_span_id: Option<Vec<u8>>,
+    name: &'static str,
+    start: Duration,
+    end: Duration,
+    attributes: Vec<(&'static str, Value)>,
+}
+
+impl FinishedSpan {
+    /// Encode the span as an OTLP JSON object.
+    fn to_json(&self) -> Value {
+        let attributes = self
+            .attributes
+            .iter()
+            .map(|(key, value)| json!({ "key": key, "value": any_value(value) }))
+            .collect::<Vec<_>>();
+        let mut span = json!({
+            "traceId": hex(&self.trace_id),
+            "spanId": hex(&self.span_id),
+            "name": self.name,
+            // SPAN_KIND_SERVER for the root span, SPAN_KIND_INTERNAL otherwise.
+            "kind": if self.parent_span_id.is_none() { 2 } else { 1 },
+            "startTimeUnixNano": self.start.as_nanos().to_string(),
+            "endTimeUnixNano": self.end.as_nanos().to_string(),
+            "attributes": attributes,
+        });
+        if let Some(ref parent_span_id) = self.parent_span_id {
+            span["parentSpanId"] = hex(parent_span_id).into();
+        }
+        span
+    }
+}
+
+/// Builder for a span, returned by `root_span` and `span`.
+pub struct SpanBuilder {
+    parent: Option<Arc<ActiveSpan>>,
+    tracer: Option<Tracer>,
+    name: &'static str,
+    attributes: Vec<(&'static str, Value)>,
+}
+
+impl SpanBuilder {
+    /// Add an attribute to the span.
+    pub fn attr(mut self, key: &'static str, value: impl Into<Value>) -> Self {
+        if self.tracer.is_some() {
+            self.attributes.push((key, value.into()));
+        }
+        self
+    }
+
+    /// Run a future inside the span.
+    ///
+    /// The span ends when the future completes. If the future is dropped before that, for example
+    /// because of a timeout, the span still ends and is marked as cancelled.
+    pub async fn run<F: Future>(self, fut: F) -> F::Output {
+        let tracer = match self.tracer {
+            Some(tracer) => tracer,
+            None => return fut.await,
+        };
+        let (trace_id, parent_span_id) = match self.parent {
+            Some(ref parent) => (parent.trace_id.clone(), Some(parent.span_id.clone())),
+            None => (tracer.rng.generate(16), None),
+        };
+        let span = Arc::new(ActiveSpan {
+            span_id: tracer.rng.generate(8),
+            tracer,
+            trace_id,
+            parent_span_id,
+            name: self.name,
+            start: unix_duration(),
+            attributes: Mutex::new(self.attributes),
+        });
+        let mut guard = SpanGuard {
+            span: span.clone(),
+            completed: false,
+        };
+        let output = CURRENT_SPAN.scope(span, fut).await;
+        guard.completed = true;
+        output
+    }
+}
+
+/// Ends a span when dropped.
+struct SpanGuard {
+    span: Arc<ActiveSpan>,
+    completed: bool,
+}
+
+impl Drop for SpanGuard {
+    fn drop(&mut self) {
+        let span = &self.span;
+        let mut attributes = std::mem::take(&mut *span.attributes.lock().unwrap());
+        if !self.completed {
+            attributes.push(("cancelled", true.into()));
+        }
+        span.tracer.exporter.send(ExportSpan(FinishedSpan {
+            trace_id: span.trace_id.clone(),
+            span_id: span.span_id.clone(),
+            parent_span_id: span.parent_span_id.clone(),
+            name: span.name,
+            start: span.start,
+            end: unix_duration(),
+            attributes,
+        }));
+    }
+}
+
+/// Start a new trace, if tracing is enabled.
+pub fn root_span(tracer: Option<&Tracer>, name: &'static str) -> SpanBuilder {
+    SpanBuilder {
+        parent: None,
+        tracer: tracer.cloned(),
+        name,
+        attributes: vec![],
+    }
+}
+
+/// Start a child span of the current span.
+///
+/// If the current task is not running in a span, this does nothing.
+pub fn span(name: &'static str) -> SpanBuilder {
+    let parent = CURRENT_SPAN.try_with(Arc::clone).ok();
+    let tracer = parent.as_ref().map(|parent| parent.tracer.clone());
+    SpanBuilder {
+        parent,
+        tracer,
+        name,
+        attributes: vec![],
+    }
+}
+
+/// Set an attribute on the current span, if any.
+pub fn set_attribute(key: &'static str, value: impl Into<Value>) {
+    let _ = CURRENT_SPAN.try_with(|span| {
+        span.attributes.lock().unwrap().push((key, value.into()));
+    });
+}
+
+/// Spawn a task that continues in the current span and request.
+///
+/// Unlike `tokio::spawn`, this carries over the span and request ID task-locals, so that spans
+/// and log lines from the task are attributed to the request that started it.
+pub fn spawn<F>(fut: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let span = CURRENT_SPAN.try_with(Arc::clone).ok();
+    let request_id = REQUEST_ID.try_with(Clone::clone).ok();
+    tokio::spawn(async move {
+        let fut = async move {
+            match span {
+                Some(span) => CURRENT_SPAN.scope(span, fut).await,
+                None => fut.await,
+            }
+        };
+        match request_id {
+            Some(request_id) => REQUEST_ID.scope(request_id, fut).await,
+            None => fut.await,
+        }
+    })
+}
+
+/// Encode bytes as lowercase hex, which is how OTLP JSON encodes IDs.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Encode an attribute value as an OTLP `AnyValue`.
+fn any_value(value: &Value) -> Value {
+    match *value {
+        Value::Bool(b) => json!({ "boolValue": b }),
+        Value::Number(ref n) if n.is_i64() || n.is_u64() => json!({ "intValue": n.to_string() }),
+        Value::Number(ref n) => json!({ "doubleValue": n }),
+        Value::String(ref s) => json!({ "stringValue": s }),
+        ref other => json!({ "stringValue": other.to_string() }),
+    }
+}
+
+/// Message containing a finished span to export.
+pub struct ExportSpan(pub FinishedSpan);
+impl Message for ExportSpan {
+    type Reply = ();
+}
+
+/// Message sent at an interval to flush buffered spans.
+struct Flush;
+impl Message for Flush {
+    type Reply = ();
+}
+
+/// Agent that buffers finished spans and exports them.
+pub struct SpanExporter {
+    /// File to append OTLP JSON lines to.
+    file: Option<PathBuf>,
+    /// URL of an OTLP/HTTP collector traces endpoint.
+    otlp_url: Option<Url>,
+    /// Client used to post to the collector.
+    client: Client<HttpsConnector<HttpConnector>>,
+    /// Spans waiting to be exported.
+    buffer: Vec<FinishedSpan>,
+    /// Handle of the flush task.
+    flush_task: Option<JoinHandle<()>>,
+}
+
+impl SpanExporter {
+    pub fn new(file: Option<PathBuf>, otlp_url: Option<Url>) -> Self {
+        if let Some(ref file) = file {
+            log::info!("Writing traces to {}", file.display());
+        }
+        if let Some(ref otlp_url) = otlp_url {
+            log::info!("Exporting traces to {}", otlp_url);
+        }
+        SpanExporter {
+            file,
+            otlp_url,
+            client: Client::builder().build(HttpsConnector::new()),
+            buffer: vec![],
+            flush_task: None,
+        }
+    }
+
+    /// Export all buffered spans.
+    ///
+    /// Writing to the file happens immediately. The returned future posts to the collector.
+    fn flush(&mut self) -> impl Future<Output = ()> {
+        let post = if self.buffer.is_empty() {
+            None
+        } else {
+            let spans = self
+                .buffer
+                .drain(..)
+                .map(|span| span.to_json())
+                .collect::<Vec<_>>();
+            let payload = json!({
+                "resourceSpans": [{
+                    "resource": {
+                        "attributes": [
+                            { "key": "service.name", "value": { "stringValue": "portier-broker" } },
+                            { "key": "service.version", "value": { "stringValue": env!("CARGO_PKG_VERSION") } },
+                        ],
+                    },
+                    "scopeSpans": [{
+                        "scope": { "name": "portier-broker" },
+                        "spans": spans,
+                    }],
+                }],
+            })
+            .to_string();
+
+            if let Some(ref path) = self.file {
+                let result = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .and_then(|mut file| writeln!(file, "{}", payload));
+                if let Err(err) = result {
+                    log::error!("Could not write traces to {}: {}", path.display(), err);
+                }
+            }
+
+            self.otlp_url.as_ref().map(|url| {
+                let request = Request::post(url.as_str())
+                    .header(hyper::header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(payload))
+                    .expect("could not build OTLP request");
+                self.client.request(request)
+            })
+        };
+        async move {
+            if let Some(post) = post {
+                match post.await {
+                    Ok(res) if res.status().is_success() => {}
+                    Ok(res) => log::error!("OTLP collector returned status {}", res.status()),
+                    Err(err) => log::error!("Could not export traces: {}", err),
+                }
+            }
+        }
+    }
+}
+
+impl Agent for SpanExporter {
+    fn started(&mut self, cx: Context<Self, AgentStarted>) {
+        let addr = cx.addr().clone();
+        self.flush_task = Some(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+            loop {
+                interval.tick().await;
+                addr.send(Flush).await;
+            }
+        }));
+        cx.reply(());
+    }
+
+    fn stopping(&mut self, cx: Context<Self, AgentStopping>) {
+        if let Some(flush_task) = self.flush_task.take() {
+            flush_task.abort();
+        }
+        cx.reply_later(self.flush());
+    }
+}
+
+impl Handler<ExportSpan> for SpanExporter {
+    fn handle(&mut self, message: ExportSpan, cx: Context<Self, ExportSpan>) {
+        self.buffer.push(message.0);
+        if self.buffer.len() >= MAX_BUFFERED_SPANS {
+            cx.reply_later(self.flush());
+        } else {
+            cx.reply(());
+        }
+    }
+}
+
+impl Handler<Flush> for SpanExporter {
+    fn handle(&mut self, _message: Flush, cx: Context<Self, Flush>) {
+        cx.reply_later(self.flush());
+    }
+}
@@ -11,6 +11,9 @@
 //!
 //! Messages are defined as types that implement the `Message` trait. Agents process these in
 //! implementations of the `Handler<M>` trait.
+//!
+//! An agent runs until `Addr::stop` is called. Messages sent before that point are still processed,
+//! after which the `Agent::stopping` method is called and the message loop ends.
 
 use std::any::type_name;
 use std::future::Future;
@@ -30,6 +33,12 @@ impl Message for AgentStarted {
     type Reply = ();
 }
 
+/// A message used for the `Agent::stopping` context.
+pub struct AgentStopping;
+impl Message for AgentStopping {
+    type Reply = ();
+}
+
 /// Context passed to handlers, used to send a reply.
 ///
 /// The agent must call one of the reply methods, which consumes the context. The context may not
@@ -101,8 +110,16 @@ impl<M: Message> Future for ReplyFuture<M> {
 /// Closure via which messages are received by the agent message loop.
 ///
 /// These closures can simply be called to invoke the correct message handler, whenever the message
-/// loop is ready to do so.
-pub type DispatchFn<A> = Box<dyn FnOnce(&mut A) + Send + 'static>;
+/// loop is ready to do so. The return value indicates whether the loop should continue.
+pub type DispatchFn<A> = Box<dyn FnOnce(&mut A) -> LoopControl + Send + 'static>;
+
+/// Returned by a `DispatchFn` to control the agent message loop.
+pub enum LoopControl {
+    /// Continue processing messages.
+    Continue,
+    /// Stop the agent.
+    Break,
+}
 
 /// A trait for types that represent agents.
 ///
@@ -111,15 +128,17 @@ pub trait Agent: Send + Sized + 'static {
     /// Spawn the message loop.
     ///
     /// The default implementation spawns a Tokio task that processes messages from the receiver in
-    /// an infinite loop. Each message is wrapped in a `block_in_place` to allow handlers to do
-    /// synchronous work while holding a mutable reference to the agent.
+    /// a loop, until the agent is stopped. Each message is wrapped in a `block_in_place` to allow
+    /// handlers to do synchronous work while holding a mutable reference to the agent.
     fn spawn_loop(mut self, mut rx: mpsc::Receiver<DispatchFn<Self>>) {
         tokio::spawn(async move {
             while let Some(dispatch) = rx.recv().await {
-                tokio::task::block_in_place(|| {
-                    dispatch(&mut self);
-                });
+                let control = tokio::task::block_in_place(|| dispatch(&mut self));
+                if let LoopControl::Break = control {
+                    break;
+                }
             }
+            log::trace!("Stopped agent {:?}", type_name::<Self>());
         });
     }
 
@@ -131,6 +150,15 @@ pub trait Agent: Send + Sized + 'static {
     fn started(&mut self, cx: Context<Self, AgentStarted>) {
         cx.reply(());
     }
+
+    /// Called once when the agent is stopped.
+    ///
+    /// Agents can implement this to stop async tasks they started, or to flush state. Like
+    /// `started`, the implementation works like a regular message handler, but no further messages
+    /// are processed after it returns. The agent is dropped afterwards.
+    fn stopping(&mut self, cx: Context<Self, AgentStopping>) {
+        cx.reply(());
+    }
 }
 
 /// Start the agent.
@@ -145,6 +173,7 @@ pub async fn spawn_agent<A: Agent>(agent: A) -> Addr<A> {
     tokio::spawn(async move {
         let send_fut = tx.send(Box::new(move |agent: &mut A| {
             agent.started(cx);
+            LoopControl::Continue
         }));
         if send_fut.await.is_err() {
             panic!("agent stopped before startup completed");
@@ -200,10 +229,30 @@ impl<A> Addr<A> {
         tokio::spawn(async move {
             let send_fut = tx.send(Box::new(move |agent: &mut A| {
                 agent.handle(message, cx);
+                LoopControl::Continue
             }));
-            if send_fut.await.is_err() {
-                panic!("tried to send message to stopped agent");
-            }
+            // If the agent has stopped, the context is dropped, and awaiting the reply panics.
+            let _ = send_fut.await;
+        });
+        reply_fut
+    }
+}
+
+impl<A: Agent> Addr<A> {
+    /// Stops the agent.
+    ///
+    /// Messages sent before this call are still processed. The returned future resolves once the
+    /// `Agent::stopping` method has sent its reply. This may only be called once.
+    pub fn stop(&self) -> ReplyFuture<AgentStopping> {
+        log::trace!("Stopping agent {:?}", type_name::<A>());
+        let (cx, reply_fut) = Context::new(self);
+        let tx = self.tx.clone();
+        tokio::spawn(async move {
+            let send_fut = tx.send(Box::new(move |agent: &mut A| {
+                agent.stopping(cx);
+                LoopControl::Break
+            }));
+            let _ = send_fut.await;
         });
         reply_fut
     }
@@ -231,3 +280,18 @@ where
         Addr::<A>::send(self, message)
     }
 }
+
+/// Trait implemented by `Addr` that allows trait objects to stop the agent.
+///
+/// Combined sender traits such as `StoreSender` include this, so the agent behind them can be
+/// stopped during shutdown.
+pub trait Stopper: Send + Sync {
+    /// Stops the agent. See `Addr::stop`.
+    fn stop(&self) -> ReplyFuture<AgentStopping>;
+}
+
+impl<A: Agent> Stopper for Addr<A> {
+    fn stop(&self) -> ReplyFuture<AgentStopping> {
+        Addr::<A>::stop(self)
+    }
+}
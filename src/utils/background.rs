@@ -0,0 +1,69 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Tracks work that may continue in the background after a request gives up on it.
+///
+/// Only one task runs per key, and the total number of tasks is limited.
+pub struct BackgroundTasks {
+    running: Arc<Mutex<HashSet<String>>>,
+    permits: Arc<Semaphore>,
+}
+
+impl BackgroundTasks {
+    pub fn new(limit: usize) -> Self {
+        BackgroundTasks {
+            running: Arc::new(Mutex::new(HashSet::new())),
+            permits: Arc::new(Semaphore::new(limit)),
+        }
+    }
+
+    /// Claim a slot for a task with the given key.
+    ///
+    /// Returns `None` if a task for the key is already running, or if the limit is reached. The
+    /// slot is released when the returned guard is dropped.
+    pub fn try_start(&self, key: &str) -> Option<BackgroundGuard> {
+        let mut running = self.running.lock().unwrap();
+        if running.contains(key) {
+            return None;
+        }
+        let permit = Arc::clone(&self.permits).try_acquire_owned().ok()?;
+        running.insert(key.to_owned());
+        Some(BackgroundGuard {
+            key: key.to_owned(),
+            running: Arc::clone(&self.running),
+            _permit: permit,
+        })
+    }
+}
+
+/// A claimed slot in `BackgroundTasks`.
+pub struct BackgroundGuard {
+    key: String,
+    running: Arc<Mutex<HashSet<String>>>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Drop for BackgroundGuard {
+    fn drop(&mut self) {
+        self.running.lock().unwrap().remove(&self.key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BackgroundTasks;
+
+    #[test]
+    fn test_try_start() {
+        let tasks = BackgroundTasks::new(2);
+        let a = tasks.try_start("a").unwrap();
+        assert!(tasks.try_start("a").is_none());
+        let b = tasks.try_start("b").unwrap();
+        assert!(tasks.try_start("c").is_none());
+        drop(a);
+        assert!(tasks.try_start("a").is_some());
+        drop(b);
+        assert!(tasks.try_start("b").is_some());
+    }
+}
@@ -0,0 +1,35 @@
+use std::fs::OpenOptions;
+use std::io::{Result as IoResult, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Write a file only readable by the owner, replacing any existing file atomically.
+///
+/// The data is written to a uniquely named temporary file next to `path`, which is then renamed
+/// over it. This means permissions of an existing file are never reused, and concurrent writers
+/// in this or other processes never write to the same temporary file.
+pub fn write_private_file(path: &Path, data: &[u8]) -> IoResult<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(format!(
+        ".{}-{}.tmp",
+        std::process::id(),
+        TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(&tmp_path)?;
+    let res = file
+        .write_all(data)
+        .and_then(|()| file.sync_all())
+        .and_then(|()| std::fs::rename(&tmp_path, path));
+    if res.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    res
+}
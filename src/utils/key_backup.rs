@@ -0,0 +1,214 @@
+//! Portable backup format for key sets.
+//!
+//! A backup is a JSON document containing key sets exactly as they are stored, including expiry
+//! times, so keys can be moved between stores without invalidating issued tokens. Optionally, the
+//! key sets are encrypted with a passphrase, using PBKDF2-HMAC-SHA256 and AES-256-GCM.
+
+use crate::agents::KeySet;
+use crate::utils::{base64url, SecureRandom};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::pbkdf2;
+use serde::{Deserialize, Serialize};
+use std::num::NonZeroU32;
+use thiserror::Error;
+
+/// Value of the `format` field, used to recognize backups.
+const FORMAT: &str = "portier-broker-keys";
+
+/// Current version of the backup format.
+const VERSION: u32 = 1;
+
+/// PBKDF2 iterations used for new backups.
+const ITERATIONS: u32 = 600_000;
+
+/// Length of the PBKDF2 salt.
+const SALT_LEN: usize = 16;
+
+#[derive(Debug, Error)]
+pub enum KeyBackupError {
+    #[error("invalid key backup: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("invalid key backup: {0}")]
+    Format(String),
+    #[error("unsupported key backup version: {0}")]
+    Version(u32),
+    #[error("the key backup is encrypted, but no passphrase was given")]
+    PassphraseRequired,
+    #[error("could not decrypt the key backup, the passphrase may be incorrect")]
+    Decrypt,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BackupFile {
+    format: String,
+    version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_sets: Option<Vec<KeySet>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encrypted: Option<Encrypted>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Encrypted {
+    iterations: u32,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Whether the input looks like a key backup, as opposed to a PEM file.
+pub fn is_backup(input: &str) -> bool {
+    input.trim_start().starts_with('{')
+}
+
+/// Encode key sets as a backup, encrypting them if a passphrase is given.
+pub fn encode(key_sets: Vec<KeySet>, passphrase: Option<&str>, rng: &SecureRandom) -> String {
+    encode_with_iterations(key_sets, passphrase, rng, ITERATIONS)
+}
+
+fn encode_with_iterations(
+    key_sets: Vec<KeySet>,
+    passphrase: Option<&str>,
+    rng: &SecureRandom,
+    iterations: u32,
+) -> String {
+    let file = match passphrase {
+        Some(passphrase) => {
+            let salt = rng.generate(SALT_LEN);
+            let nonce = rng.generate(NONCE_LEN);
+            let mut data =
+                serde_json::to_vec(&key_sets).expect("Could not encode key sets as JSON");
+            derive_key(passphrase, &salt, iterations)
+                .seal_in_place_append_tag(
+                    Nonce::try_assume_unique_for_key(&nonce).unwrap(),
+                    Aad::from(FORMAT),
+                    &mut data,
+                )
+                .expect("Could not encrypt key backup");
+            BackupFile {
+                format: FORMAT.to_owned(),
+                version: VERSION,
+                key_sets: None,
+                encrypted: Some(Encrypted {
+                    iterations,
+                    salt: base64url::encode(&salt),
+                    nonce: base64url::encode(&nonce),
+                    ciphertext: base64url::encode(&data),
+                }),
+            }
+        }
+        None => BackupFile {
+            format: FORMAT.to_owned(),
+            version: VERSION,
+            key_sets: Some(key_sets),
+            encrypted: None,
+        },
+    };
+    serde_json::to_string_pretty(&file).expect("Could not encode key backup as JSON")
+}
+
+/// Decode a backup, decrypting it with the passphrase if it is encrypted.
+pub fn decode(input: &str, passphrase: Option<&str>) -> Result<Vec<KeySet>, KeyBackupError> {
+    let file: BackupFile = serde_json::from_str(input)?;
+    if file.format != FORMAT {
+        return Err(KeyBackupError::Format(format!(
+            "unknown format '{}'",
+            file.format
+        )));
+    }
+    if file.version != VERSION {
+        return Err(KeyBackupError::Version(file.version));
+    }
+    match (file.key_sets, file.encrypted) {
+        (Some(key_sets), None) => Ok(key_sets),
+        (None, Some(encrypted)) => {
+            let passphrase = passphrase.ok_or(KeyBackupError::PassphraseRequired)?;
+            let salt = base64url::decode(&encrypted.salt).map_err(|_| KeyBackupError::Decrypt)?;
+            let nonce = base64url::decode(&encrypted.nonce).map_err(|_| KeyBackupError::Decrypt)?;
+            let mut data =
+                base64url::decode(&encrypted.ciphertext).map_err(|_| KeyBackupError::Decrypt)?;
+            let nonce =
+                Nonce::try_assume_unique_for_key(&nonce).map_err(|_| KeyBackupError::Decrypt)?;
+            let plain = derive_key(passphrase, &salt, encrypted.iterations)
+                .open_in_place(nonce, Aad::from(FORMAT), &mut data)
+                .map_err(|_| KeyBackupError::Decrypt)?;
+            Ok(serde_json::from_slice(plain)?)
+        }
+        _ => Err(KeyBackupError::Format(
+            "expected either key_sets or encrypted".to_owned(),
+        )),
+    }
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], iterations: u32) -> LessSafeKey {
+    let mut key = [0; 32];
+    let iterations = NonZeroU32::new(iterations.max(1)).unwrap();
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+    LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &key).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agents::Expiring;
+    use crate::crypto::SigningAlgorithm;
+    use ring::rand::SystemRandom;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn key_sets() -> Vec<KeySet> {
+        vec![KeySet {
+            signing_alg: SigningAlgorithm::EdDsa,
+            current: Some(Expiring {
+                value: "current".to_owned(),
+                expires: UNIX_EPOCH + Duration::from_secs(1000),
+            }),
+            next: Some(Expiring {
+                value: "next".to_owned(),
+                expires: UNIX_EPOCH + Duration::from_secs(2000),
+            }),
+            previous: Some("previous".to_owned()),
+        }]
+    }
+
+    fn rng() -> SecureRandom {
+        SecureRandom {
+            generator: SystemRandom::new(),
+        }
+    }
+
+    #[test]
+    fn test_roundtrip_plain() {
+        let output = encode_with_iterations(key_sets(), None, &rng(), 1);
+        assert!(is_backup(&output));
+        let decoded = decode(&output, None).unwrap();
+        assert_eq!(decoded.len(), 1);
+        assert_eq!(decoded[0].current.as_ref().unwrap().value, "current");
+        assert_eq!(
+            decoded[0].next.as_ref().unwrap().expires,
+            UNIX_EPOCH + Duration::from_secs(2000)
+        );
+        assert_eq!(decoded[0].previous.as_deref(), Some("previous"));
+    }
+
+    #[test]
+    fn test_roundtrip_encrypted() {
+        let output = encode_with_iterations(key_sets(), Some("hunter2"), &rng(), 1);
+        assert!(!output.contains("current"));
+        let decoded = decode(&output, Some("hunter2")).unwrap();
+        assert_eq!(decoded[0].current.as_ref().unwrap().value, "current");
+        assert!(matches!(
+            decode(&output, Some("wrong")),
+            Err(KeyBackupError::Decrypt)
+        ));
+        assert!(matches!(
+            decode(&output, None),
+            Err(KeyBackupError::PassphraseRequired)
+        ));
+    }
+}
@@ -0,0 +1,49 @@
+use crate::utils::base64url;
+use ring::hmac;
+
+/// Replaces personal data with an HMAC-SHA256 keyed with a secret from configuration.
+///
+/// Used for email addresses in the audit log, and for values in rate limit keys.
+#[derive(Clone)]
+pub struct KeyedHasher {
+    key: hmac::Key,
+}
+
+impl KeyedHasher {
+    pub fn new(secret: &str) -> Self {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+        KeyedHasher { key }
+    }
+
+    /// Hash a value, encoded as lowercase hex.
+    pub fn hex(&self, value: &str) -> String {
+        hmac::sign(&self.key, value.as_bytes())
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// Hash a value, truncated to 128 bits and encoded as base64url.
+    ///
+    /// This is plenty to avoid collisions, and keeps keys short.
+    pub fn short(&self, value: &str) -> String {
+        let tag = hmac::sign(&self.key, value.as_bytes());
+        base64url::encode(&tag.as_ref()[..16])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KeyedHasher;
+
+    #[test]
+    fn test_hash() {
+        let hasher = KeyedHasher::new("correct horse battery staple");
+        assert_eq!(hasher.hex("a").len(), 64);
+        assert_eq!(hasher.short("a").len(), 22);
+        assert_eq!(hasher.hex("a"), hasher.hex("a"));
+        assert_ne!(hasher.hex("a"), hasher.hex("b"));
+        assert_ne!(hasher.hex("a"), KeyedHasher::new("other secret").hex("a"));
+    }
+}
@@ -0,0 +1,297 @@
+//! Listening sockets for the HTTP server.
+//!
+//! Hyper can bind a plain TCP socket by itself, but to also listen on Unix sockets and terminate
+//! TLS ourselves, we run our own accept loop. Connections are accepted in a separate task, and TLS
+//! handshakes are done in tasks of their own, so a slow client cannot hold up other connections.
+//! Established connections are then handed to Hyper through a channel.
+
+use futures_util::stream;
+use hyper::rt::Executor;
+use hyper::server::accept::{self, Accept};
+use native_tls::{Identity, TlsAcceptor as NativeTlsAcceptor};
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::{Error as IoError, Result as IoResult};
+#[cfg(unix)]
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+use tokio_native_tls::TlsAcceptor;
+
+/// Time allowed for a client to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("could not read '{}': {}", path.display(), err)]
+    Read { path: PathBuf, err: IoError },
+    #[error("could not load certificate and key: {0}")]
+    Identity(#[from] native_tls::Error),
+}
+
+/// Paths to the PEM files used for TLS.
+#[derive(Clone)]
+pub struct TlsFiles {
+    /// File containing the certificate chain.
+    pub cert_file: PathBuf,
+    /// File containing the PKCS #8 private key.
+    pub key_file: PathBuf,
+}
+
+impl TlsFiles {
+    /// Read the files and build a TLS acceptor.
+    fn load(&self) -> Result<TlsAcceptor, TlsError> {
+        let read = |path: &Path| {
+            std::fs::read(path).map_err(|err| TlsError::Read {
+                path: path.to_owned(),
+                err,
+            })
+        };
+        let cert = read(&self.cert_file)?;
+        let key = read(&self.key_file)?;
+        let identity = Identity::from_pkcs8(&cert, &key)?;
+        Ok(NativeTlsAcceptor::new(identity)?.into())
+    }
+}
+
+/// A TLS acceptor that can be reloaded from disk at runtime.
+///
+/// This struct can be cheaply cloned. Reloading only affects new connections.
+#[derive(Clone)]
+pub struct ReloadableTlsAcceptor {
+    files: TlsFiles,
+    current: Arc<RwLock<Arc<TlsAcceptor>>>,
+}
+
+impl ReloadableTlsAcceptor {
+    /// Create an acceptor from the given files.
+    pub fn new(files: TlsFiles) -> Result<Self, TlsError> {
+        let acceptor = files.load()?;
+        Ok(ReloadableTlsAcceptor {
+            files,
+            current: Arc::new(RwLock::new(Arc::new(acceptor))),
+        })
+    }
+
+    /// Reload the certificate and key from disk.
+    ///
+    /// On failure, the acceptor continues to use the previous certificate and key.
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let acceptor = self.files.load()?;
+        *self.current.write().expect("TLS acceptor lock poisoned") = Arc::new(acceptor);
+        Ok(())
+    }
+
+    /// Get the currently active acceptor.
+    fn get(&self) -> Arc<TlsAcceptor> {
+        self.current
+            .read()
+            .expect("TLS acceptor lock poisoned")
+            .clone()
+    }
+}
+
+/// Any stream we can serve HTTP over.
+trait RawStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> RawStream for T {}
+
+/// A listening socket for the HTTP server.
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Bind a Unix socket at the given path, optionally setting permissions on the socket file.
+    ///
+    /// A stale socket file at the path, left behind by a previous run, is removed first.
+    #[cfg(unix)]
+    pub fn bind_unix(path: &Path, mode: Option<u32>) -> IoResult<Listener> {
+        use std::fs::{self, Permissions};
+        use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+        match fs::symlink_metadata(path) {
+            Ok(meta) if meta.file_type().is_socket() => fs::remove_file(path)?,
+            _ => {}
+        }
+        let listener = UnixListener::bind(path)?;
+        if let Some(mode) = mode {
+            fs::set_permissions(path, Permissions::from_mode(mode))?;
+        }
+        Ok(Listener::Unix(listener))
+    }
+
+    /// Accept a new connection.
+    ///
+    /// Connections on a Unix socket have no meaningful remote address, so we report them as
+    /// coming from the loopback address. This way, a reverse proxy on the same host is trusted by
+    /// the default `trusted_proxies` setting.
+    async fn accept(&self) -> IoResult<(Box<dyn RawStream>, SocketAddr)> {
+        match *self {
+            Listener::Tcp(ref listener) => {
+                let (stream, remote_addr) = listener.accept().await?;
+                Ok((Box::new(stream), remote_addr))
+            }
+            #[cfg(unix)]
+            Listener::Unix(ref listener) => {
+                let (stream, _) = listener.accept().await?;
+                let remote_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0);
+                Ok((Box::new(stream), remote_addr))
+            }
+        }
+    }
+}
+
+/// A connection accepted by one of our listeners.
+pub struct Connection {
+    stream: Box<dyn RawStream>,
+    remote_addr: SocketAddr,
+}
+
+impl Connection {
+    /// The address of the remote end of the connection.
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+/// Start accepting connections on a listener.
+///
+/// If an acceptor is given, a TLS handshake is done on every connection first. The result can be
+/// passed to `hyper::Server::builder`.
+pub fn accept_connections(
+    listener: Listener,
+    tls: Option<ReloadableTlsAcceptor>,
+) -> impl Accept<Conn = Connection, Error = IoError> {
+    let (tx, mut rx) = mpsc::channel(32);
+    tokio::spawn(async move {
+        loop {
+            let (stream, remote_addr) = match listener.accept().await {
+                Ok(res) => res,
+                Err(err) => {
+                    // Usually a temporary condition, like running out of file descriptors.
+                    log::error!("Failed to accept a connection: {}", err);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+            let tx = tx.clone();
+            if let Some(ref tls) = tls {
+                let acceptor = tls.get();
+                tokio::spawn(async move {
+                    let handshake = acceptor.accept(stream);
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+                        Ok(Ok(stream)) => {
+                            let stream = Box::new(stream);
+                            let _ = tx
+                                .send(Connection {
+                                    stream,
+                                    remote_addr,
+                                })
+                                .await;
+                        }
+                        Ok(Err(err)) => {
+                            log::debug!("TLS handshake with {} failed: {}", remote_addr, err);
+                        }
+                        Err(_) => {
+                            log::debug!("TLS handshake with {} timed out", remote_addr);
+                        }
+                    }
+                });
+            } else {
+                let conn = Connection {
+                    stream,
+                    remote_addr,
+                };
+                if tx.send(conn).await.is_err() {
+                    // The server was dropped.
+                    break;
+                }
+            }
+        }
+    });
+    accept::from_stream(stream::poll_fn(move |cx| {
+        rx.poll_recv(cx).map(|conn| conn.map(Ok))
+    }))
+}
+
+/// Executor for connection tasks of the HTTP server, which can abort all of them.
+///
+/// Hyper spawns a task for every connection, and these keep running when the server future is
+/// dropped. Once the shutdown timeout expires, we abort the remaining connections before stopping
+/// agents, because requests still in progress would otherwise fail waiting for agent replies.
+#[derive(Clone, Default)]
+pub struct ConnectionTasks {
+    inner: Arc<Mutex<ConnectionTasksInner>>,
+}
+
+#[derive(Default)]
+struct ConnectionTasksInner {
+    next_id: u64,
+    handles: HashMap<u64, AbortHandle>,
+}
+
+impl ConnectionTasks {
+    /// Abort all connection tasks that are still running.
+    pub fn abort_all(&self) {
+        let handles = std::mem::take(&mut self.inner.lock().unwrap().handles);
+        for handle in handles.values() {
+            handle.abort();
+        }
+    }
+}
+
+impl<F> Executor<F> for ConnectionTasks
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    fn execute(&self, fut: F) {
+        // Hold the lock while spawning, so the task can't try to remove itself before it was added.
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        let tasks = Arc::clone(&self.inner);
+        let handle = tokio::spawn(async move {
+            fut.await;
+            tasks.lock().unwrap().handles.remove(&id);
+        });
+        inner.handles.insert(id, handle.abort_handle());
+    }
+}
@@ -1,9 +1,60 @@
 use log::{Level, Metadata, Record};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+
+tokio::task_local! {
+    /// The ID of the request being handled by the current task.
+    ///
+    /// The web service sets this for the duration of each request, and the logger adds it to
+    /// every line logged from within the request task.
+    pub static REQUEST_ID: String;
+}
+
+/// Format of log lines written to stderr.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Free-form text, meant for humans.
+    Text,
+    /// One JSON object per line, meant for log pipelines.
+    Json,
+}
+
+/// Whether to write JSON. Set once after configuration is read.
+static JSON_FORMAT: AtomicBool = AtomicBool::new(false);
 
 pub struct Logger {
     level: Level,
 }
 
+impl Logger {
+    fn log_text(record: &Record, request_id: Option<&str>) {
+        let mut prefix = String::new();
+        if !record.target().starts_with("portier_broker") {
+            prefix.push_str(&format!("[{}] ", record.target()));
+        }
+        if let Some(request_id) = request_id {
+            prefix.push_str(&format!("req={} ", request_id));
+        }
+        eprintln!("{: <6} {}{}", record.level(), prefix, record.args());
+    }
+
+    fn log_json(record: &Record, request_id: Option<&str>) {
+        let mut line = json!({
+            "timestamp": humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            "level": record.level().as_str(),
+            "target": record.target(),
+            "message": record.args().to_string(),
+        });
+        if let Some(request_id) = request_id {
+            line["request_id"] = request_id.into();
+        }
+        eprintln!("{}", line);
+    }
+}
+
 impl log::Log for Logger {
     fn enabled(&self, metadata: &Metadata) -> bool {
         metadata.level() <= self.level
@@ -13,15 +64,11 @@ impl log::Log for Logger {
         if !self.enabled(record.metadata()) {
             return;
         }
-        if record.target().starts_with("portier_broker") {
-            eprintln!("{: <6} {}", record.level(), record.args());
+        let request_id = REQUEST_ID.try_with(Clone::clone).ok();
+        if JSON_FORMAT.load(Ordering::Relaxed) {
+            Self::log_json(record, request_id.as_deref());
         } else {
-            eprintln!(
-                "{: <6} [{}] {}",
-                record.level(),
-                record.target(),
-                record.args()
-            );
+            Self::log_text(record, request_id.as_deref());
         }
     }
 
@@ -37,3 +84,11 @@ pub fn init() {
     log::set_boxed_logger(logger).expect("Failed to initialize logger");
     log::set_max_level(level.to_level_filter());
 }
+
+/// Switch the log format.
+///
+/// The logger is initialized before configuration is read, so lines logged before this call are
+/// always in the text format.
+pub fn set_format(format: LogFormat) {
+    JSON_FORMAT.store(format == LogFormat::Json, Ordering::Relaxed);
+}
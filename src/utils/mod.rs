@@ -1,14 +1,20 @@
 pub mod agent;
+pub mod background;
 pub mod base64url;
 mod delay_queue_task;
+pub mod fs;
 pub mod http;
+pub mod key_backup;
+pub mod keyed_hash;
 pub mod keys;
+pub mod listener;
 pub mod logger;
 pub mod pem;
 mod real_ip;
 #[cfg(feature = "redis")]
 pub mod redis;
 mod rng;
+pub mod storage_encryption;
 mod time;
 mod tlds;
 
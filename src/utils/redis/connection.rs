@@ -0,0 +1,1108 @@
+use native_tls::{Certificate, Identity};
+use redis::{
+    aio::{ConnectionLike, MultiplexedConnection},
+    cmd, from_redis_value, pipe, Arg, AsyncCommands, Cmd, ConnectionAddr, ConnectionInfo,
+    ErrorKind, IntoConnectionInfo, Pipeline, RedisError, RedisFuture, RedisResult, Value,
+};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::future::Future;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::time::{sleep, timeout, Duration, Instant};
+use tokio_native_tls::TlsConnector;
+
+/// Default port of Sentinel servers.
+const SENTINEL_PORT: u16 = 26379;
+
+/// Default port of Redis servers.
+const REDIS_PORT: u16 = 6379;
+
+/// Number of hash slots in a Redis Cluster.
+const CLUSTER_SLOTS: u16 = 16384;
+
+/// Timeout for establishing a connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to keep retrying a request while a failover is in progress.
+const FAILOVER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Delay between retries while a failover is in progress.
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Maximum number of cluster redirects to follow for a single request.
+const MAX_REDIRECTS: usize = 16;
+
+/// Files used for TLS connections to Redis. All are optional.
+#[derive(Clone, Default)]
+pub struct TlsConfig {
+    /// PEM file with CA certificates to trust, in addition to the system defaults.
+    pub ca_file: Option<PathBuf>,
+    /// PEM file with a client certificate chain.
+    pub cert_file: Option<PathBuf>,
+    /// PEM file with the private key of the client certificate, in PKCS #8 format.
+    pub key_file: Option<PathBuf>,
+}
+
+/// A stream to a Redis server, either plain or TLS.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Opens streams to Redis servers.
+#[derive(Clone)]
+pub struct Connector {
+    tls: TlsConnector,
+}
+
+impl Connector {
+    /// Create a connector, loading the TLS files.
+    pub fn new(config: &TlsConfig) -> RedisResult<Self> {
+        let read = |path: &Path| {
+            std::fs::read(path).map_err(|err| {
+                tls_config_error(format!("could not read '{}': {}", path.display(), err))
+            })
+        };
+        let mut builder = native_tls::TlsConnector::builder();
+        if let Some(ref ca_file) = config.ca_file {
+            let pem = read(ca_file)?;
+            for cert in split_pem_certs(&pem) {
+                builder
+                    .add_root_certificate(Certificate::from_pem(cert).map_err(tls_config_error)?);
+            }
+        }
+        if let (Some(ref cert_file), Some(ref key_file)) = (&config.cert_file, &config.key_file) {
+            let identity = Identity::from_pkcs8(&read(cert_file)?, &read(key_file)?)
+                .map_err(tls_config_error)?;
+            builder.identity(identity);
+        }
+        let tls = builder.build().map_err(tls_config_error)?.into();
+        Ok(Connector { tls })
+    }
+
+    /// Open a stream to a server.
+    pub async fn open(&self, info: &ConnectionInfo) -> RedisResult<Box<dyn Stream>> {
+        let stream: Box<dyn Stream> = match *info.addr {
+            ConnectionAddr::Tcp(ref host, port) => {
+                Box::new(TcpStream::connect((host.as_str(), port)).await?)
+            }
+            ConnectionAddr::TcpTls { ref host, port, .. } => {
+                let stream = TcpStream::connect((host.as_str(), port)).await?;
+                let stream = self
+                    .tls
+                    .connect(host, stream)
+                    .await
+                    .map_err(|err| IoError::new(IoErrorKind::Other, err))?;
+                Box::new(stream)
+            }
+            #[cfg(unix)]
+            ConnectionAddr::Unix(ref path) => Box::new(UnixStream::connect(path).await?),
+            #[cfg(not(unix))]
+            ConnectionAddr::Unix(_) => {
+                return Err(RedisError::from((
+                    ErrorKind::InvalidClientConfig,
+                    "Cannot connect to unix sockets on this platform",
+                )))
+            }
+        };
+        Ok(stream)
+    }
+
+    /// Make a regular connection to a single Redis server.
+    async fn connect(&self, info: &ConnectionInfo) -> RedisResult<MultiplexedConnection> {
+        let res = timeout(CONNECT_TIMEOUT, async {
+            let stream = self.open(info).await?;
+            let (conn, driver) = MultiplexedConnection::new(info, stream).await?;
+            tokio::spawn(driver);
+            Ok(conn)
+        })
+        .await;
+        match res {
+            Ok(res) => res,
+            Err(_) => Err(RedisError::from((
+                ErrorKind::IoError,
+                "Timed out connecting to Redis",
+            ))),
+        }
+    }
+}
+
+/// Split a PEM file into individual certificates.
+fn split_pem_certs(pem: &[u8]) -> Vec<&[u8]> {
+    const BEGIN: &[u8] = b"-----BEGIN CERTIFICATE-----";
+    let mut certs = Vec::new();
+    let mut rest = pem;
+    while let Some(start) = find(rest, BEGIN) {
+        let end = find(&rest[start + BEGIN.len()..], BEGIN)
+            .map_or(rest.len(), |idx| start + BEGIN.len() + idx);
+        certs.push(&rest[start..end]);
+        rest = &rest[end..];
+    }
+    certs
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn tls_config_error(err: impl Display) -> RedisError {
+    RedisError::from((
+        ErrorKind::InvalidClientConfig,
+        "Invalid Redis TLS configuration",
+        err.to_string(),
+    ))
+}
+
+/// Where to find Redis, and how to connect.
+#[derive(Clone)]
+pub struct Target {
+    servers: Servers,
+    connector: Connector,
+}
+
+impl Target {
+    /// Parse a Redis URL, and load TLS files.
+    pub fn new(url: &str, tls: &TlsConfig) -> RedisResult<Self> {
+        Ok(Target {
+            servers: Servers::parse(url)?,
+            connector: Connector::new(tls)?,
+        })
+    }
+
+    /// Candidate servers for a pubsub connection, in order of preference.
+    ///
+    /// For a Sentinel setup, this asks Sentinel for the current master.
+    pub async fn pubsub_nodes(&self) -> RedisResult<Vec<ConnectionInfo>> {
+        match self.servers {
+            Servers::Single(ref info) => Ok(vec![info.clone()]),
+            Servers::Sentinel {
+                ref sentinels,
+                ref master_name,
+                ref info,
+            } => Ok(vec![
+                resolve_master(&self.connector, sentinels, master_name, info).await?,
+            ]),
+            // Messages are broadcast across the cluster, so any node will do.
+            Servers::Cluster(ref nodes) => Ok(nodes.clone()),
+        }
+    }
+
+    /// Open a stream to a server.
+    pub async fn open(&self, info: &ConnectionInfo) -> RedisResult<Box<dyn Stream>> {
+        match timeout(CONNECT_TIMEOUT, self.connector.open(info)).await {
+            Ok(res) => res,
+            Err(_) => Err(RedisError::from((
+                ErrorKind::IoError,
+                "Timed out connecting to Redis",
+            ))),
+        }
+    }
+}
+
+/// Redis servers, as parsed from the `redis_url` setting.
+#[derive(Clone, Debug)]
+enum Servers {
+    /// A single Redis server.
+    Single(ConnectionInfo),
+    /// A master that is discovered through Sentinel.
+    Sentinel {
+        /// Addresses of the Sentinel servers.
+        sentinels: Vec<ConnectionInfo>,
+        /// Name of the monitored master.
+        master_name: String,
+        /// Database and credentials used with the master.
+        info: ConnectionInfo,
+    },
+    /// A Redis Cluster, reached through any of the given nodes.
+    Cluster(Vec<ConnectionInfo>),
+}
+
+impl Servers {
+    /// Parse a Redis URL.
+    ///
+    /// Besides regular `redis://` URLs, this accepts `redis+sentinel://` and `redis+cluster://`
+    /// URLs with a comma-separated list of hosts:
+    ///
+    /// - `redis+sentinel://[[user]:password@]host[:port],.../master_name[/db]`
+    /// - `redis+cluster://[[user]:password@]host[:port],...`
+    ///
+    /// Credentials are used for Redis servers, not for Sentinel servers. Each of these schemes
+    /// also has a TLS variant: `rediss://`, `rediss+sentinel://` and `rediss+cluster://`.
+    fn parse(url: &str) -> RedisResult<Self> {
+        for scheme in &["rediss://", "rediss+sentinel://", "rediss+cluster://"] {
+            if let Some(rest) = url.strip_prefix(scheme) {
+                let url = format!("{}{}", scheme.replacen("rediss", "redis", 1), rest);
+                return Ok(Self::parse(&url)?.with_tls());
+            }
+        }
+        if let Some(rest) = url.strip_prefix("redis+sentinel://") {
+            let (auth, hosts, path) = split_multi_host(rest, SENTINEL_PORT)?;
+            let mut path = path.splitn(2, '/');
+            let master_name = match path.next() {
+                Some(master_name) if !master_name.is_empty() => master_name.to_owned(),
+                _ => return Err(invalid_url("missing Sentinel master name")),
+            };
+            let db = path.next().unwrap_or("0");
+            let sentinels = hosts
+                .iter()
+                .map(|host| format!("redis://{}", host).into_connection_info())
+                .collect::<RedisResult<_>>()?;
+            let info = format!("redis://{}localhost/{}", auth, db).into_connection_info()?;
+            Ok(Servers::Sentinel {
+                sentinels,
+                master_name,
+                info,
+            })
+        } else if let Some(rest) = url.strip_prefix("redis+cluster://") {
+            let (auth, hosts, path) = split_multi_host(rest, REDIS_PORT)?;
+            if !path.is_empty() && path != "0" {
+                return Err(invalid_url("Redis Cluster only supports database 0"));
+            }
+            let nodes = hosts
+                .iter()
+                .map(|host| format!("redis://{}{}", auth, host).into_connection_info())
+                .collect::<RedisResult<_>>()?;
+            Ok(Servers::Cluster(nodes))
+        } else if url.starts_with("http://") {
+            Ok(Servers::Single(
+                url.replace("http://", "redis://").into_connection_info()?,
+            ))
+        } else if url.starts_with("redis://") {
+            Ok(Servers::Single(url.into_connection_info()?))
+        } else {
+            Ok(Servers::Single(
+                format!("redis://{}", url).into_connection_info()?,
+            ))
+        }
+    }
+
+    /// Switch all servers to TLS connections.
+    fn with_tls(self) -> Self {
+        match self {
+            Servers::Single(info) => Servers::Single(with_tls(info)),
+            Servers::Sentinel {
+                sentinels,
+                master_name,
+                info,
+            } => Servers::Sentinel {
+                sentinels: sentinels.into_iter().map(with_tls).collect(),
+                master_name,
+                info: with_tls(info),
+            },
+            Servers::Cluster(nodes) => Servers::Cluster(nodes.into_iter().map(with_tls).collect()),
+        }
+    }
+}
+
+/// Switch a TCP server address to TLS.
+fn with_tls(info: ConnectionInfo) -> ConnectionInfo {
+    let addr = match *info.addr {
+        ConnectionAddr::Tcp(host, port) => ConnectionAddr::TcpTls {
+            host,
+            port,
+            insecure: false,
+        },
+        addr => addr,
+    };
+    ConnectionInfo {
+        addr: Box::new(addr),
+        ..info
+    }
+}
+
+/// Build the address of a discovered server, using TLS if the template does.
+fn discovered_addr(template: &ConnectionInfo, host: String, port: u16) -> ConnectionInfo {
+    let addr = match *template.addr {
+        ConnectionAddr::TcpTls { .. } => ConnectionAddr::TcpTls {
+            host,
+            port,
+            insecure: false,
+        },
+        _ => ConnectionAddr::Tcp(host, port),
+    };
+    ConnectionInfo {
+        addr: Box::new(addr),
+        ..template.clone()
+    }
+}
+
+/// Split the part of a multi-host URL after the scheme into credentials, hosts and path.
+///
+/// The credentials are returned including the `@` separator, and hosts always include a port.
+fn split_multi_host(input: &str, default_port: u16) -> RedisResult<(&str, Vec<String>, &str)> {
+    let (authority, path) = match input.find('/') {
+        Some(idx) => (&input[..idx], &input[idx + 1..]),
+        None => (input, ""),
+    };
+    let (auth, hosts) = match authority.rfind('@') {
+        Some(idx) => (&authority[..=idx], &authority[idx + 1..]),
+        None => ("", authority),
+    };
+    let hosts: Vec<String> = hosts
+        .split(',')
+        .filter(|host| !host.is_empty())
+        .map(|host| {
+            // Account for IPv6 addresses in brackets.
+            let host_end = host.rfind(']').unwrap_or(0);
+            if host[host_end..].contains(':') {
+                host.to_owned()
+            } else {
+                format!("{}:{}", host, default_port)
+            }
+        })
+        .collect();
+    if hosts.is_empty() {
+        return Err(invalid_url("no hosts specified"));
+    }
+    Ok((auth, hosts, path.trim_end_matches('/')))
+}
+
+fn invalid_url(detail: &'static str) -> RedisError {
+    RedisError::from((
+        ErrorKind::InvalidClientConfig,
+        "Invalid Redis URL",
+        detail.to_owned(),
+    ))
+}
+
+/// Ask Sentinel for the address of the current master.
+///
+/// Sentinels are tried in order, and the first answer is used.
+async fn resolve_master(
+    connector: &Connector,
+    sentinels: &[ConnectionInfo],
+    master_name: &str,
+    info: &ConnectionInfo,
+) -> RedisResult<ConnectionInfo> {
+    let mut last_err = None;
+    for sentinel in sentinels {
+        let res: RedisResult<Option<(String, u16)>> = async {
+            cmd("SENTINEL")
+                .arg("get-master-addr-by-name")
+                .arg(master_name)
+                .query_async(&mut connector.connect(sentinel).await?)
+                .await
+        }
+        .await;
+        match res {
+            Ok(Some((host, port))) => return Ok(discovered_addr(info, host, port)),
+            Ok(None) => {
+                last_err = Some(RedisError::from((
+                    ErrorKind::ResponseError,
+                    "Sentinel does not know the master",
+                    master_name.to_owned(),
+                )));
+            }
+            Err(err) => {
+                log::warn!("Could not query Sentinel at {:?}: {}", sentinel.addr, err);
+                last_err = Some(err);
+            }
+        }
+    }
+    Err(last_err.expect("No Sentinel servers configured"))
+}
+
+/// Whether an error may be resolved by reconnecting after a failover.
+fn is_failover_error(err: &RedisError) -> bool {
+    err.is_io_error()
+        || matches!(
+            err.kind(),
+            ErrorKind::ReadOnly | ErrorKind::TryAgain | ErrorKind::ClusterDown
+        )
+}
+
+/// Calculate the cluster hash slot of a key.
+///
+/// If the key contains a hash tag, such as `{tag}` in `prefix:{tag}`, only the tag is hashed.
+pub fn hash_slot(key: &[u8]) -> u16 {
+    let key = match key.iter().position(|b| *b == b'{') {
+        Some(open) => match key[open + 1..].iter().position(|b| *b == b'}') {
+            Some(len) if len > 0 => &key[open + 1..open + 1 + len],
+            _ => key,
+        },
+        None => key,
+    };
+    // CRC16-CCITT (XMODEM)
+    let mut crc: u16 = 0;
+    for byte in key {
+        crc ^= u16::from(*byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc % CLUSTER_SLOTS
+}
+
+/// Return the key a command operates on, used to route it in a cluster.
+fn command_key(cmd: &Cmd) -> Option<&[u8]> {
+    let args: Vec<&[u8]> = cmd
+        .args_iter()
+        .filter_map(|arg| match arg {
+            Arg::Simple(arg) => Some(arg),
+            Arg::Cursor => None,
+        })
+        .collect();
+    let name = args.first()?.to_ascii_uppercase();
+    match &name[..] {
+        b"EVAL" | b"EVALSHA" => {
+            let num_keys: usize = std::str::from_utf8(args.get(2)?).ok()?.parse().ok()?;
+            if num_keys > 0 {
+                args.get(3).copied()
+            } else {
+                None
+            }
+        }
+        b"PING" | b"PUBLISH" | b"SCAN" | b"SCRIPT" | b"MULTI" | b"EXEC" | b"DISCARD"
+        | b"ASKING" | b"ROLE" | b"INFO" | b"CLUSTER" => None,
+        _ => args.get(1).copied(),
+    }
+}
+
+/// A request sent through a `ConnectionLike`.
+#[derive(Clone, Copy)]
+enum Request<'a> {
+    Cmd(&'a Cmd),
+    Pipeline(&'a Pipeline, usize, usize),
+}
+
+impl Request<'_> {
+    /// Send the request, optionally preceded by `ASKING`.
+    ///
+    /// The replies of a pipeline are wrapped in a bulk value.
+    async fn send(self, conn: &mut MultiplexedConnection, asking: bool) -> RedisResult<Value> {
+        match (self, asking) {
+            (Request::Cmd(cmd), false) => conn.req_packed_command(cmd).await,
+            (Request::Cmd(cmd), true) => {
+                let mut pipe = pipe();
+                pipe.cmd("ASKING").add_command(cmd.clone());
+                let mut values = conn.req_packed_commands(&pipe, 1, 1).await?;
+                Ok(values.pop().unwrap_or(Value::Nil))
+            }
+            (Request::Pipeline(pipeline, offset, count), _) => Ok(Value::Bulk(
+                conn.req_packed_commands(pipeline, offset, count).await?,
+            )),
+        }
+    }
+
+    /// The hash slot used to route the request in a cluster.
+    fn slot(self) -> Option<u16> {
+        match self {
+            Request::Cmd(cmd) => command_key(cmd).map(hash_slot),
+            Request::Pipeline(pipeline, _, _) => {
+                pipeline.cmd_iter().find_map(command_key).map(hash_slot)
+            }
+        }
+    }
+
+    /// Whether this is a `SCRIPT` command, which must be sent to all cluster nodes.
+    fn is_script(self) -> bool {
+        match self {
+            Request::Cmd(cmd) => matches!(
+                cmd.args_iter().next(),
+                Some(Arg::Simple(name)) if name.eq_ignore_ascii_case(b"SCRIPT")
+            ),
+            Request::Pipeline(..) => false,
+        }
+    }
+}
+
+/// A connection to Redis.
+///
+/// This struct can be cheaply cloned. Depending on the target, it either connects to a single
+/// server, follows the master through Sentinel, or routes commands to the right nodes of a
+/// cluster. A single server connection reconnects after errors, and in the latter two cases,
+/// requests are retried during a failover.
+#[derive(Clone)]
+pub enum Connection {
+    Single(Arc<SingleConnection>),
+    Sentinel(Arc<SentinelConnection>),
+    Cluster(Arc<ClusterConnection>),
+}
+
+impl Connection {
+    /// Connect to Redis.
+    pub async fn connect(target: &Target) -> RedisResult<Self> {
+        let connector = target.connector.clone();
+        match target.servers {
+            Servers::Single(ref info) => {
+                let conn = Arc::new(SingleConnection {
+                    connector,
+                    info: info.clone(),
+                    current: Mutex::new((0, None)),
+                    reconnect_lock: tokio::sync::Mutex::new(()),
+                });
+                conn.conn().await?;
+                Ok(Connection::Single(conn))
+            }
+            Servers::Sentinel {
+                ref sentinels,
+                ref master_name,
+                ref info,
+            } => {
+                let conn = Arc::new(SentinelConnection {
+                    connector,
+                    sentinels: sentinels.clone(),
+                    master_name: master_name.clone(),
+                    info: info.clone(),
+                    current: Mutex::new((0, None)),
+                    reconnect_lock: tokio::sync::Mutex::new(()),
+                });
+                conn.master().await?;
+                Ok(Connection::Sentinel(conn))
+            }
+            Servers::Cluster(ref nodes) => {
+                let conn = Arc::new(ClusterConnection {
+                    connector,
+                    seeds: nodes.clone(),
+                    slots: RwLock::new(Vec::new()),
+                    nodes: Mutex::new(HashMap::new()),
+                    refresh_lock: tokio::sync::Mutex::new(()),
+                });
+                conn.refresh_slots().await?;
+                Ok(Connection::Cluster(conn))
+            }
+        }
+    }
+
+    /// Whether this is a connection to a Redis Cluster.
+    pub fn is_cluster(&self) -> bool {
+        matches!(self, Connection::Cluster(_))
+    }
+
+    /// Collect all keys matching a pattern, using `SCAN`.
+    ///
+    /// In a cluster, this scans all master nodes.
+    pub async fn scan_keys(&mut self, pattern: &str) -> RedisResult<Vec<String>> {
+        let conns = match self {
+            Connection::Cluster(cluster) => cluster.master_conns().await?,
+            Connection::Sentinel(sentinel) => vec![sentinel.master().await?.1],
+            Connection::Single(single) => vec![single.conn().await?.1],
+        };
+        let mut keys = Vec::new();
+        for mut conn in conns {
+            let mut iter = conn.scan_match(pattern).await?;
+            while let Some(key) = iter.next_item().await {
+                keys.push(key);
+            }
+        }
+        Ok(keys)
+    }
+
+    fn request<'a>(&'a mut self, req: Request<'a>) -> RedisFuture<'a, Value> {
+        match self {
+            Connection::Single(single) => Box::pin(single.request(req)),
+            Connection::Sentinel(sentinel) => Box::pin(sentinel.request(req)),
+            Connection::Cluster(cluster) => Box::pin(cluster.request(req)),
+        }
+    }
+}
+
+impl ConnectionLike for Connection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        self.request(Request::Cmd(cmd))
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        let fut = self.request(Request::Pipeline(cmd, offset, count));
+        Box::pin(async move {
+            match fut.await? {
+                Value::Bulk(values) => Ok(values),
+                value => panic!("Unexpected pipeline result: {:?}", value),
+            }
+        })
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            Connection::Single(single) => single.info.db,
+            Connection::Sentinel(sentinel) => sentinel.info.db,
+            Connection::Cluster(_) => 0,
+        }
+    }
+}
+
+/// Retry an operation while a failover is in progress.
+async fn with_failover_retry<F, Fut>(mut f: F) -> RedisResult<Value>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = RedisResult<Value>>,
+{
+    let deadline = Instant::now() + FAILOVER_TIMEOUT;
+    loop {
+        match f().await {
+            Err(err) if is_failover_error(&err) && Instant::now() < deadline => {
+                log::warn!("Redis request failed, retrying: {}", err);
+                sleep(RETRY_DELAY).await;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Connection to a single server, which reconnects after a connection error.
+pub struct SingleConnection {
+    connector: Connector,
+    info: ConnectionInfo,
+    /// Generation number, incremented on every reconnect, and the current connection.
+    current: Mutex<(u64, Option<MultiplexedConnection>)>,
+    /// Held while connecting.
+    reconnect_lock: tokio::sync::Mutex<()>,
+}
+
+impl SingleConnection {
+    /// Get the current connection, connecting if necessary.
+    async fn conn(&self) -> RedisResult<(u64, MultiplexedConnection)> {
+        if let (generation, Some(ref conn)) = *self.current.lock().unwrap() {
+            return Ok((generation, conn.clone()));
+        }
+        let _guard = self.reconnect_lock.lock().await;
+        // Another request may have reconnected while we were waiting.
+        if let (generation, Some(ref conn)) = *self.current.lock().unwrap() {
+            return Ok((generation, conn.clone()));
+        }
+        let conn = self.connector.connect(&self.info).await?;
+        let mut current = self.current.lock().unwrap();
+        if current.0 != 0 {
+            log::info!("Reconnected to Redis at {:?}", self.info.addr);
+        }
+        current.0 += 1;
+        current.1 = Some(conn.clone());
+        Ok((current.0, conn))
+    }
+
+    /// Drop the connection, if it is still the one with the given generation number.
+    fn invalidate(&self, generation: u64) {
+        let mut current = self.current.lock().unwrap();
+        if current.0 == generation {
+            current.1 = None;
+        }
+    }
+
+    async fn request(&self, req: Request<'_>) -> RedisResult<Value> {
+        let mut retried = false;
+        loop {
+            let (generation, mut conn) = self.conn().await?;
+            match req.send(&mut conn, false).await {
+                Err(err) if err.is_io_error() => {
+                    log::warn!("Redis connection failed, reconnecting: {}", err);
+                    self.invalidate(generation);
+                    // The server may have restarted, so retry once on a new connection.
+                    if retried {
+                        return Err(err);
+                    }
+                    retried = true;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+/// Connection to a master that is discovered through Sentinel.
+pub struct SentinelConnection {
+    connector: Connector,
+    sentinels: Vec<ConnectionInfo>,
+    master_name: String,
+    info: ConnectionInfo,
+    /// Generation number, incremented on every reconnect, and the current master connection.
+    current: Mutex<(u64, Option<MultiplexedConnection>)>,
+    /// Held while looking up and connecting to a new master.
+    reconnect_lock: tokio::sync::Mutex<()>,
+}
+
+impl SentinelConnection {
+    /// Get a connection to the current master, connecting if necessary.
+    async fn master(&self) -> RedisResult<(u64, MultiplexedConnection)> {
+        if let (generation, Some(ref conn)) = *self.current.lock().unwrap() {
+            return Ok((generation, conn.clone()));
+        }
+        let _guard = self.reconnect_lock.lock().await;
+        // Another request may have reconnected while we were waiting.
+        if let (generation, Some(ref conn)) = *self.current.lock().unwrap() {
+            return Ok((generation, conn.clone()));
+        }
+        let info = resolve_master(
+            &self.connector,
+            &self.sentinels,
+            &self.master_name,
+            &self.info,
+        )
+        .await?;
+        let mut conn = self.connector.connect(&info).await?;
+        // Sentinel may briefly report an old master during failover, so verify the role.
+        let role: Vec<Value> = cmd("ROLE").query_async(&mut conn).await?;
+        match role.first() {
+            Some(Value::Data(role)) if role == b"master" => {}
+            _ => {
+                return Err(RedisError::from((
+                    ErrorKind::ReadOnly,
+                    "Server reported by Sentinel is not a master",
+                )))
+            }
+        }
+        log::info!("Connected to Redis master at {:?}", info.addr);
+        let mut current = self.current.lock().unwrap();
+        current.0 += 1;
+        current.1 = Some(conn.clone());
+        Ok((current.0, conn))
+    }
+
+    /// Drop the master connection, if it is still the one with the given generation number.
+    fn invalidate(&self, generation: u64) {
+        let mut current = self.current.lock().unwrap();
+        if current.0 == generation {
+            current.1 = None;
+        }
+    }
+
+    async fn request(&self, req: Request<'_>) -> RedisResult<Value> {
+        with_failover_retry(|| async move {
+            let (generation, mut conn) = self.master().await?;
+            let res = req.send(&mut conn, false).await;
+            if let Err(ref err) = res {
+                if is_failover_error(err) {
+                    self.invalidate(generation);
+                }
+            }
+            res
+        })
+        .await
+    }
+}
+
+/// A range of hash slots served by a cluster node.
+struct SlotRange {
+    start: u16,
+    end: u16,
+    /// Node address, as `host:port`.
+    node: String,
+}
+
+/// Connection to a Redis Cluster.
+pub struct ClusterConnection {
+    connector: Connector,
+    /// Nodes from configuration, also used as a template for credentials.
+    seeds: Vec<ConnectionInfo>,
+    /// The current slot map.
+    slots: RwLock<Vec<SlotRange>>,
+    /// Connections to nodes, by address.
+    nodes: Mutex<HashMap<String, MultiplexedConnection>>,
+    /// Held while refreshing the slot map.
+    refresh_lock: tokio::sync::Mutex<()>,
+}
+
+impl ClusterConnection {
+    /// Get a connection to a node, connecting if necessary.
+    async fn node_conn(&self, node: &str) -> RedisResult<MultiplexedConnection> {
+        if let Some(conn) = self.nodes.lock().unwrap().get(node) {
+            return Ok(conn.clone());
+        }
+        let (host, port) = match node.rfind(':') {
+            Some(idx) => (&node[..idx], node[idx + 1..].parse().ok()),
+            None => (node, None),
+        };
+        let port = port.ok_or_else(|| {
+            RedisError::from((
+                ErrorKind::ResponseError,
+                "Invalid cluster node address",
+                node.to_owned(),
+            ))
+        })?;
+        let info = discovered_addr(&self.seeds[0], host.to_owned(), port);
+        let conn = self.connector.connect(&info).await?;
+        self.nodes
+            .lock()
+            .unwrap()
+            .insert(node.to_owned(), conn.clone());
+        Ok(conn)
+    }
+
+    /// Address of the node serving a slot, or any node if no slot is given.
+    fn slot_node(&self, slot: Option<u16>) -> Option<String> {
+        let slots = self.slots.read().unwrap();
+        match slot {
+            Some(slot) => slots
+                .iter()
+                .find(|range| range.start <= slot && slot <= range.end)
+                .map(|range| range.node.clone()),
+            None => slots.first().map(|range| range.node.clone()),
+        }
+    }
+
+    /// Connections to all master nodes.
+    async fn master_conns(&self) -> RedisResult<Vec<MultiplexedConnection>> {
+        let mut nodes: Vec<String> = self
+            .slots
+            .read()
+            .unwrap()
+            .iter()
+            .map(|range| range.node.clone())
+            .collect();
+        nodes.sort();
+        nodes.dedup();
+        let mut conns = Vec::with_capacity(nodes.len());
+        for node in &nodes {
+            conns.push(self.node_conn(node).await?);
+        }
+        Ok(conns)
+    }
+
+    /// Fetch the slot map using `CLUSTER SLOTS`, from the first node that answers.
+    async fn refresh_slots(&self) -> RedisResult<()> {
+        let _guard = self.refresh_lock.lock().await;
+        let mut candidates: Vec<String> = self.nodes.lock().unwrap().keys().cloned().collect();
+        candidates.extend(self.seeds.iter().filter_map(|info| match *info.addr {
+            ConnectionAddr::Tcp(ref host, port) | ConnectionAddr::TcpTls { ref host, port, .. } => {
+                Some(format!("{}:{}", host, port))
+            }
+            ConnectionAddr::Unix(_) => None,
+        }));
+        let mut last_err = None;
+        for node in &candidates {
+            let res = async {
+                let mut conn = self.node_conn(node).await?;
+                let value: Value = cmd("CLUSTER").arg("SLOTS").query_async(&mut conn).await?;
+                let host = &node[..node.rfind(':').unwrap_or(node.len())];
+                parse_slots(&value, host)
+            }
+            .await;
+            match res {
+                Ok(slots) => {
+                    *self.slots.write().unwrap() = slots;
+                    return Ok(());
+                }
+                Err(err) => {
+                    self.nodes.lock().unwrap().remove(node);
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.expect("No Redis Cluster nodes configured"))
+    }
+
+    async fn request(&self, req: Request<'_>) -> RedisResult<Value> {
+        if req.is_script() {
+            // Scripts must be loaded on every node that may run them.
+            let mut value = Value::Nil;
+            for mut conn in self.master_conns().await? {
+                value = req.send(&mut conn, false).await?;
+            }
+            return Ok(value);
+        }
+        let slot = req.slot();
+        with_failover_retry(|| async move {
+            let mut redirect: Option<(String, bool)> = None;
+            for _ in 0..MAX_REDIRECTS {
+                let (node, asking) = match redirect.take() {
+                    Some(redirect) => redirect,
+                    None => match self.slot_node(slot) {
+                        Some(node) => (node, false),
+                        None => {
+                            self.refresh_slots().await?;
+                            continue;
+                        }
+                    },
+                };
+                let res = match self.node_conn(&node).await {
+                    Ok(mut conn) => req.send(&mut conn, asking).await,
+                    Err(err) => Err(err),
+                };
+                match res {
+                    Err(ref err) if err.kind() == ErrorKind::Moved => {
+                        let target = err.redirect_node().map(|(addr, _)| addr.to_owned());
+                        self.refresh_slots().await?;
+                        redirect = target.map(|addr| (addr, false));
+                    }
+                    Err(ref err) if err.kind() == ErrorKind::Ask => {
+                        redirect = err.redirect_node().map(|(addr, _)| (addr.to_owned(), true));
+                    }
+                    Err(ref err) if is_failover_error(err) => {
+                        self.nodes.lock().unwrap().remove(&node);
+                        // The cluster may still be electing a new master, so ignore errors here.
+                        let _ = self.refresh_slots().await;
+                        return res;
+                    }
+                    res => return res,
+                }
+            }
+            Err(RedisError::from((
+                ErrorKind::ClusterDown,
+                "Too many Redis Cluster redirects",
+            )))
+        })
+        .await
+    }
+}
+
+/// Parse the reply of `CLUSTER SLOTS`.
+///
+/// Nodes with an empty host are assumed to be on the host we queried.
+fn parse_slots(value: &Value, default_host: &str) -> RedisResult<Vec<SlotRange>> {
+    let invalid = || {
+        RedisError::from((
+            ErrorKind::ResponseError,
+            "Invalid CLUSTER SLOTS reply from Redis",
+        ))
+    };
+    let entries = match value {
+        Value::Bulk(entries) => entries,
+        _ => return Err(invalid()),
+    };
+    let mut slots = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let parts = match entry {
+            Value::Bulk(parts) if parts.len() >= 3 => parts,
+            _ => return Err(invalid()),
+        };
+        let master = match parts[2] {
+            Value::Bulk(ref master) if master.len() >= 2 => master,
+            _ => return Err(invalid()),
+        };
+        let host: String = from_redis_value(&master[0])?;
+        let port: u16 = from_redis_value(&master[1])?;
+        let host = if host.is_empty() { default_host } else { &host };
+        slots.push(SlotRange {
+            start: from_redis_value(&parts[0])?,
+            end: from_redis_value(&parts[1])?,
+            node: format!("{}:{}", host, port),
+        });
+    }
+    slots.sort_by_key(|range| range.start);
+    Ok(slots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_slot() {
+        assert_eq!(hash_slot(b"123456789"), 0x31C3);
+        assert_eq!(hash_slot(b"foo"), 12182);
+        assert_eq!(hash_slot(b"session:{foo}"), 12182);
+        // Empty or unterminated hash tags are ignored.
+        assert_ne!(hash_slot(b"{}foo"), 12182);
+        assert_ne!(hash_slot(b"{foo"), 12182);
+    }
+
+    #[test]
+    fn test_command_key() {
+        assert_eq!(command_key(cmd("GET").arg("foo")), Some(&b"foo"[..]));
+        assert_eq!(
+            command_key(cmd("EVALSHA").arg("sha").arg(1).arg("foo").arg("bar")),
+            Some(&b"foo"[..])
+        );
+        assert_eq!(command_key(cmd("PUBLISH").arg("chan").arg("msg")), None);
+    }
+
+    #[test]
+    fn test_parse_target() {
+        match Servers::parse("redis+sentinel://:secret@s1,s2:26380/mymaster/2").unwrap() {
+            Servers::Sentinel {
+                sentinels,
+                master_name,
+                info,
+            } => {
+                let addrs: Vec<_> = sentinels.into_iter().map(|info| *info.addr).collect();
+                assert_eq!(
+                    addrs,
+                    vec![
+                        ConnectionAddr::Tcp("s1".to_owned(), 26379),
+                        ConnectionAddr::Tcp("s2".to_owned(), 26380),
+                    ]
+                );
+                assert_eq!(master_name, "mymaster");
+                assert_eq!(info.db, 2);
+                assert_eq!(info.passwd.as_deref(), Some("secret"));
+            }
+            _ => panic!("expected a Sentinel target"),
+        }
+        match Servers::parse("redis+cluster://n1,n2:7000").unwrap() {
+            Servers::Cluster(nodes) => {
+                assert_eq!(*nodes[0].addr, ConnectionAddr::Tcp("n1".to_owned(), 6379));
+                assert_eq!(*nodes[1].addr, ConnectionAddr::Tcp("n2".to_owned(), 7000));
+            }
+            _ => panic!("expected a cluster target"),
+        }
+        assert!(Servers::parse("redis+sentinel://s1").is_err());
+        assert!(Servers::parse("redis+cluster://n1/1").is_err());
+        assert!(matches!(
+            Servers::parse("localhost").unwrap(),
+            Servers::Single(_)
+        ));
+        match Servers::parse("rediss+cluster://n1").unwrap() {
+            Servers::Cluster(nodes) => assert_eq!(
+                *nodes[0].addr,
+                ConnectionAddr::TcpTls {
+                    host: "n1".to_owned(),
+                    port: 6379,
+                    insecure: false,
+                }
+            ),
+            _ => panic!("expected a cluster target"),
+        }
+        // A bare host name that happens to start with a scheme name is not a URL.
+        match Servers::parse("rediss.internal:6379").unwrap() {
+            Servers::Single(info) => assert_eq!(
+                *info.addr,
+                ConnectionAddr::Tcp("rediss.internal".to_owned(), 6379)
+            ),
+            _ => panic!("expected a single target"),
+        }
+    }
+
+    #[test]
+    fn test_split_pem_certs() {
+        let pem = b"-----BEGIN CERTIFICATE-----\nA\n-----END CERTIFICATE-----\n\
+                    -----BEGIN CERTIFICATE-----\nB\n-----END CERTIFICATE-----\n";
+        let certs = split_pem_certs(pem);
+        assert_eq!(certs.len(), 2);
+        assert!(certs[0].ends_with(b"A\n-----END CERTIFICATE-----\n"));
+        assert!(certs[1].ends_with(b"B\n-----END CERTIFICATE-----\n"));
+    }
+
+    #[tokio::test]
+    async fn test_single_reconnect() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+
+        // Fake server that answers one command per connection, then hangs up.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0; 64];
+                if stream.read(&mut buf).await.unwrap_or(0) > 0 {
+                    let _ = stream.write_all(b"+PONG\r\n").await;
+                }
+            }
+        });
+
+        let target = Target::new(
+            &format!("redis://127.0.0.1:{}", port),
+            &TlsConfig::default(),
+        )
+        .unwrap();
+        let mut conn = Connection::connect(&target).await.unwrap();
+        for _ in 0..3 {
+            let pong: String = cmd("PING").query_async(&mut conn).await.unwrap();
+            assert_eq!(pong, "PONG");
+        }
+    }
+}
@@ -1,6 +1,9 @@
-use crate::utils::{redis::pubsub::Subscriber, SecureRandom};
+use crate::utils::{
+    redis::{connection::Connection, pubsub::Subscriber},
+    SecureRandom,
+};
 use futures_util::future::{self, FutureExt};
-use redis::{aio::MultiplexedConnection, RedisResult, Script, Value};
+use redis::{RedisResult, Script, Value};
 use std::sync::Arc;
 use tokio::time::{interval, Duration};
 
@@ -12,7 +15,7 @@ use tokio::time::{interval, Duration};
 pub struct LockGuard {
     key: Vec<u8>,
     request: Vec<u8>,
-    conn: MultiplexedConnection,
+    conn: Connection,
     unlock_script: Arc<Script>,
 }
 
@@ -38,7 +41,7 @@ impl Drop for LockGuard {
 /// This struct can be cheaply cloned.
 #[derive(Clone)]
 pub struct LockClient {
-    conn: MultiplexedConnection,
+    conn: Connection,
     pubsub: Subscriber,
     rng: SecureRandom,
     unlock_script: Arc<Script>,
@@ -48,8 +51,8 @@ impl LockClient {
     /// Create a new instance.
     ///
     /// This takes a Redis connection and a Redis pubsub connection, which must both be connected
-    /// to the same server.
-    pub fn new(conn: MultiplexedConnection, pubsub: Subscriber, rng: SecureRandom) -> Self {
+    /// to the same server, Sentinel master or cluster.
+    pub fn new(conn: Connection, pubsub: Subscriber, rng: SecureRandom) -> Self {
         let unlock_script = Arc::new(Script::new(
             r"
             if redis.call('GET', KEYS[1]) == ARGV[1] then
@@ -92,20 +95,26 @@ impl LockClient {
     }
 
     /// Try to acquire a lock without waiting.
+    ///
+    /// Errors are logged and treated as a failure to lock, so `lock` keeps retrying while Redis
+    /// is unavailable.
     async fn try_lock(&mut self, key: &[u8], request: &[u8]) -> bool {
-        let value = redis::cmd("SET")
+        let res = redis::cmd("SET")
             .arg(key)
             .arg(request)
             .arg("nx")
             .arg("px")
             .arg("30000")
             .query_async(&mut self.conn)
-            .await
-            .expect("Could not make Redis lock request");
-        match value {
-            Value::Nil => false,
-            Value::Okay => true,
-            value => panic!("Unexpected lock result from Redis: {:?}", value),
+            .await;
+        match res {
+            Ok(Value::Nil) => false,
+            Ok(Value::Okay) => true,
+            Ok(value) => panic!("Unexpected lock result from Redis: {:?}", value),
+            Err(err) => {
+                log::error!("Could not make Redis lock request: {}", err);
+                false
+            }
         }
     }
 
@@ -1,2 +1,3 @@
+pub mod connection;
 pub mod locking;
 pub mod pubsub;
@@ -1,17 +1,20 @@
+use crate::utils::redis::connection::Target;
 use futures_util::future::poll_fn;
-use redis::{ConnectionAddr, ConnectionInfo, ErrorKind, RedisError, RedisResult, Value};
+use redis::{ConnectionInfo, ErrorKind, RedisError, RedisResult, Value};
 use std::collections::hash_map::{Entry, HashMap};
 use std::future::Future;
 use std::io::Result as IoResult;
-use std::net::ToSocketAddrs;
 use std::pin::Pin;
 use std::task::Poll;
 use tokio::io::{self, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use tokio::time::{sleep, Duration};
 
-#[cfg(unix)]
-use tokio::net::UnixStream;
+/// Delay before the first attempt to reconnect after the connection was lost.
+const RECONNECT_DELAY_MIN: Duration = Duration::from_millis(500);
+
+/// Maximum delay between attempts to reconnect. The delay doubles after each failed attempt.
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(30);
 
 type Decoder = combine::stream::Decoder<
     combine::parser::combinator::AnySendSyncPartialState,
@@ -78,7 +81,70 @@ enum LoopEvent {
 }
 
 /// The Redis pubsub connection loop.
-async fn conn_loop(mut rx: ReadHalf, mut tx: WriteHalf, mut cmd: mpsc::Receiver<Cmd>) {
+///
+/// If the connection is lost, this reconnects and resubscribes to all active channels. Because
+/// messages may have been missed in the mean time, every reconnect increments the counter in
+/// `reconnects`.
+async fn conn_loop(
+    target: Target,
+    mut conn: (ReadHalf, WriteHalf),
+    mut cmd: mpsc::Receiver<Cmd>,
+    reconnects: watch::Sender<u64>,
+) {
+    // Map of active subscriptions by channel name.
+    let mut subs: HashMap<Vec<u8>, Sub> = HashMap::new();
+
+    loop {
+        let (rx, tx) = conn;
+        match run_conn(rx, tx, &mut cmd, &mut subs).await {
+            Ok(()) => break,
+            Err(err) => log::error!("Redis pubsub connection failed, reconnecting: {}", err),
+        }
+        conn = reconnect(&target, &subs).await;
+        let count = *reconnects.borrow() + 1;
+        let _ignored = reconnects.send(count);
+    }
+}
+
+/// Reconnect and resubscribe, retrying with exponential backoff until successful.
+async fn reconnect(target: &Target, subs: &HashMap<Vec<u8>, Sub>) -> (ReadHalf, WriteHalf) {
+    let mut delay = RECONNECT_DELAY_MIN;
+    loop {
+        sleep(delay).await;
+        delay = std::cmp::min(delay * 2, RECONNECT_DELAY_MAX);
+        let res = async {
+            let (rx, mut tx) = open(target).await?;
+            if !subs.is_empty() {
+                let mut sub_cmd: Vec<&[u8]> = vec![b"SUBSCRIBE"];
+                sub_cmd.extend(subs.keys().map(|chan| &chan[..]));
+                tx.write(&sub_cmd).await?;
+            }
+            Ok::<_, RedisError>((rx, tx))
+        }
+        .await;
+        match res {
+            Ok(conn) => {
+                log::info!("Reconnected the Redis pubsub connection");
+                return conn;
+            }
+            Err(err) => log::error!(
+                "Failed to reconnect the Redis pubsub connection, retrying in {:?}: {}",
+                delay,
+                err
+            ),
+        }
+    }
+}
+
+/// Process commands and events on a single connection.
+///
+/// Returns `Ok` when all `Subscriber` handles were dropped, or an error if the connection failed.
+async fn run_conn(
+    mut rx: ReadHalf,
+    mut tx: WriteHalf,
+    cmd: &mut mpsc::Receiver<Cmd>,
+    subs: &mut HashMap<Vec<u8>, Sub>,
+) -> RedisResult<()> {
     // Ping or cleanup subscriptions at an interval.
     let interval = tokio::time::interval(tokio::time::Duration::from_secs(20));
     tokio::pin!(interval);
@@ -92,9 +158,6 @@ async fn conn_loop(mut rx: ReadHalf, mut tx: WriteHalf, mut cmd: mpsc::Receiver<
         (res, rx)
     });
 
-    // Map of active subscriptions by channel name.
-    let mut subs: HashMap<Vec<u8>, Sub> = HashMap::new();
-
     loop {
         // This specifically prioritizes processing commands over receiving.
         match poll_fn(|cx| {
@@ -130,15 +193,14 @@ async fn conn_loop(mut rx: ReadHalf, mut tx: WriteHalf, mut cmd: mpsc::Receiver<
                         tx: broadcast::channel(8).0,
                         pending: Some(vec![reply]),
                     });
-                    tx.write(&[b"SUBSCRIBE", &chan])
-                        .await
-                        .expect("Failed to send subscribe command to Redis");
+                    tx.write(&[b"SUBSCRIBE", &chan]).await?;
                 }
             },
             LoopEvent::CmdClosed => {
-                // TODO: Stop reading from the command channel.
-                // This, plus an empty `subs`, means we can exit.
-                unimplemented!();
+                // All `Subscriber` handles were dropped, so no new subscriptions can be made.
+                // Closing the connection also closes the broadcast channels of remaining
+                // subscriptions, which signals their receivers.
+                return Ok(());
             }
             LoopEvent::Interval => {
                 // Unsubscribe from channels that no longer have subscribers, or send a ping.
@@ -153,18 +215,14 @@ async fn conn_loop(mut rx: ReadHalf, mut tx: WriteHalf, mut cmd: mpsc::Receiver<
                     })
                     .collect();
                 if to_unsub.is_empty() {
-                    tx.write(&[b"PING"])
-                        .await
-                        .expect("Failed to send ping command to Redis");
+                    tx.write(&[b"PING"]).await?;
                 } else {
                     for chan in &to_unsub {
                         subs.remove(chan);
                     }
                     let mut unsub_cmd: Vec<&[u8]> = vec![b"UNSUBSCRIBE"];
                     unsub_cmd.extend(to_unsub.iter().map(|chan| &chan[..]));
-                    tx.write(&unsub_cmd)
-                        .await
-                        .expect("Failed to send unsubscribe command to Redis");
+                    tx.write(&unsub_cmd).await?;
                 }
             }
             LoopEvent::Read((res, mut rx)) => {
@@ -172,7 +230,7 @@ async fn conn_loop(mut rx: ReadHalf, mut tx: WriteHalf, mut cmd: mpsc::Receiver<
                     let res = rx.read().await;
                     (res, rx)
                 });
-                let value = res.expect("Failed to read from Redis");
+                let value = res?;
                 let vec = match value {
                     // Note: If we have no subscriptions at all, we receive pongs as regular
                     // replies instead of events.
@@ -217,6 +275,7 @@ async fn conn_loop(mut rx: ReadHalf, mut tx: WriteHalf, mut cmd: mpsc::Receiver<
 #[derive(Clone)]
 pub struct Subscriber {
     cmd: mpsc::Sender<Cmd>,
+    reconnects: watch::Receiver<u64>,
 }
 
 impl Subscriber {
@@ -236,55 +295,47 @@ impl Subscriber {
         }
         panic!("Tried to subscribe on closed pubsub connection");
     }
+
+    /// Watch for reconnects of the pubsub connection.
+    ///
+    /// Messages sent while the connection was down are lost, so subscribers may need to recover
+    /// state when this changes. The sender is closed when the connection loop exits.
+    pub fn reconnects(&self) -> watch::Receiver<u64> {
+        self.reconnects.clone()
+    }
 }
 
 /// Make a pubsub connection to Redis.
-pub async fn connect(info: &ConnectionInfo) -> RedisResult<Subscriber> {
-    // Note: This code is borrowed from the redis crate.
-    let (rx, tx): (
-        Box<dyn io::AsyncRead + Unpin + Send>,
-        Box<dyn io::AsyncWrite + Unpin + Send>,
-    ) = match *info.addr {
-        ConnectionAddr::Tcp(ref host, port) => {
-            let socket_addr = {
-                let mut socket_addrs = (&host[..], port).to_socket_addrs()?;
-                match socket_addrs.next() {
-                    Some(socket_addr) => socket_addr,
-                    None => {
-                        return Err(RedisError::from((
-                            ErrorKind::InvalidClientConfig,
-                            "No address found for host",
-                        )));
-                    }
-                }
-            };
-
-            let (rx, tx) = io::split(TcpStream::connect(&socket_addr).await?);
-            (Box::new(rx), Box::new(tx))
-        }
-
-        ConnectionAddr::TcpTls { .. } => {
-            return Err(RedisError::from((
-                ErrorKind::InvalidClientConfig,
-                "TLS connections not yet supported",
-            )))
-        }
+pub async fn connect(target: Target) -> RedisResult<Subscriber> {
+    let conn = open(&target).await?;
+    let (cmd_tx, cmd_rx) = mpsc::channel(8);
+    let (reconnects_tx, reconnects_rx) = watch::channel(0);
+    tokio::spawn(conn_loop(target, conn, cmd_rx, reconnects_tx));
+    Ok(Subscriber {
+        cmd: cmd_tx,
+        reconnects: reconnects_rx,
+    })
+}
 
-        #[cfg(unix)]
-        ConnectionAddr::Unix(ref path) => {
-            let (rx, tx) = io::split(UnixStream::connect(path).await?);
-            (Box::new(rx), Box::new(tx))
+/// Open a connection to the first reachable candidate server.
+async fn open(target: &Target) -> RedisResult<(ReadHalf, WriteHalf)> {
+    let mut last_err = None;
+    for info in target.pubsub_nodes().await? {
+        match open_one(target, &info).await {
+            Ok(conn) => return Ok(conn),
+            Err(err) => last_err = Some(err),
         }
+    }
+    Err(last_err.expect("No Redis servers to connect to"))
+}
 
-        #[cfg(not(unix))]
-        ConnectionAddr::Unix(_) => {
-            return Err(RedisError::from((
-                ErrorKind::InvalidClientConfig,
-                "Cannot connect to unix sockets \
-                 on this platform",
-            )))
-        }
-    };
+/// Open a connection to a single server.
+async fn open_one(target: &Target, info: &ConnectionInfo) -> RedisResult<(ReadHalf, WriteHalf)> {
+    let (rx, tx) = io::split(target.open(info).await?);
+    let (rx, tx): (
+        Box<dyn io::AsyncRead + Unpin + Send>,
+        Box<dyn io::AsyncWrite + Unpin + Send>,
+    ) = (Box::new(rx), Box::new(tx));
 
     let mut rx = ReadHalf {
         inner: io::BufReader::new(rx),
@@ -313,7 +364,5 @@ pub async fn connect(info: &ConnectionInfo) -> RedisResult<Subscriber> {
 
     // Note: Pubsub ignores database ID, so we don't need to send `SELECT`.
 
-    let (cmd_tx, cmd_rx) = mpsc::channel(8);
-    tokio::spawn(conn_loop(rx, tx, cmd_rx));
-    Ok(Subscriber { cmd: cmd_tx })
+    Ok((rx, tx))
 }
@@ -0,0 +1,179 @@
+//! Encryption of data at rest in stores.
+//!
+//! When `storage_encryption_key` is set, stores seal session data and key sets with AES-256-GCM
+//! before writing them, using a key derived from the setting with HKDF-SHA256. Sealed values carry
+//! a prefix, so plaintext values written before encryption was enabled can still be read.
+
+use crate::agents::KeySet;
+use crate::crypto::SigningAlgorithm;
+use crate::utils::{base64url, BoxError, SecureRandom};
+use crate::web::Session;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::hkdf;
+use std::sync::Arc;
+use thiserror::Error;
+
+/// Prefix of sealed values.
+const PREFIX: &str = "enc1:";
+
+/// Salt used to derive the encryption key from the secret.
+const HKDF_SALT: &[u8] = b"portier-broker storage encryption";
+
+#[derive(Debug, Error)]
+pub enum StorageEncryptionError {
+    #[error("found encrypted data in storage, but storage_encryption_key is not set")]
+    KeyRequired,
+    #[error("could not decrypt data in storage, storage_encryption_key may be incorrect")]
+    Decrypt,
+}
+
+/// Seals and opens values written to storage. Does nothing if no key is configured.
+#[derive(Clone)]
+pub struct StorageEncryption {
+    key: Option<Arc<LessSafeKey>>,
+    rng: SecureRandom,
+}
+
+impl StorageEncryption {
+    /// Create an instance, deriving the encryption key from a secret if given.
+    pub fn new(secret: Option<&str>, rng: SecureRandom) -> Self {
+        let key = secret.map(|secret| {
+            let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, HKDF_SALT).extract(secret.as_bytes());
+            let okm = prk
+                .expand(&[], &AES_256_GCM)
+                .expect("Could not derive storage encryption key");
+            Arc::new(LessSafeKey::new(UnboundKey::from(okm)))
+        });
+        StorageEncryption { key, rng }
+    }
+
+    /// Seal a value, if a key is configured.
+    ///
+    /// The context identifies where the value is stored, for example the session ID, so a sealed
+    /// value can't be moved to another location.
+    pub fn seal(&self, context: &str, value: String) -> String {
+        let key = match self.key {
+            Some(ref key) => key,
+            None => return value,
+        };
+        let nonce = self.rng.generate(NONCE_LEN);
+        let mut data = value.into_bytes();
+        key.seal_in_place_append_tag(
+            Nonce::try_assume_unique_for_key(&nonce).unwrap(),
+            Aad::from(context),
+            &mut data,
+        )
+        .expect("Could not encrypt value for storage");
+        format!(
+            "{}{}.{}",
+            PREFIX,
+            base64url::encode(&nonce),
+            base64url::encode(&data)
+        )
+    }
+
+    /// Open a value read from storage. Values that are not sealed are returned as-is.
+    pub fn open(&self, context: &str, value: String) -> Result<String, StorageEncryptionError> {
+        let sealed = match value.strip_prefix(PREFIX) {
+            Some(sealed) => sealed,
+            None => return Ok(value),
+        };
+        let key = self
+            .key
+            .as_ref()
+            .ok_or(StorageEncryptionError::KeyRequired)?;
+        let mut parts = sealed.splitn(2, '.');
+        let nonce = parts
+            .next()
+            .and_then(|part| base64url::decode(part).ok())
+            .ok_or(StorageEncryptionError::Decrypt)?;
+        let mut data = parts
+            .next()
+            .and_then(|part| base64url::decode(part).ok())
+            .ok_or(StorageEncryptionError::Decrypt)?;
+        let nonce = Nonce::try_assume_unique_for_key(&nonce)
+            .map_err(|_| StorageEncryptionError::Decrypt)?;
+        let plain = key
+            .open_in_place(nonce, Aad::from(context), &mut data)
+            .map_err(|_| StorageEncryptionError::Decrypt)?;
+        String::from_utf8(plain.to_vec()).map_err(|_| StorageEncryptionError::Decrypt)
+    }
+
+    /// Encode session data for storage.
+    pub fn seal_session(
+        &self,
+        session_id: &str,
+        data: &Session,
+    ) -> Result<String, serde_json::Error> {
+        let data = serde_json::to_string(data)?;
+        Ok(self.seal(&format!("session:{}", session_id), data))
+    }
+
+    /// Decode session data read from storage.
+    pub fn open_session(&self, session_id: &str, data: String) -> Result<Session, BoxError> {
+        let data = self.open(&format!("session:{}", session_id), data)?;
+        Ok(serde_json::from_str(&data)?)
+    }
+
+    /// Encode a key set for storage.
+    pub fn seal_key_set(&self, key_set: &KeySet) -> String {
+        let data = serde_json::to_string(key_set).expect("Could not encode key set as JSON");
+        self.seal(&format!("keys:{}", key_set.signing_alg), data)
+    }
+
+    /// Decode a key set read from storage.
+    pub fn open_key_set(
+        &self,
+        signing_alg: SigningAlgorithm,
+        data: String,
+    ) -> Result<KeySet, BoxError> {
+        let data = self.open(&format!("keys:{}", signing_alg), data)?;
+        Ok(serde_json::from_str(&data)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+
+    fn encryption(secret: Option<&str>) -> StorageEncryption {
+        let rng = SecureRandom {
+            generator: SystemRandom::new(),
+        };
+        StorageEncryption::new(secret, rng)
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let enc = encryption(Some("correct horse battery staple"));
+        let sealed = enc.seal("session-1", "secret data".to_owned());
+        assert!(sealed.starts_with(PREFIX));
+        assert!(!sealed.contains("secret"));
+        assert_eq!(
+            enc.open("session-1", sealed.clone()).unwrap(),
+            "secret data"
+        );
+        assert!(matches!(
+            enc.open("session-2", sealed.clone()),
+            Err(StorageEncryptionError::Decrypt)
+        ));
+        assert!(matches!(
+            encryption(Some("wrong")).open("session-1", sealed.clone()),
+            Err(StorageEncryptionError::Decrypt)
+        ));
+        assert!(matches!(
+            encryption(None).open("session-1", sealed),
+            Err(StorageEncryptionError::KeyRequired)
+        ));
+    }
+
+    #[test]
+    fn test_plaintext_passthrough() {
+        let enc = encryption(Some("correct horse battery staple"));
+        assert_eq!(enc.open("ctx", "{}".to_owned()).unwrap(), "{}");
+        let disabled = encryption(None);
+        assert_eq!(disabled.seal("ctx", "{}".to_owned()), "{}");
+        assert_eq!(disabled.open("ctx", "{}".to_owned()).unwrap(), "{}");
+    }
+}
@@ -4,14 +4,15 @@ use crate::config::ConfigRc;
 use crate::crypto::{self, SigningAlgorithm};
 use crate::email_address::EmailAddress;
 use crate::error::{BrokerError, BrokerResult};
+use crate::metrics;
 use crate::router::router;
-use crate::utils::{http::ResponseExt, real_ip, BoxError, BoxFuture};
+use crate::telemetry;
+use crate::utils::{http::ResponseExt, logger::REQUEST_ID, real_ip, BoxError, BoxFuture};
 use bytes::{Bytes, BytesMut};
 use futures_util::stream::StreamExt;
 use gettext::Catalog;
 use headers::{CacheControl, ContentType, Header, StrictTransportSecurity};
-use http::{HeaderMap, Method, StatusCode, Uri};
-use hyper::server::conn::AddrStream;
+use http::{HeaderMap, HeaderValue, Method, StatusCode, Uri};
 use hyper::service::Service as HyperService;
 use hyper::Body;
 use log::info;
@@ -46,6 +47,8 @@ pub enum ResponseMode {
     Fragment,
     #[serde(rename = "form_post")]
     FormPost,
+    #[serde(rename = "query")]
+    Query,
 }
 
 /// Parameters used to return to the relying party
@@ -55,6 +58,9 @@ pub struct ReturnParams {
     pub response_mode: ResponseMode,
     pub response_errors: bool,
     pub state: String,
+    /// PKCE challenge, if the RP uses the authorization code flow.
+    #[serde(default)]
+    pub code_challenge: Option<String>,
 }
 
 /// Common session data.
@@ -153,12 +159,11 @@ impl Context {
             Some(data) => data,
             None => return Ok(false),
         };
-        self.app
-            .store
-            .send(SaveSession {
+        telemetry::span("store.save_session")
+            .run(self.app.store.send(SaveSession {
                 session_id: self.session_id.clone(),
                 data: Session { data, bridge_data },
-            })
+            }))
             .await
             .map_err(|e| BrokerError::Internal(format!("could not save a session: {}", e)))?;
         Ok(true)
@@ -169,12 +174,10 @@ impl Context {
         assert!(self.session_id.is_empty());
         assert!(self.session_data.is_none());
         assert!(self.return_params.is_none());
-        let Session { data, bridge_data } = self
-            .app
-            .store
-            .send(GetSession {
+        let Session { data, bridge_data } = telemetry::span("store.get_session")
+            .run(self.app.store.send(GetSession {
                 session_id: id.to_owned(),
-            })
+            }))
             .await
             .map_err(|e| BrokerError::Internal(format!("could not load a session: {}", e)))?
             .ok_or(BrokerError::SessionExpired)?;
@@ -201,11 +204,8 @@ pub struct Service {
 }
 
 impl Service {
-    pub fn new(app: ConfigRc, stream: &AddrStream) -> Self {
-        Self {
-            app,
-            remote_addr: stream.remote_addr(),
-        }
+    pub fn new(app: ConfigRc, remote_addr: SocketAddr) -> Self {
+        Self { app, remote_addr }
     }
 
     async fn serve(ip: IpAddr, req: Request, app: ConfigRc) -> Result<Response, BoxError> {
@@ -281,11 +281,31 @@ impl HyperService<Request> for Service {
 
     fn call(&mut self, req: Request) -> Self::Future {
         let ip = real_ip(self.remote_addr, &req, &self.app.trusted_proxies);
-        info!("{} - {} {}", ip, req.method(), req.uri());
 
         // Grab what we need from `self` before creating a future.
         let app = Arc::clone(&self.app);
-        Box::pin(Self::serve(ip, req, app))
+        Box::pin(async move {
+            // Assign an ID to the request, which is added to every log line it causes.
+            let request_id = crypto::random_zbase32(16, &app.rng).await;
+            let header_value =
+                HeaderValue::from_str(&request_id).expect("request ID is not a valid header");
+            let span = telemetry::root_span(app.tracer.as_ref(), "request")
+                .attr("http.method", req.method().as_str())
+                .attr("http.target", req.uri().path())
+                .attr("request_id", request_id.as_str());
+            REQUEST_ID
+                .scope(
+                    request_id,
+                    span.run(async move {
+                        info!("{} - {} {}", ip, req.method(), req.uri());
+                        let mut response = Self::serve(ip, req, app).await?;
+                        telemetry::set_attribute("http.status_code", response.status().as_u16());
+                        response.headers_mut().insert("x-request-id", header_value);
+                        Ok(response)
+                    }),
+                )
+                .await
+        })
     }
 }
 
@@ -308,6 +328,7 @@ impl HyperService<Request> for Service {
 /// The large match-statement below handles all these scenario's properly, and
 /// sets proper response codes for each category.
 async fn handle_error(ctx: &Context, err: BrokerError) -> Response {
+    metrics::ERRORS.with_label_values(&[err.kind()]).inc();
     let reference = err.log(Some(&ctx.app.rng)).await;
 
     if ctx.want_json() {
@@ -484,6 +505,15 @@ pub fn return_to_relier(ctx: &Context, params: &[(&str, &str)]) -> Response {
             res.header(hyper::header::LOCATION, redirect_uri.into_string());
             res
         }
+        // Add params as query parameters and redirect.
+        ResponseMode::Query => {
+            let mut redirect_uri = redirect_uri.clone();
+            redirect_uri.query_pairs_mut().extend_pairs(params);
+
+            let mut res = empty_response(StatusCode::SEE_OTHER);
+            res.header(hyper::header::LOCATION, redirect_uri.into_string());
+            res
+        }
         // Render a form that submits a POST request.
         ResponseMode::FormPost => {
             let data = mustache::MapBuilder::new()
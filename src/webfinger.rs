@@ -2,6 +2,8 @@ use crate::agents::FetchUrlCached;
 use crate::config::ConfigRc;
 use crate::email_address::EmailAddress;
 use crate::error::BrokerError;
+use crate::metrics;
+use crate::telemetry;
 use serde::{Deserialize, Serialize};
 use std::fmt::{Display, Error as FmtError, Formatter};
 use std::str::FromStr;
@@ -12,6 +14,8 @@ use url::Url;
 pub const WEBFINGER_PORTIER_REL: &str = "https://portier.io/specs/auth/1.0/idp";
 /// Portier + Google webfinger relation
 pub const WEBFINGER_GOOGLE_REL: &str = "https://portier.io/specs/auth/1.0/idp/google";
+/// Configured upstream OpenID Connect provider relation
+pub const WEBFINGER_OIDC_REL: &str = "https://portier.io/specs/auth/1.0/idp/oidc";
 
 /// Deserialization types
 #[derive(Deserialize)]
@@ -39,6 +43,7 @@ pub enum ParseRelationError {
 pub enum Relation {
     Portier,
     Google,
+    Oidc,
 }
 
 impl Display for Relation {
@@ -46,6 +51,7 @@ impl Display for Relation {
         match self {
             Relation::Portier => Display::fmt(WEBFINGER_PORTIER_REL, f),
             Relation::Google => Display::fmt(WEBFINGER_GOOGLE_REL, f),
+            Relation::Oidc => Display::fmt(WEBFINGER_OIDC_REL, f),
         }
     }
 }
@@ -57,6 +63,7 @@ impl FromStr for Relation {
         match s {
             WEBFINGER_PORTIER_REL => Ok(Relation::Portier),
             WEBFINGER_GOOGLE_REL => Ok(Relation::Google),
+            WEBFINGER_OIDC_REL => Ok(Relation::Oidc),
             value => Err(ParseRelationError::InvalidValue(value.to_owned())),
         }
     }
@@ -118,9 +125,10 @@ pub async fn query(app: &ConfigRc, email_addr: &EmailAddress) -> Result<Vec<Link
     .map_err(|e| BrokerError::Internal(format!("could not build webfinger query url: {}", e)))?;
 
     // Make the request.
-    let descriptor = app
-        .store
-        .send(FetchUrlCached { url })
+    let _timer = metrics::WEBFINGER_DURATION.start_timer();
+    let descriptor = telemetry::span("store.fetch_url_cached")
+        .attr("url", url.as_str())
+        .run(app.store.send(FetchUrlCached { url }))
         .await
         .map_err(|e| BrokerError::Provider(format!("webfinger request failed: {}", e)))?;
     let descriptor: DescriptorDef = serde_json::from_str(&descriptor)